@@ -0,0 +1,119 @@
+//! Admin commands over a Unix socket with `--admin-socket`
+//!
+//! Each connection sends a single command on one line, the
+//! [`Server`](::server::Server) answers and the connection is closed:
+//!
+//! - `shutdown`: Close the connections and stop the server.
+//! - `status`: List the connected peers, with their address and uptime.
+//! - `kick <addr>`: Disconnect the peers connected from `<addr>`, an IP
+//!   address or an `ip:port`.
+//!
+//! ```shell
+//! echo status | nc -U /run/chat.sock
+//! ```
+
+use std::io;
+use std::path::Path;
+use actix::prelude::*;
+use futures::Future;
+
+/// An admin command, answered by the server with a text
+#[derive(Message, Debug, PartialEq)]
+#[rtype(result = "String")]
+pub enum AdminCommand {
+    /// Stop the server
+    Shutdown,
+    /// List the connected peers
+    Status,
+    /// Disconnect the peers connected from an address
+    Kick(String)
+}
+
+impl AdminCommand {
+    /// Parse a command line
+    pub fn parse(line: &str) -> Result<AdminCommand, String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("shutdown"), None, _) => Ok(AdminCommand::Shutdown),
+            (Some("status"), None, _) => Ok(AdminCommand::Status),
+            (Some("kick"), Some(addr), None) => Ok(AdminCommand::Kick(addr.to_owned())),
+            _ => Err(format!("Unknown command {:?}, expected shutdown, status or kick <addr>",
+                             line.trim()))
+        }
+    }
+}
+
+/// Longest command line read, the rest is ignored
+const MAX_LINE: u64 = 1024;
+
+/// Listen on the Unix socket `path`, in the current Arbiter, and give
+/// the commands to `server`
+///
+/// A socket file left by a previous server is replaced, any other file
+/// is an error. The commands are read up to the end of the line.
+#[cfg(unix)]
+pub fn serve(path: &Path, server: Recipient<AdminCommand>) -> io::Result<()> {
+    use std::fs;
+    use std::io::{BufReader, Read};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+    use futures::Stream;
+    use tokio::net::UnixListener;
+
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the file exists and is not a socket"));
+        }
+        // Nobody listens on it anymore
+        Ok(_) if UnixStream::connect(path).is_err() => fs::remove_file(path)?,
+        _ => ()
+    }
+    let listener = UnixListener::bind(path)?;
+
+    Arbiter::spawn(listener.incoming()
+        .map_err(|e| println!("Admin socket error: {}", e))
+        .for_each(move |socket| {
+            let server = server.clone();
+            Arbiter::spawn(::tokio_io::io::read_until(BufReader::new(socket.take(MAX_LINE)), b'\n', Vec::new())
+                .map_err(|_| ())
+                .and_then(move |(reader, line)| {
+                    let socket = reader.into_inner().into_inner();
+                    let line = String::from_utf8_lossy(&line).into_owned();
+                    let answer: Box<dyn Future<Item = String, Error = ()>> = match AdminCommand::parse(&line) {
+                        Ok(command) => Box::new(server.send(command).map_err(|_| ())),
+                        Err(e) => Box::new(::futures::future::ok(e))
+                    };
+                    answer.and_then(move |answer| {
+                        ::tokio_io::io::write_all(socket, answer + "\n").map_err(|_| ())
+                    })
+                })
+                .map(|_| ()));
+            Ok(())
+        }));
+
+    Ok(())
+}
+
+/// There are no Unix sockets outside of Unix
+#[cfg(not(unix))]
+pub fn serve(_path: &Path, _server: Recipient<AdminCommand>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::AdminCommand;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(AdminCommand::parse("shutdown\n"), Ok(AdminCommand::Shutdown));
+        assert_eq!(AdminCommand::parse(" status "), Ok(AdminCommand::Status));
+        assert_eq!(AdminCommand::parse("kick 10.0.0.1:4000\n"),
+                   Ok(AdminCommand::Kick("10.0.0.1:4000".to_owned())));
+        assert!(AdminCommand::parse("kick").is_err());
+        assert!(AdminCommand::parse("kick a b").is_err());
+        assert!(AdminCommand::parse("status now").is_err());
+        assert!(AdminCommand::parse("").is_err());
+    }
+}
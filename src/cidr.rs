@@ -0,0 +1,132 @@
+//! Address ranges of the `--allow` and `--deny` options
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of addresses, like `192.168.0.0/16` or `::1/128`
+///
+/// An address without prefix length matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    /// Return true if `ip` is in the range
+    ///
+    /// IPv4-mapped IPv6 addresses match the IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(u128::from(u32::MAX), 32, self.prefix);
+                u128::from(u32::from(net)) & mask == u128::from(u32::from(ip)) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(u128::MAX, 128, self.prefix);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+/// Keep the `prefix` high bits of a `bits` wide mask
+fn mask(all: u128, bits: u8, prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => all & !((1u128 << (bits - prefix)) - 1)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = parts.next()
+                        .and_then(|a| IpAddr::from_str(a).ok())
+                        .ok_or_else(|| format!("Invalid address: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => u8::from_str(prefix).ok()
+                                                .filter(|&p| p <= max)
+                                                .ok_or_else(|| format!("Invalid prefix length: {}", s))?,
+            None => max
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Parse a comma separated list of ranges
+pub fn parse_list(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',').map(Cidr::from_str).collect()
+}
+
+/// Return true if a connection from `ip` is accepted
+///
+/// A denied address is always rejected. When there is an allow list,
+/// the address must be in it.
+pub fn is_allowed(ip: IpAddr, allow: &[Cidr], deny: &[Cidr]) -> bool {
+    if deny.iter().any(|cidr| cidr.contains(ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|cidr| cidr.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use super::{Cidr, is_allowed, parse_list};
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn contains() {
+        let net = Cidr::from_str("192.168.0.0/16").unwrap();
+        assert!(net.contains(ip("192.168.10.1")));
+        assert!(net.contains(ip("::ffff:192.168.10.1")));
+        assert!(!net.contains(ip("192.169.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(Cidr::from_str("10.0.0.1").unwrap().contains(ip("10.0.0.1")));
+        assert!(!Cidr::from_str("10.0.0.1").unwrap().contains(ip("10.0.0.2")));
+        assert!(Cidr::from_str("fe80::/10").unwrap().contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+        assert!(parse_list("10.0.0.0/8,nope").is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let allow = parse_list("10.0.0.0/8").unwrap();
+        let deny = parse_list("10.0.0.5").unwrap();
+
+        assert!(is_allowed(ip("10.1.2.3"), &allow, &deny));
+        assert!(!is_allowed(ip("10.0.0.5"), &allow, &deny));
+        assert!(!is_allowed(ip("127.0.0.1"), &allow, &deny));
+        assert!(is_allowed(ip("127.0.0.1"), &[], &deny));
+    }
+}
@@ -1,12 +1,28 @@
 //! Client Actor
 
 use actix::prelude::*;
+use actix::actors::signal;
 use tokio_tcp::TcpStream;
-use tokio_reactor::Handle;
+use tokio::timer::Timeout;
+use futures::{future, Future};
+use futures::future::Loop;
+use trust_dns_resolver::ResolverFuture;
+use trust_dns_resolver::config::LookupIpStrategy;
+use trust_dns_resolver::system_conf::read_system_conf;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use std::collections::VecDeque;
+use bytes::Bytes;
+use byteorder::{BigEndian, ByteOrder};
 
-use peer::{Peer, PeerClose};
-use user::{UserInput, User};
-use Config;
+use peer::{Peer, PeerClose, Disconnect, HalfClose, SendFile, Relay, Forward, Pause, pause_signals, Inspect, inspect_signals, SetDisplay};
+use reader::Kind;
+use replay;
+use socks;
+use user::{Command, Input, UserInput, User, InputClosed, Typing, TypingDebounce};
+use {Config, ExitCode, Fanout, IpVersion};
 
 /// Address of a [`Peer`]
 type APeer = Addr<Peer<Client>>;
@@ -15,11 +31,35 @@ type AUser = Addr<User<Client>>;
 
 /// The Client Actor connect to a server, handle user input and
 /// send/read data to/from the server
+///
+/// With `--connections`, it opens several connections to the server.
 pub struct Client {
-    /// The [`Peer`] Actor
-    peer: Option<APeer>,
+    /// The [`Peer`] Actors, with the index of their connection
+    peers: Vec<(usize, APeer)>,
+    /// Position in `peers` of the next peer used with
+    /// [`Fanout::RoundRobin`]
+    next_peer: usize,
     /// The [`User`] Actor
     user: Option<AUser>,
+    /// Input received while disconnected, sent once connected
+    queue: VecDeque<UserInput>,
+    /// Reconnections attempted since the last successful connection
+    reconnects: u32,
+    /// Connections being opened
+    connecting: usize,
+    /// We are shutting down, waiting for the connection to close
+    closing: bool,
+    /// The user input is closed, we won't send anything else
+    input_closed: bool,
+    /// Sending the user input is paused with [`Pause`], the
+    /// connections opened meanwhile are paused too
+    paused: bool,
+    /// Number of inputs taken, counted for `--send-limit`
+    sent: usize,
+    /// Number of responses received on the closed connections
+    acked: u64,
+    /// Limit the typing notifications
+    typing: TypingDebounce,
     /// Configuration
     config: Config
 }
@@ -28,20 +68,410 @@ impl Client {
     /// Create a Client
     pub fn new(config: Config) -> Client {
         Client {
-            peer: None,
+            peers: Vec::new(),
+            next_peer: 0,
             user: None,
+            queue: VecDeque::new(),
+            reconnects: 0,
+            connecting: 0,
+            closing: false,
+            input_closed: false,
+            paused: false,
+            sent: 0,
+            acked: 0,
+            typing: TypingDebounce::default(),
             config
         }
     }
 }
 
-impl Handler<UserInput> for Client {
-    type Result = ();
+/// Future of the addresses of a host
+type Resolving = Box<dyn Future<Item = Vec<SocketAddr>, Error = io::Error>>;
+
+/// Future of an open connection, or of why it can't be opened
+type Opening = Box<dyn Future<Item = TcpStream, Error = String>>;
+
+/// Resolve `host` to all its addresses of the IP `version`, without
+/// blocking
+///
+/// An IP address is taken as it is. A name is looked up in the hosts
+/// file, then with the name servers of the system.
+pub fn resolve(host: &str, port: u16, version: IpVersion) -> Resolving {
+    let none_found = move || match version {
+        IpVersion::Any => io::Error::new(ErrorKind::NotFound, "no address found"),
+        IpVersion::V4 => io::Error::new(ErrorKind::NotFound, "no IPv4 address found"),
+        IpVersion::V6 => io::Error::new(ErrorKind::NotFound, "no IPv6 address found")
+    };
+
+    if let Ok(ip) = IpAddr::from_str(host) {
+        let addr = SocketAddr::new(ip, port);
+        return match version.allows(&addr) {
+            true => Box::new(future::ok(vec![addr])),
+            false => Box::new(future::err(none_found()))
+        };
+    }
+
+    let (config, mut options) = match read_system_conf() {
+        Ok(conf) => conf,
+        Err(e) => return Box::new(future::err(e))
+    };
+    options.ip_strategy = match version {
+        IpVersion::Any => LookupIpStrategy::Ipv4AndIpv6,
+        IpVersion::V4 => LookupIpStrategy::Ipv4Only,
+        IpVersion::V6 => LookupIpStrategy::Ipv6Only
+    };
+    let host = host.to_owned();
+
+    Box::new(ResolverFuture::new(config, options)
+        .and_then(move |resolver| resolver.lookup_ip(host.as_str()))
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))
+        .and_then(move |ips| {
+            let addrs: Vec<SocketAddr> = ips.iter()
+                                            .map(|ip| SocketAddr::new(ip, port))
+                                            .filter(|addr| version.allows(addr))
+                                            .collect();
+            match addrs.is_empty() {
+                true => Err(none_found()),
+                false => Ok(addrs)
+            }
+        }))
+}
+
+/// Try to connect to each address in order, until one succeeds
+///
+/// `timeout` applies to each address. The error of the last attempt
+/// is returned when all of them failed.
+fn connect(addrs: Vec<SocketAddr>, timeout: Duration) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+    let none = io::Error::new(ErrorKind::NotFound, "no address found");
+
+    Box::new(future::loop_fn((addrs.into_iter(), none), move |(mut addrs, error)| {
+        let addr = match addrs.next() {
+            Some(addr) => addr,
+            None => return future::Either::A(future::err(error))
+        };
+        future::Either::B(Timeout::new(TcpStream::connect(&addr), timeout).then(move |connected| {
+            let error = match connected {
+                Ok(socket) => {
+                    println!("Connected to {}", addr);
+                    return Ok(Loop::Break(socket));
+                }
+                Err(ref e) if e.is_elapsed() => {
+                    println!("Connection to {} timed out after {}s", addr, timeout.as_secs());
+                    io::Error::from(ErrorKind::TimedOut)
+                }
+                Err(e) => {
+                    let e = e.into_inner().unwrap_or_else(|| io::Error::new(ErrorKind::Other, "timer error"));
+                    println!("Can not connect to {}: {}", addr, e);
+                    e
+                }
+            };
+            Ok(Loop::Continue((addrs, error)))
+        }))
+    }))
+}
+
+/// Explain why the connection to `what` at `host:port` failed, with
+/// a hint for the common errors
+fn connect_error(what: &str, host: &str, port: u16, error: &io::Error, timeout: Duration) -> String {
+    match error.kind() {
+        ErrorKind::ConnectionRefused => {
+            format!("Can not connect to {} at {}:{}: connection refused. \
+                     Is {} running and the port correct?", what, host, port, what)
+        }
+        ErrorKind::TimedOut => {
+            format!("Can not connect to {} at {}:{}: no answer after {}s. \
+                     Is the host reachable, or is a firewall dropping the connection?",
+                    what, host, port, timeout.as_secs())
+        }
+        _ => format!("Can not connect to {} at {}:{}: {}", what, host, port, error)
+    }
+}
+
+/// Explain why `host` can't be resolved
+fn resolve_error(what: &str, host: &str, error: &io::Error) -> String {
+    format!("Can not resolve the address of {} {}: {}. Is the host name correct?", what, host, error)
+}
+
+/// Resolve `host` and connect to one of its addresses, `what` names
+/// it in the errors
+fn resolve_and_connect(what: &'static str, host: &str, port: u16, version: IpVersion,
+                       timeout: Duration) -> Opening {
+    let host = host.to_owned();
+
+    Box::new(resolve(&host, port, version).then(move |addrs| match addrs {
+        Ok(addrs) => future::Either::A(connect(addrs, timeout).map_err(move |e| {
+            connect_error(what, &host, port, &e, timeout)
+        })),
+        Err(e) => future::Either::B(future::err(resolve_error(what, &host, &e)))
+    }))
+}
+
+/// Open a connection to the server, through the `--proxy` if any
+fn open(config: &Config) -> Opening {
+    let port = config.ports[0];
+    let timeout = config.connect_timeout;
+    let proxy = match config.proxy {
+        Some(ref proxy) => proxy,
+        None => return resolve_and_connect("the server", &config.host, port, config.ip_version, timeout)
+    };
+
+    let host = config.host.clone();
+    let auth = proxy.auth.clone();
+    let connected = resolve_and_connect("the proxy", &proxy.host, proxy.port, config.ip_version, timeout);
+
+    Box::new(connected.and_then(move |socket| {
+        // The handshake is bounded by the connect timeout too
+        Timeout::new(socks::handshake(socket, &host, port, auth.as_ref()), timeout)
+            .map(move |socket| {
+                println!("Connected to {}:{} through the proxy", host, port);
+                socket
+            })
+            .map_err(move |e| match e.into_inner() {
+                Some(e) => format!("Can not connect to server through the proxy: {}", e),
+                None => format!("Can not connect to server through the proxy: no answer after {}s",
+                                timeout.as_secs())
+            })
+    }))
+}
+
+impl Client {
+    /// Open the connection `index` to the server, without blocking
+    ///
+    /// On failure, retry later when `--reconnect` is set, otherwise stop
+    /// the system if there is no other connection.
+    fn connect(&mut self, index: usize, ctx: &mut Context<Self>) {
+        self.connecting += 1;
+        open(&self.config)
+            .into_actor(self)
+            .then(move |opened, client, ctx| {
+                client.connecting -= 1;
+                match opened {
+                    Ok(socket) => client.add_peer(index, socket, ctx),
+                    Err(e) => {
+                        println!("{}", e);
+                        client.reconnect_later(index, ctx);
+                    }
+                }
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Create a [`Peer`] for the connection `index`, once open
+    fn add_peer(&mut self, index: usize, socket: TcpStream, ctx: &mut Context<Self>) {
+        socket.set_nodelay(true).ok();
+        if let Some(idle) = self.config.tcp_keepalive {
+            if let Err(e) = socket.set_keepalive(Some(idle)) {
+                println!("Can not set TCP keepalive: {}", e);
+            }
+        }
+
+        // Connected, we create a Peer
+        self.reconnects = 0;
+        let peer = Peer::new(self.config.clone(), ctx.address(), socket, index);
+        if self.paused {
+            peer.do_send(Pause::Toggle);
+        }
 
-    fn handle(&mut self, input: UserInput, _ctx: &mut Context<Self>) {
-        if let Some(ref peer) = self.peer {
+        // Register our name before anything else
+        if let Some(ref nick) = self.config.nick {
+            peer.do_send(Forward(Kind::Hello, Bytes::from(nick.as_str())));
+        }
+        if let Some(ref kinds) = self.config.subscribe {
+            let mut mask = [0; 2];
+            BigEndian::write_u16(&mut mask, Kind::mask(kinds));
+            peer.do_send(Forward(Kind::Subscribe, Bytes::from(&mask[..])));
+        }
+
+        // Send what the user typed while we were disconnected
+        let queued = !self.queue.is_empty();
+        for input in self.queue.drain(..) {
             peer.do_send(input);
+        }
+
+        // The file is only sent on the first connection
+        if let Some(path) = self.config.file.take() {
+            peer.do_send(SendFile(path));
+        }
+
+        // So is the message of --message
+        if let Some(message) = self.config.message.take() {
+            peer.do_send(UserInput::new(message));
+            if !self.config.wait_response {
+                peer.do_send(Disconnect("message sent".to_owned()));
+            }
+        }
+
+        if self.input_closed {
+            peer.do_send(HalfClose);
+        }
+
+        self.peers.push((index, peer));
+
+        // The last message was waiting for the connection
+        if queued && self.send_limit_reached() {
+            self.shutdown("send limit reached");
+        }
+    }
+
+    /// Schedule a new connection, or stop the system if reconnection is
+    /// disabled and there is no other connection, open or being opened
+    ///
+    /// After `--max-reconnects` failed reconnections in a row, the
+    /// client gives up and exits.
+    fn reconnect_later(&mut self, index: usize, ctx: &mut Context<Self>) {
+        match self.config.reconnect {
+            Some(_) if Some(self.reconnects) == self.config.max_reconnects => {
+                println!("Giving up after {} failed reconnections, {} queued messages lost",
+                         self.reconnects, self.queue.len());
+                ExitCode::GaveUp.exit();
+            }
+            Some(delay) => {
+                self.reconnects += 1;
+                println!("Reconnecting in {}s", delay.as_secs());
+                ctx.run_later(delay, move |client, ctx| client.connect(index, ctx));
+            }
+            None if self.peers.is_empty() && self.connecting == 0 => ExitCode::Connect.exit(),
+            None => ()
+        }
+    }
+
+    /// Return the connected peers
+    fn connected(&self) -> Vec<&APeer> {
+        self.peers.iter()
+                  .map(|(_, peer)| peer)
+                  .filter(|peer| peer.connected())
+                  .collect()
+    }
+
+    /// Close the connections and stop once they are closed
+    fn shutdown(&mut self, reason: &str) {
+        self.closing = true;
+        let peers = self.connected();
+        if peers.is_empty() {
+            System::current().stop();
+        }
+        for peer in peers {
+            peer.do_send(Disconnect(reason.to_owned()));
+        }
+    }
+
+    /// Return true once `--send-limit` inputs were taken
+    fn send_limit_reached(&self) -> bool {
+        self.config.send_limit.map_or(false, |limit| self.sent >= limit)
+    }
+
+    /// Run a command typed instead of a message
+    fn run_command(&mut self, command: Result<Command, String>) {
+        match command {
+            Ok(Command::SetDisplay(display)) => {
+                println!("Display set to {}", display.name());
+                self.config.display = display.clone();
+                for peer in self.connected() {
+                    peer.do_send(SetDisplay(display.clone()));
+                }
+            }
+            Err(e) => println!("{}", e)
+        }
+    }
+}
+
+impl Handler<UserInput> for Client {
+    type Result = ResponseFuture<(), ()>;
+
+    /// Resolves once the peers took the input, or it is queued
+    ///
+    /// Fails once `--send-limit` inputs were taken, the [`User`] stops
+    /// reading.
+    fn handle(&mut self, input: UserInput, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(command) = input.command() {
+            self.run_command(command);
+            return Box::new(future::ok(()));
+        }
+        if self.send_limit_reached() {
+            return Box::new(future::err(()));
+        }
+        self.sent += 1;
+        let peers = self.connected();
+        let queued = peers.is_empty();
+
+        // A closed peer doesn't hold back the user
+        let taken: Self::Result = match self.config.fanout {
+            _ if peers.is_empty() => {
+                // Disconnected, keep the input until we reconnect
+                if self.queue.len() >= self.config.max_queue {
+                    println!("Queue full, dropping the oldest message");
+                    self.queue.pop_front();
+                }
+                self.queue.push_back(input);
+                Box::new(future::ok(()))
+            }
+            Fanout::Broadcast => {
+                let sent: Vec<_> = peers.iter().map(|peer| {
+                    peer.send(input.clone()).then(|_| Ok(()))
+                }).collect();
+                Box::new(future::join_all(sent).map(|_| ()))
+            }
+            Fanout::RoundRobin => {
+                let next = self.next_peer % peers.len();
+                let sent = peers[next].send(input).then(|_| Ok(()));
+                self.next_peer = next + 1;
+                Box::new(sent)
+            }
         };
+
+        // Queued, we disconnect once it's sent
+        if !queued && self.send_limit_reached() {
+            self.shutdown("send limit reached");
+        }
+        taken
+    }
+}
+
+impl Handler<InputClosed> for Client {
+    type Result = ();
+
+    fn handle(&mut self, _: InputClosed, _ctx: &mut Context<Self>) {
+        if self.closing {
+            // Stopped by --send-limit
+            self.user = None;
+            return;
+        }
+        if self.config.replay.is_some() {
+            self.user = None;
+            println!("Replay over");
+            self.shutdown("replay over");
+            return;
+        }
+        // Tell the server we are done sending, but keep the connection
+        // open to receive messages
+        self.user = None;
+        self.input_closed = true;
+        for peer in self.connected() {
+            peer.do_send(HalfClose);
+        }
+        println!("Input closed, the session remains open (CTRL+C to quit)\n");
+    }
+}
+
+impl Handler<Typing> for Client {
+    type Result = ();
+
+    fn handle(&mut self, _: Typing, _ctx: &mut Context<Self>) {
+        if !self.config.typing_indicators || !self.typing.ready() {
+            return;
+        }
+        for peer in self.connected() {
+            peer.do_send(Forward(Kind::Typing, Bytes::new()));
+        }
+    }
+}
+
+impl Handler<Relay> for Client {
+    type Result = ();
+
+    fn handle(&mut self, _: Relay, _ctx: &mut Context<Self>) {
+        // The client has no other peer to relay to
     }
 }
 
@@ -49,34 +479,60 @@ impl Actor for Client {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // Connect to the server
-        let host = self.config.host.as_str();
-        let port = self.config.port;
-
-        let socket = match ::std::net::TcpStream::connect((host, port))
-            .and_then(|socket| TcpStream::from_std(socket, &Handle::default()))
-        {
-            Ok(socket) => socket,
-            Err(e) => {
-                println!("Can not connect to server: {}", e);
-                System::current().stop();
-                return;
+        // Close the connection properly on CTRL+C
+        let signals = signal::ProcessSignals::from_registry();
+        signals.do_send(signal::Subscribe(ctx.address().recipient()));
+        ctx.add_message_stream(pause_signals());
+        ctx.add_message_stream(inspect_signals());
+
+        let one_shot = self.config.message.is_some();
+        if one_shot {
+            // Stop once the message is sent, or once its response came
+            self.closing = true;
+            if self.config.wait_response {
+                let timeout = self.config.response_timeout;
+                ctx.run_later(timeout, move |_, _| {
+                    println!("No response after {}s", timeout.as_secs());
+                    ExitCode::Failure.exit();
+                });
             }
-        };
+        }
 
-        socket.set_nodelay(true).ok();
+        if let Some(duration) = self.config.duration {
+            ctx.run_later(duration, move |client, _| {
+                println!("Session of {}s over", duration.as_secs());
+                client.shutdown("session over");
+            });
+        }
 
-        // Connected, we create a Peer
-        let peer = Peer::new(self.config.clone(), ctx.address(), socket);
+        // Connect to the server, the input is queued until connected
+        for index in 0..self.config.connections {
+            self.connect(index, ctx);
+        }
 
         // Start a User to handle input
-        let client = ctx.address();
-        let user = Arbiter::start(|_| {
-            User::new(client)
-        });
+        if !self.config.no_stdin && !one_shot {
+            let input = match self.config.replay {
+                Some(ref path) => {
+                    let config = &self.config;
+                    match replay::load(path, config.replay_format, &config.framing) {
+                        Ok(messages) => Input::Replay(messages),
+                        Err(e) => {
+                            println!("{}", e);
+                            ExitCode::Failure.exit();
+                        }
+                    }
+                }
+                None => Input::Stdin
+            };
+            let client = ctx.address();
+            let config = self.config.clone();
+            let user = Arbiter::start(move |_| {
+                User::new(client, input, &config)
+            });
 
-        self.peer = Some(peer);
-        self.user = Some(user);
+            self.user = Some(user);
+        }
 
         println!("Running as client");
     }
@@ -85,8 +541,120 @@ impl Actor for Client {
 impl Handler<PeerClose> for Client {
     type Result = ();
 
-    fn handle(&mut self, _: PeerClose, _ctx: &mut Context<Self>) {
-        println!("Connection closed");
-        ::std::process::exit(1);
+    fn handle(&mut self, close: PeerClose, ctx: &mut Context<Self>) {
+        match close.reason {
+            Some(reason) => println!("Connection closed by the server: {}", reason),
+            None if self.closing => println!("Connection closed"),
+            None => println!("Connection dropped")
+        }
+        let index = close.id;
+        self.peers.retain(|&(i, _)| i != index);
+        self.acked += close.stats.responses;
+
+        let pending = !self.peers.is_empty() || (self.connecting > 0 && !self.closing);
+        if pending && self.config.reconnect.is_none() {
+            // Wait for all the connections to close, or to fail opening
+            return;
+        }
+        if self.closing {
+            if self.peers.is_empty() {
+                if let Some(limit) = self.config.send_limit {
+                    println!("Sent {} of {} messages, {} acknowledged", self.sent, limit, self.acked);
+                }
+                System::current().stop();
+            }
+            return;
+        }
+        if self.config.reconnect.is_none() {
+            if close.protocol_error {
+                ExitCode::Protocol.exit();
+            }
+            ExitCode::Disconnected.exit();
+        }
+        self.reconnect_later(index, ctx);
+    }
+}
+
+impl Handler<Pause> for Client {
+    type Result = ();
+
+    fn handle(&mut self, msg: Pause, _ctx: &mut Context<Self>) {
+        self.paused = msg == Pause::Toggle && !self.paused;
+        for peer in self.connected() {
+            peer.do_send(msg);
+        }
+    }
+}
+
+impl Handler<Inspect> for Client {
+    type Result = ();
+
+    fn handle(&mut self, msg: Inspect, _ctx: &mut Context<Self>) {
+        for peer in self.connected() {
+            peer.do_send(msg);
+        }
+    }
+}
+
+impl Handler<signal::Signal> for Client {
+    type Result = ();
+
+    fn handle(&mut self, sig: signal::Signal, _ctx: &mut Context<Self>) {
+        match sig.0 {
+            signal::SignalType::Int
+            | signal::SignalType::Term
+            | signal::SignalType::Quit => self.shutdown("client shutdown"),
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{self, ErrorKind};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use futures::Future;
+    use tokio::runtime::current_thread::block_on_all;
+    use IpVersion;
+    use super::{connect, connect_error, resolve};
+
+    #[test]
+    fn resolve_ip_addresses() {
+        let addrs = resolve("127.0.0.1", 80, IpVersion::Any).wait().unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:80".parse().unwrap()]);
+        let addrs = resolve("::1", 80, IpVersion::V6).wait().unwrap();
+        assert_eq!(addrs, vec!["[::1]:80".parse().unwrap()]);
+
+        let error = resolve("::1", 80, IpVersion::V4).wait().unwrap_err();
+        assert_eq!(error.to_string(), "no IPv4 address found");
+    }
+
+    #[test]
+    fn connect_next_address() {
+        // Nothing listens on the first one anymore
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let socket = block_on_all(connect(vec![closed, open], Duration::from_secs(5))).unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), open);
+
+        let error = block_on_all(connect(vec![closed], Duration::from_secs(5))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn connect_error_hints() {
+        let timeout = Duration::from_secs(10);
+        let refused = io::Error::from(ErrorKind::ConnectionRefused);
+        let message = connect_error("the server", "localhost", 12345, &refused, timeout);
+        assert!(message.starts_with("Can not connect to the server at localhost:12345: connection refused."));
+        assert!(message.ends_with("Is the server running and the port correct?"));
+
+        let timed_out = io::Error::from(ErrorKind::TimedOut);
+        let message = connect_error("the proxy", "10.0.0.1", 1080, &timed_out, timeout);
+        assert!(message.contains("no answer after 10s"));
     }
 }
@@ -0,0 +1,749 @@
+//! Programmatic construction of the [`Config`]
+//!
+//! The command line is parsed into a [`Config`] with the builder too,
+//! see [`config_from`](::config_from).
+
+use std::path::PathBuf;
+use std::time::Duration;
+use regex::Regex;
+
+use cidr::Cidr;
+use events::EventLog;
+use peer::Hooks;
+use reader::{Framing, Kind};
+use rtt::RttCsv;
+use socks::Proxy;
+use {Config, Display, Duplicate, Fanout, IpVersion, Oversize, RelayFull, ReplayFormat, SlowPeer, Unsolicited};
+
+impl Default for Config {
+    /// Same values as the command line defaults, in server mode
+    fn default() -> Config {
+        Config {
+            is_client: false,
+            label: "server".to_owned(),
+            host: "127.0.0.1".to_owned(),
+            ports: vec![12345],
+            require_all_ports: false,
+            systemd: false,
+            ip_version: IpVersion::Any,
+            interface: None,
+            bind_retry: None,
+            backlog: 1024,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            one_per_ip: None,
+            display: Display::Binary,
+            display_response: None,
+            display_stream: false,
+            tee: false,
+            write_buffer: (0, 0),
+            read_buffer: 4096,
+            connect_timeout: Duration::from_secs(10),
+            reconnect: None,
+            max_reconnects: None,
+            proxy: None,
+            max_queue: 100,
+            window: 0,
+            tcp_keepalive: None,
+            idle_timeout: None,
+            handshake_timeout: None,
+            throttle: None,
+            drain_timeout: Duration::from_secs(5),
+            write_retries: 0,
+            file: None,
+            download_dir: PathBuf::from("."),
+            receive_files: None,
+            dump_dir: None,
+            message: None,
+            replay: None,
+            replay_format: ReplayFormat::Lines,
+            replay_speed: 1.0,
+            send_limit: None,
+            nick: None,
+            subscribe: None,
+            wait_response: false,
+            response_timeout: Duration::from_secs(10),
+            framing: Framing::default(),
+            color: false,
+            max_print: 256,
+            show_framing: false,
+            show_header: false,
+            connections: 1,
+            fanout: Fanout::Broadcast,
+            slow_peer: SlowPeer::Block,
+            slow_peer_timeout: Duration::from_secs(5),
+            relay_full: RelayFull::Drop,
+            metrics_port: None,
+            admin_socket: None,
+            status_interval: None,
+            duration: None,
+            rtt_csv: None,
+            event_log: None,
+            typing_indicators: false,
+            session_nonce: None,
+            allow_empty: false,
+            max_message: 65536,
+            on_oversize: Oversize::Split,
+            on_unsolicited_response: Unsolicited::Warn,
+            advertise_max_message: false,
+            no_stdin: false,
+            follow_input: false,
+            follow: false,
+            lines: false,
+            keep_newline: false,
+            coalesce: None,
+            echo: false,
+            ack_kinds: vec![Kind::Data],
+            banner: None,
+            history: 0,
+            history_bytes: 1024 * 1024,
+            filter: None,
+            hooks: Hooks::default(),
+            config_file: None,
+            check: false,
+            list_kinds: false
+        }
+    }
+}
+
+impl Config {
+    /// Take the options of `new` that can change while running: the
+    /// display, the colors and `--throttle`
+    ///
+    /// Return the names of the other options that changed, they are
+    /// ignored until a restart.
+    pub fn reconfigure(&mut self, new: &Config) -> Vec<&'static str> {
+        self.display = new.display.clone();
+        self.display_response = new.display_response.clone();
+        self.display_stream = new.display_stream;
+        self.tee = new.tee;
+        self.max_print = new.max_print;
+        self.show_framing = new.show_framing;
+        self.show_header = new.show_header;
+        self.color = new.color;
+        self.throttle = new.throttle;
+
+        let mut ignored = Vec::new();
+        if new.ports != self.ports {
+            ignored.push("port");
+        }
+        if new.interface != self.interface {
+            ignored.push("interface");
+        }
+        if new.host != self.host {
+            ignored.push("host");
+        }
+        ignored
+    }
+}
+
+/// Build a [`Config`] without parsing a command line
+///
+/// Unset options keep their command line default:
+///
+/// ```ignore
+/// let config = ConfigBuilder::new()
+///     .client(true)
+///     .ports(vec![4000])
+///     .display(Display::Utf8)
+///     .build();
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+    /// Set with [`ConfigBuilder::label`], otherwise it follows the mode
+    label: Option<String>
+}
+
+impl ConfigBuilder {
+    /// Return a builder with the default [`Config`]
+    pub fn new() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Run in client mode
+    pub fn client(mut self, is_client: bool) -> ConfigBuilder {
+        self.config.is_client = is_client;
+        self
+    }
+
+    /// Printed in front of the peer lines, `client` or `server` when
+    /// not set
+    pub fn label(mut self, label: &str) -> ConfigBuilder {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    /// Host the client connects to
+    pub fn host(mut self, host: &str) -> ConfigBuilder {
+        self.config.host = host.to_owned();
+        self
+    }
+
+    /// Port the server listens on, or the client connects to. 0 lets
+    /// the OS choose one for the server
+    #[allow(dead_code)]
+    pub fn port(mut self, port: u16) -> ConfigBuilder {
+        self.config.ports = vec![port];
+        self
+    }
+
+    /// Ports the server listens on, the client connects to the first one
+    pub fn ports(mut self, ports: Vec<u16>) -> ConfigBuilder {
+        self.config.ports = ports;
+        self
+    }
+
+    /// How to display received messages
+    pub fn display(mut self, display: Display) -> ConfigBuilder {
+        self.config.display = display;
+        self
+    }
+
+    /// How to display received responses, binary when not set
+    pub fn display_response(mut self, display: Display) -> ConfigBuilder {
+        self.config.display_response = Some(display);
+        self
+    }
+
+    /// Display a utf8 character cut between 2 messages with the second
+    pub fn display_stream(mut self, display_stream: bool) -> ConfigBuilder {
+        self.config.display_stream = display_stream;
+        self
+    }
+
+    /// Print with colors
+    pub fn color(mut self, color: bool) -> ConfigBuilder {
+        self.config.color = color;
+        self
+    }
+
+    /// Don't read stdin, the input is only given with
+    /// [`UserInput`](::user::UserInput)
+    pub fn no_stdin(mut self, no_stdin: bool) -> ConfigBuilder {
+        self.config.no_stdin = no_stdin;
+        self
+    }
+
+    /// Send this message once connected and disconnect, in client mode
+    pub fn message(mut self, message: &[u8]) -> ConfigBuilder {
+        self.config.message = Some(message.to_vec());
+        self
+    }
+
+    /// Wait for the response to the [`ConfigBuilder::message`] before
+    /// disconnecting, at most `timeout`
+    pub fn wait_response(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.wait_response = true;
+        self.config.response_timeout = timeout;
+        self
+    }
+
+    /// Nickname registered on the server, in client mode
+    pub fn nick(mut self, nick: &str) -> ConfigBuilder {
+        self.config.nick = Some(nick.to_owned());
+        self
+    }
+
+    /// Delay before reconnecting, in client mode
+    pub fn reconnect(mut self, delay: Duration) -> ConfigBuilder {
+        self.config.reconnect = Some(delay);
+        self
+    }
+
+    /// Maximum number of messages waiting for their response, 0 for
+    /// no limit
+    pub fn window(mut self, window: usize) -> ConfigBuilder {
+        self.config.window = window;
+        self
+    }
+
+    /// Maximum number of bytes written per second
+    pub fn throttle(mut self, rate: usize) -> ConfigBuilder {
+        self.config.throttle = Some(rate);
+        self
+    }
+
+    /// Maximum time for a client to register its nickname, in server
+    /// mode
+    pub fn handshake_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum time to write the pending messages when closing
+    pub fn drain_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    /// Maximum length of an input message, and what to do with longer
+    /// ones
+    pub fn max_message(mut self, max: usize, on_oversize: Oversize) -> ConfigBuilder {
+        self.config.max_message = max;
+        self.config.on_oversize = on_oversize;
+        self
+    }
+
+    /// Tell the other side of the maximum length of a message, with a
+    /// [`Kind::MaxMessage`](::reader::Kind::MaxMessage)
+    pub fn advertise_max_message(mut self, advertise: bool) -> ConfigBuilder {
+        self.config.advertise_max_message = advertise;
+        self
+    }
+
+    /// Number sent once connected, receiving it back means we are
+    /// connected to ourselves
+    pub fn session_nonce(mut self, nonce: u64) -> ConfigBuilder {
+        self.config.session_nonce = Some(nonce);
+        self
+    }
+
+    /// What to do with a response when no message waits for one
+    pub fn on_unsolicited_response(mut self, unsolicited: Unsolicited) -> ConfigBuilder {
+        self.config.on_unsolicited_response = unsolicited;
+        self
+    }
+
+    /// How messages are framed
+    pub fn framing(mut self, framing: Framing) -> ConfigBuilder {
+        self.config.framing = framing;
+        self
+    }
+
+    /// Answer each message with its own payload, in server mode
+    pub fn echo(mut self, echo: bool) -> ConfigBuilder {
+        self.config.echo = echo;
+        self
+    }
+
+    /// Reject a connection from the same source as a connected peer,
+    /// in server mode
+    pub fn one_per_ip(mut self, duplicate: Duplicate) -> ConfigBuilder {
+        self.config.one_per_ip = Some(duplicate);
+        self
+    }
+
+    /// What the server does with a peer too busy to take a relayed
+    /// message
+    pub fn relay_full(mut self, relay_full: RelayFull) -> ConfigBuilder {
+        self.config.relay_full = relay_full;
+        self
+    }
+
+    /// Number of messages the server replays to new clients
+    pub fn history(mut self, history: usize) -> ConfigBuilder {
+        self.config.history = history;
+        self
+    }
+
+    /// Directory where the received files are written
+    pub fn download_dir(mut self, dir: PathBuf) -> ConfigBuilder {
+        self.config.download_dir = dir;
+        self
+    }
+
+    /// Bytes of files accepted per connection, `None` refuses them
+    pub fn receive_files(mut self, max_bytes: Option<u64>) -> ConfigBuilder {
+        self.config.receive_files = max_bytes;
+        self
+    }
+
+    /// Stop the server if one of the ports can't be bound
+    pub fn require_all_ports(mut self, require_all_ports: bool) -> ConfigBuilder {
+        self.config.require_all_ports = require_all_ports;
+        self
+    }
+
+    /// Listen on the sockets passed by systemd, fail if there are none
+    pub fn systemd(mut self, systemd: bool) -> ConfigBuilder {
+        self.config.systemd = systemd;
+        self
+    }
+
+    /// IP version the server listens on, or the client connects with
+    pub fn ip_version(mut self, ip_version: IpVersion) -> ConfigBuilder {
+        self.config.ip_version = ip_version;
+        self
+    }
+
+    /// Network interface the server listens on, all when `None`
+    pub fn interface(mut self, interface: Option<String>) -> ConfigBuilder {
+        self.config.interface = interface;
+        self
+    }
+
+    /// How long to retry binding a port already in use. `None` to give
+    /// up right away
+    pub fn bind_retry(mut self, bind_retry: Option<Duration>) -> ConfigBuilder {
+        self.config.bind_retry = bind_retry;
+        self
+    }
+
+    /// Maximum number of connections waiting to be accepted
+    pub fn backlog(mut self, backlog: i32) -> ConfigBuilder {
+        self.config.backlog = backlog;
+        self
+    }
+
+    /// Addresses the server accepts connections from, all when empty
+    pub fn allow(mut self, allow: Vec<Cidr>) -> ConfigBuilder {
+        self.config.allow = allow;
+        self
+    }
+
+    /// Addresses the server rejects connections from
+    pub fn deny(mut self, deny: Vec<Cidr>) -> ConfigBuilder {
+        self.config.deny = deny;
+        self
+    }
+
+    /// Print the received messages even with `--display none`
+    pub fn tee(mut self, tee: bool) -> ConfigBuilder {
+        self.config.tee = tee;
+        self
+    }
+
+    /// Low and high watermarks of the socket write buffer, in bytes
+    pub fn write_buffer(mut self, write_buffer: (usize, usize)) -> ConfigBuilder {
+        self.config.write_buffer = write_buffer;
+        self
+    }
+
+    /// Initial capacity of the buffer where received data is read, in
+    /// bytes
+    pub fn read_buffer(mut self, read_buffer: usize) -> ConfigBuilder {
+        self.config.read_buffer = read_buffer;
+        self
+    }
+
+    /// Maximum time to wait for the connection to the server
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> ConfigBuilder {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Number of failed reconnections in a row after which the client
+    /// gives up. `None` to retry forever
+    pub fn max_reconnects(mut self, max_reconnects: Option<u32>) -> ConfigBuilder {
+        self.config.max_reconnects = max_reconnects;
+        self
+    }
+
+    /// SOCKS5 proxy the client connects through
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> ConfigBuilder {
+        self.config.proxy = proxy;
+        self
+    }
+
+    /// Maximum number of messages kept while the client is disconnected
+    pub fn max_queue(mut self, max_queue: usize) -> ConfigBuilder {
+        self.config.max_queue = max_queue;
+        self
+    }
+
+    /// Idle time before TCP keepalive probes are sent. `None` to
+    /// disable them
+    pub fn tcp_keepalive(mut self, tcp_keepalive: Option<Duration>) -> ConfigBuilder {
+        self.config.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    /// Close connections from which nothing was received for this long.
+    /// `None` to keep them open
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> ConfigBuilder {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Number of times a write failing with a transient error is
+    /// retried before closing the connection
+    pub fn write_retries(mut self, write_retries: u32) -> ConfigBuilder {
+        self.config.write_retries = write_retries;
+        self
+    }
+
+    /// File to send in chunks once connected, in client mode
+    pub fn file(mut self, file: Option<PathBuf>) -> ConfigBuilder {
+        self.config.file = file;
+        self
+    }
+
+    /// Directory where each received message is written to its own file
+    pub fn dump_dir(mut self, dump_dir: Option<PathBuf>) -> ConfigBuilder {
+        self.config.dump_dir = dump_dir;
+        self
+    }
+
+    /// Transcript sent instead of reading stdin, in client mode. The
+    /// client disconnects once it's sent
+    pub fn replay(mut self, replay: Option<PathBuf>) -> ConfigBuilder {
+        self.config.replay = replay;
+        self
+    }
+
+    /// Format of the transcript
+    pub fn replay_format(mut self, replay_format: ReplayFormat) -> ConfigBuilder {
+        self.config.replay_format = replay_format;
+        self
+    }
+
+    /// Divide the delays between the messages of the transcript
+    pub fn replay_speed(mut self, replay_speed: f64) -> ConfigBuilder {
+        self.config.replay_speed = replay_speed;
+        self
+    }
+
+    /// Number of messages the client sends before disconnecting, `None`
+    /// for no limit
+    pub fn send_limit(mut self, send_limit: Option<usize>) -> ConfigBuilder {
+        self.config.send_limit = send_limit;
+        self
+    }
+
+    /// Kinds the server forwards to the client, all when `None`
+    pub fn subscribe(mut self, subscribe: Option<Vec<Kind>>) -> ConfigBuilder {
+        self.config.subscribe = subscribe;
+        self
+    }
+
+    /// Maximum number of bytes of a message printed, 0 for no limit
+    pub fn max_print(mut self, max_print: usize) -> ConfigBuilder {
+        self.config.max_print = max_print;
+        self
+    }
+
+    /// Print the framing of each message sent
+    pub fn show_framing(mut self, show_framing: bool) -> ConfigBuilder {
+        self.config.show_framing = show_framing;
+        self
+    }
+
+    /// Print the header bytes of the received messages
+    pub fn show_header(mut self, show_header: bool) -> ConfigBuilder {
+        self.config.show_header = show_header;
+        self
+    }
+
+    /// Number of connections opened by the client
+    pub fn connections(mut self, connections: usize) -> ConfigBuilder {
+        self.config.connections = connections;
+        self
+    }
+
+    /// How the client sends the input over its connections
+    pub fn fanout(mut self, fanout: Fanout) -> ConfigBuilder {
+        self.config.fanout = fanout;
+        self
+    }
+
+    /// What to do with a peer reading slower than we write
+    pub fn slow_peer(mut self, slow_peer: SlowPeer) -> ConfigBuilder {
+        self.config.slow_peer = slow_peer;
+        self
+    }
+
+    /// How long a peer can stay over the high watermark with
+    /// [`SlowPeer::Drop`]
+    pub fn slow_peer_timeout(mut self, slow_peer_timeout: Duration) -> ConfigBuilder {
+        self.config.slow_peer_timeout = slow_peer_timeout;
+        self
+    }
+
+    /// Port serving the metrics over HTTP, in server mode
+    pub fn metrics_port(mut self, metrics_port: Option<u16>) -> ConfigBuilder {
+        self.config.metrics_port = metrics_port;
+        self
+    }
+
+    /// Unix socket taking the admin commands, in server mode
+    pub fn admin_socket(mut self, admin_socket: Option<PathBuf>) -> ConfigBuilder {
+        self.config.admin_socket = admin_socket;
+        self
+    }
+
+    /// Interval between two status lines printed by the server. `None`
+    /// to print none
+    pub fn status_interval(mut self, status_interval: Option<Duration>) -> ConfigBuilder {
+        self.config.status_interval = status_interval;
+        self
+    }
+
+    /// Time after which the session ends, `None` to run until stopped
+    pub fn duration(mut self, duration: Option<Duration>) -> ConfigBuilder {
+        self.config.duration = duration;
+        self
+    }
+
+    /// File where the roundtrips are written
+    pub fn rtt_csv(mut self, rtt_csv: Option<RttCsv>) -> ConfigBuilder {
+        self.config.rtt_csv = rtt_csv;
+        self
+    }
+
+    /// File where the connection events are written
+    pub fn event_log(mut self, event_log: Option<EventLog>) -> ConfigBuilder {
+        self.config.event_log = event_log;
+        self
+    }
+
+    /// Tell the peers when we are typing, and relay it in server mode
+    pub fn typing_indicators(mut self, typing_indicators: bool) -> ConfigBuilder {
+        self.config.typing_indicators = typing_indicators;
+        self
+    }
+
+    /// Send an empty message when stdin is empty and not a terminal, or
+    /// for the empty lines with `--lines`
+    pub fn allow_empty(mut self, allow_empty: bool) -> ConfigBuilder {
+        self.config.allow_empty = allow_empty;
+        self
+    }
+
+    /// Read the terminal once a piped stdin ends
+    pub fn follow_input(mut self, follow_input: bool) -> ConfigBuilder {
+        self.config.follow_input = follow_input;
+        self
+    }
+
+    /// Send what a piped stdin gives as it comes, without waiting for
+    /// its end
+    pub fn follow(mut self, follow: bool) -> ConfigBuilder {
+        self.config.follow = follow;
+        self
+    }
+
+    /// Send each line of the input as a message
+    pub fn lines(mut self, lines: bool) -> ConfigBuilder {
+        self.config.lines = lines;
+        self
+    }
+
+    /// Keep the end of line of the messages with `lines`
+    pub fn keep_newline(mut self, keep_newline: bool) -> ConfigBuilder {
+        self.config.keep_newline = keep_newline;
+        self
+    }
+
+    /// Inputs received within this delay of each other are sent as a
+    /// single message. `None` to send each one
+    pub fn coalesce(mut self, coalesce: Option<Duration>) -> ConfigBuilder {
+        self.config.coalesce = coalesce;
+        self
+    }
+
+    /// Kinds of the received messages answered with a response
+    pub fn ack_kinds(mut self, ack_kinds: Vec<Kind>) -> ConfigBuilder {
+        self.config.ack_kinds = ack_kinds;
+        self
+    }
+
+    /// Sent by the server to each client once connected
+    pub fn banner(mut self, banner: Option<Vec<u8>>) -> ConfigBuilder {
+        self.config.banner = banner;
+        self
+    }
+
+    /// Maximum total length of the replayed messages
+    pub fn history_bytes(mut self, history_bytes: usize) -> ConfigBuilder {
+        self.config.history_bytes = history_bytes;
+        self
+    }
+
+    /// The server drops the text messages matching it instead of
+    /// relaying them
+    pub fn filter(mut self, filter: Option<Regex>) -> ConfigBuilder {
+        self.config.filter = filter;
+        self
+    }
+
+    /// Only check that the server can listen, or that the host of the
+    /// client resolves, and exit
+    pub fn check(mut self, check: bool) -> ConfigBuilder {
+        self.config.check = check;
+        self
+    }
+
+    /// Only list the kinds of messages, and exit
+    pub fn list_kinds(mut self, list_kinds: bool) -> ConfigBuilder {
+        self.config.list_kinds = list_kinds;
+        self
+    }
+
+    /// Config file given with `--config`, read again on SIGHUP
+    pub fn config_file(mut self, config_file: Option<PathBuf>) -> ConfigBuilder {
+        self.config.config_file = config_file;
+        self
+    }
+
+    /// Recipients notified of the roundtrips and received messages
+    #[allow(dead_code)]
+    pub fn hooks(mut self, hooks: Hooks) -> ConfigBuilder {
+        self.config.hooks = hooks;
+        self
+    }
+
+    /// Return the [`Config`]
+    pub fn build(self) -> Config {
+        let mut config = self.config;
+        config.label = match self.label {
+            Some(label) => label,
+            None if config.is_client => "client".to_owned(),
+            None => "server".to_owned()
+        };
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+    use super::ConfigBuilder;
+    use Display;
+
+    #[test]
+    fn same_defaults_as_the_command_line() {
+        let parsed = ::config_from(&::app().get_matches_from(vec!["chat", "--color", "never"]));
+        let built = ConfigBuilder::new().build();
+
+        // Config can't be compared, but its Debug output can
+        assert_eq!(format!("{:?}", built), format!("{:?}", parsed));
+    }
+
+    #[test]
+    fn invalid_options_returned() {
+        // Returned for a reload instead of exiting
+        let args = ::app().get_matches_from(vec!["chat", "--framing", "newline", "--varint"]);
+        assert!(::builder_from(&args).unwrap_err().contains("--varint"));
+        let args = ::app().get_matches_from(vec!["chat", "--banner", "hello world", "--max-message", "5"]);
+        assert!(::builder_from(&args).unwrap_err().contains("--max-message"));
+    }
+
+    #[test]
+    fn label_follows_the_mode() {
+        assert_eq!(ConfigBuilder::new().build().label, "server");
+        assert_eq!(ConfigBuilder::new().client(true).build().label, "client");
+        assert_eq!(ConfigBuilder::new().client(true).label("alice").build().label, "alice");
+    }
+
+    #[test]
+    fn setters() {
+        let config = ConfigBuilder::new()
+            .client(true)
+            .host("example.com")
+            .port(4000)
+            .display(Display::Utf8)
+            .message(b"hello")
+            .wait_response(Duration::from_secs(3))
+            .build();
+
+        assert!(config.is_client);
+        assert_eq!(config.host, "example.com");
+        assert_eq!(config.ports, vec![4000]);
+        assert!(matches!(config.display, Display::Utf8));
+        assert_eq!(config.message, Some(b"hello".to_vec()));
+        assert!(config.wait_response);
+        assert_eq!(config.response_timeout, Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,186 @@
+//! Pre-shared key encryption of the payloads
+//!
+//! Payloads are encrypted with AES-256 in CTR mode. The key is the
+//! SHA-256 of the pre-shared key given with `--psk`, both sides must
+//! use the same one.
+//!
+//! A random nonce is generated for each message and sent in front of
+//! the encrypted payload:
+//! - Nonce: 16 bytes
+//! - Encrypted payload
+//!
+//! There is no authentication: a wrong key decrypts to garbage.
+//!
+//! Authentication is separate, with `--hmac-key`: an HMAC-SHA256 tag
+//! of the frame is appended to it, see [`HmacKey`].
+
+use std::fmt;
+use aes::Aes256;
+use ctr::Ctr128BE;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use sha2::{Digest, Sha256};
+use rand;
+
+/// Length of the nonce in front of each encrypted payload
+pub const NONCE_LEN: usize = 16;
+
+/// Key shared by both sides
+#[derive(Clone)]
+pub struct Psk {
+    key: [u8; 32]
+}
+
+impl Psk {
+    /// Derive the key from the pre-shared key
+    pub fn new(secret: &str) -> Psk {
+        let mut key = [0; 32];
+        key.copy_from_slice(&Sha256::digest(secret.as_bytes()));
+        Psk { key }
+    }
+
+    /// Apply the keystream of `nonce` on `data`
+    fn apply(&self, nonce: &[u8], data: &mut [u8]) {
+        let mut cipher = Ctr128BE::<Aes256>::new(self.key[..].into(), nonce.into());
+        cipher.apply_keystream(data);
+    }
+
+    /// Encrypt `data`, return the nonce followed by the encrypted data
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+
+        let mut output = Vec::with_capacity(NONCE_LEN + data.len());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(data);
+        self.apply(&nonce, &mut output[NONCE_LEN..]);
+        output
+    }
+
+    /// Decrypt a payload made by [`Psk::encrypt`]
+    ///
+    /// A payload too short to contain a nonce can't come from
+    /// [`Psk::encrypt`], it is returned unchanged.
+    pub fn decrypt(&self, payload: &[u8]) -> Vec<u8> {
+        if payload.len() < NONCE_LEN {
+            return payload.to_vec();
+        }
+
+        let (nonce, data) = payload.split_at(NONCE_LEN);
+        let mut output = data.to_vec();
+        self.apply(nonce, &mut output);
+        output
+    }
+}
+
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key
+        write!(f, "Psk")
+    }
+}
+
+/// Length of an HMAC-SHA256 tag
+pub const TAG_LEN: usize = 32;
+
+/// Block size of SHA-256
+const BLOCK_LEN: usize = 64;
+
+/// Key signing the frames with HMAC-SHA256 (RFC 2104)
+#[derive(Clone)]
+pub struct HmacKey {
+    /// The key xored with the inner padding
+    inner: [u8; BLOCK_LEN],
+    /// The key xored with the outer padding
+    outer: [u8; BLOCK_LEN]
+}
+
+impl HmacKey {
+    /// Prepare the key from the secret, a long secret is hashed first
+    pub fn new(secret: &[u8]) -> HmacKey {
+        let mut key = [0; BLOCK_LEN];
+        if secret.len() > BLOCK_LEN {
+            key[..32].copy_from_slice(&Sha256::digest(secret));
+        } else {
+            key[..secret.len()].copy_from_slice(secret);
+        }
+
+        let mut inner = [0x36; BLOCK_LEN];
+        let mut outer = [0x5C; BLOCK_LEN];
+        for (index, byte) in key.iter().enumerate() {
+            inner[index] ^= byte;
+            outer[index] ^= byte;
+        }
+        HmacKey { inner, outer }
+    }
+
+    /// Return the tag of `data`
+    pub fn tag(&self, data: &[u8]) -> [u8; TAG_LEN] {
+        let inner = Sha256::new().chain_update(&self.inner[..])
+                                 .chain_update(data)
+                                 .finalize();
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(&Sha256::new().chain_update(&self.outer[..])
+                                          .chain_update(inner)
+                                          .finalize());
+        tag
+    }
+
+    /// Return true if `tag` is the tag of `data`
+    ///
+    /// The comparison takes the same time wherever the tags differ.
+    pub fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let expected = self.tag(data);
+        tag.len() == TAG_LEN
+            && expected.iter().zip(tag).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl fmt::Debug for HmacKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key
+        write!(f, "HmacKey")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{HmacKey, Psk, NONCE_LEN};
+
+    #[test]
+    fn roundtrip() {
+        let psk = Psk::new("secret");
+        let encrypted = psk.encrypt(b"hello");
+
+        assert_eq!(encrypted.len(), NONCE_LEN + 5);
+        assert_ne!(&encrypted[NONCE_LEN..], b"hello");
+        assert_eq!(psk.decrypt(&encrypted), b"hello");
+    }
+
+    #[test]
+    fn wrong_key() {
+        let encrypted = Psk::new("secret").encrypt(b"hello");
+
+        assert_ne!(Psk::new("other").decrypt(&encrypted), b"hello");
+    }
+
+    #[test]
+    fn hmac_rfc4231() {
+        // Test case 2 of RFC 4231
+        let tag = HmacKey::new(b"Jefe").tag(b"what do ya want for nothing?");
+        let hex: String = tag.iter().map(|b| format!("{:02x}", b)).collect();
+
+        assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn hmac_verify() {
+        let key = HmacKey::new(b"secret");
+        let mut tag = key.tag(b"hello");
+
+        assert!(key.verify(b"hello", &tag));
+        assert!(!HmacKey::new(b"other").verify(b"hello", &tag));
+        tag[0] ^= 1;
+        assert!(!key.verify(b"hello", &tag));
+        assert!(!key.verify(b"hello", &tag[..4]));
+    }
+}
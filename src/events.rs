@@ -0,0 +1,159 @@
+//! Session events written to a file with `--event-log`
+//!
+//! Each line is a JSON object with:
+//! - time: When the event happened, in seconds since the Unix epoch
+//! - event: `connect`, `rejected`, `disconnect`, `message`, `nick` or
+//!   `error`
+//! - id: Id of the connection, absent for `rejected`
+//! - addr: Address of the peer, `null` when unknown
+//! - len: Length of the message, for `message`
+//! - nick: Nickname registered by the client, for `nick`
+//! - reason: Why the connection closed or failed, for `disconnect`
+//!   and `error`
+
+use std::fmt::Write as FmtWrite;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Something that happened to a connection
+pub enum Event<'a> {
+    /// A connection was accepted
+    Connect { id: usize, addr: Option<SocketAddr> },
+    /// A connection was refused by `--allow` or `--deny`
+    Rejected { addr: SocketAddr },
+    /// A connection closed
+    Disconnect { id: usize, addr: Option<SocketAddr>, reason: Option<&'a str> },
+    /// A message was received
+    Message { id: usize, addr: Option<SocketAddr>, len: usize },
+    /// A client registered a nickname
+    Nick { id: usize, addr: Option<SocketAddr>, nick: &'a str },
+    /// Something went wrong on a connection
+    Error { id: usize, addr: Option<SocketAddr>, reason: &'a str }
+}
+
+impl<'a> Event<'a> {
+    /// Return the event as a JSON object, on one line
+    fn to_json(&self, time: Duration) -> String {
+        let mut json = format!("{{\"time\":{}.{:06}", time.as_secs(), time.subsec_micros());
+
+        let (name, id, addr) = match *self {
+            Event::Connect { id, addr } => ("connect", Some(id), addr),
+            Event::Rejected { addr } => ("rejected", None, Some(addr)),
+            Event::Disconnect { id, addr, .. } => ("disconnect", Some(id), addr),
+            Event::Message { id, addr, .. } => ("message", Some(id), addr),
+            Event::Nick { id, addr, .. } => ("nick", Some(id), addr),
+            Event::Error { id, addr, .. } => ("error", Some(id), addr)
+        };
+
+        let _ = write!(json, ",\"event\":\"{}\"", name);
+        if let Some(id) = id {
+            let _ = write!(json, ",\"id\":{}", id);
+        }
+        match addr {
+            Some(addr) => { let _ = write!(json, ",\"addr\":\"{}\"", addr); }
+            None => json.push_str(",\"addr\":null")
+        }
+
+        match *self {
+            Event::Message { len, .. } => { let _ = write!(json, ",\"len\":{}", len); }
+            Event::Nick { nick, .. } => { let _ = write!(json, ",\"nick\":\"{}\"", escape(nick)); }
+            Event::Disconnect { reason: Some(reason), .. }
+            | Event::Error { reason, .. } => {
+                let _ = write!(json, ",\"reason\":\"{}\"", escape(reason));
+            }
+            _ => ()
+        }
+
+        json.push('}');
+        json
+    }
+}
+
+/// Escape `s` to be used in a JSON string
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(escaped, "\\u{:04x}", c as u32); }
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Append-only file of events, shared by the server and all the peers
+#[derive(Clone)]
+pub struct EventLog {
+    file: Arc<Mutex<File>>
+}
+
+impl EventLog {
+    /// Open `path` to append to it
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(EventLog { file: Arc::new(Mutex::new(file)) })
+    }
+
+    /// Write an event, flushed right away
+    pub fn record(&self, event: &Event) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut line = event.to_json(time);
+        line.push('\n');
+
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(_) => return
+        };
+
+        // One write per line, so lines are never interleaved
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            println!("Can not write the event: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventLog")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+    use super::Event;
+
+    #[test]
+    fn json_lines() {
+        let time = Duration::from_millis(1500);
+        let addr = Some("127.0.0.1:4000".parse().unwrap());
+
+        assert_eq!(
+            Event::Connect { id: 3, addr }.to_json(time),
+            r#"{"time":1.500000,"event":"connect","id":3,"addr":"127.0.0.1:4000"}"#
+        );
+        assert_eq!(
+            Event::Message { id: 3, addr: None, len: 12 }.to_json(time),
+            r#"{"time":1.500000,"event":"message","id":3,"addr":null,"len":12}"#
+        );
+        assert_eq!(
+            Event::Nick { id: 3, addr: None, nick: "alice" }.to_json(time),
+            r#"{"time":1.500000,"event":"nick","id":3,"addr":null,"nick":"alice"}"#
+        );
+        assert_eq!(
+            Event::Disconnect { id: 3, addr, reason: Some("say \"bye\"\n") }.to_json(time),
+            r#"{"time":1.500000,"event":"disconnect","id":3,"addr":"127.0.0.1:4000","reason":"say \"bye\"\n"}"#
+        );
+    }
+}
@@ -18,7 +18,6 @@
 //! - Any data can be send: binary, text (any encoding)
 //! 
 
-#[macro_use]
 extern crate actix;
 extern crate futures;
 extern crate tokio_tcp;
@@ -29,23 +28,98 @@ extern crate bytes;
 extern crate byteorder;
 extern crate atty;
 extern crate clap;
+extern crate aes;
+extern crate ctr;
+extern crate sha2;
+extern crate rand;
+extern crate toml;
+extern crate socket2;
+extern crate tokio_signal;
+extern crate regex;
+extern crate trust_dns_resolver;
 
 use std::str::FromStr;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::net::SocketAddr;
+use std::ffi::OsString;
+use std::fs;
+use std::cell::RefCell;
+use std::rc::Rc;
 use actix::prelude::*;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, ErrorKind};
+use regex::Regex;
 
 mod server;
 mod client;
 mod reader;
 mod peer;
 mod user;
+mod transfer;
+mod crypto;
+mod cidr;
+mod metrics;
+mod admin;
+mod replay;
+mod rtt;
+mod events;
+mod config;
+mod socks;
+#[cfg(test)]
+mod memory;
+#[cfg(test)]
+mod tests;
 
 use client::Client;
 use server::Server;
+use reader::{Endian, Framing, Kind, LengthEncoding};
+use crypto::{HmacKey, Psk};
+use rtt::RttCsv;
+use events::EventLog;
+use cidr::Cidr;
+use peer::Hooks;
+use config::ConfigBuilder;
+use socks::Proxy;
 
 /// Maximum allowed message length
 pub const MESSAGE_MAX_LEN: u64 = 0x0001_0000_0000_0000;
 
+/// Options relying on the header of the messages, not available with
+/// `--raw` or `--framing newline`
+const HEADER_OPTIONS: &[&str] = &["varint", "psk", "hmac-key", "window", "file", "echo",
+                                  "wait-response", "typing-indicators", "show-framing", "show-header", "nick",
+                                  "detect-loops", "banner", "one-way-latency",
+                                  "advertise-max-message", "subscribe", "handshake-timeout"];
+
+/// Exit code of the process, for scripts and supervisors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    /// Clean shutdown
+    Success = 0,
+    /// Invalid configuration or input, or no response with
+    /// `--wait-response`
+    Failure = 1,
+    /// The server can't bind its ports
+    Bind = 2,
+    /// The client can't connect to the server
+    Connect = 3,
+    /// The connection was closed because of invalid data
+    Protocol = 4,
+    /// The server closed or dropped the connection, without
+    /// `--reconnect`
+    Disconnected = 5,
+    /// The client gave up reconnecting after `--max-reconnects`
+    /// attempts
+    GaveUp = 6
+}
+
+impl ExitCode {
+    /// Exit the process with this code
+    pub fn exit(self) -> ! {
+        ::std::process::exit(self as i32)
+    }
+}
+
 /// How to display received messages
 #[derive(Debug, Clone)]
 pub enum Display {
@@ -53,45 +127,448 @@ pub enum Display {
     Binary,
     /// Display data as utf8 if possible, otherwise as binary
     Utf8,
+    /// Display data as utf8, invalid sequences are replaced with U+FFFD
+    Utf8Lossy,
+    /// Display data as utf16 if possible, otherwise as binary.
+    /// The endianness is given by the BOM, big endian without it
+    Utf16,
+    /// Display data as `hexdump -C`: offset, hexadecimal bytes and
+    /// ASCII
+    HexDump,
     /// Don't display data
     None
 }
 
-impl<'a> From<&'a str> for Display {
+impl Display {
+    /// Names of the modes, as given to `--display`
+    pub const NAMES: [&'static str; 6] = ["binary", "utf8", "utf8-lossy", "utf16", "hexdump", "none"];
+
+    /// Name of the mode, as given to `--display`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Display::Binary => "binary",
+            Display::Utf8 => "utf8",
+            Display::Utf8Lossy => "utf8-lossy",
+            Display::Utf16 => "utf16",
+            Display::HexDump => "hexdump",
+            Display::None => "none"
+        }
+    }
+}
+
+impl From<&str> for Display {
     fn from(s: &str) -> Display {
         match s {
             "binary" => Display::Binary,
             "utf8" => Display::Utf8,
+            "utf8-lossy" => Display::Utf8Lossy,
+            "utf16" => Display::Utf16,
+            "hexdump" => Display::HexDump,
             _ => Display:: None
         }
     }
 }
 
+/// How the client spreads the user input over its connections
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fanout {
+    /// Send each input on every connection
+    Broadcast,
+    /// Send each input on the next connection, in turn
+    RoundRobin
+}
+
+impl From<&str> for Fanout {
+    fn from(s: &str) -> Fanout {
+        match s {
+            "round-robin" => Fanout::RoundRobin,
+            _ => Fanout::Broadcast
+        }
+    }
+}
+
+/// What to do with a peer reading slower than we write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowPeer {
+    /// Wait for the peer to read, once the write buffer is full
+    Block,
+    /// Close the connection when the write buffer stays full too long
+    Drop
+}
+
+impl From<&str> for SlowPeer {
+    fn from(s: &str) -> SlowPeer {
+        match s {
+            "drop" => SlowPeer::Drop,
+            _ => SlowPeer::Block
+        }
+    }
+}
+
+/// What the server does when a peer can't take a relayed message
+/// because its mailbox is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayFull {
+    /// Skip the message for this peer
+    Drop,
+    /// Close the connection with this peer
+    Disconnect
+}
+
+impl From<&str> for RelayFull {
+    fn from(s: &str) -> RelayFull {
+        match s {
+            "disconnect" => RelayFull::Disconnect,
+            _ => RelayFull::Drop
+        }
+    }
+}
+
+/// IP version of the addresses, chosen with `--ipv4` or `--ipv6`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpVersion {
+    /// IPv4 for the server, any address for the client
+    Any,
+    /// IPv4 only
+    V4,
+    /// IPv6 only
+    V6
+}
+
+impl IpVersion {
+    /// Return true if `addr` is of this version
+    pub fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpVersion::Any => true,
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6()
+        }
+    }
+}
+
+/// Which connections are from the same source with `--one-per-ip`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Duplicate {
+    /// Same IP address, any port
+    Ip,
+    /// Same IP address and port
+    Addr
+}
+
+impl Duplicate {
+    /// Return true if connections from `a` and `b` are from the same
+    /// source
+    pub fn matches(self, a: SocketAddr, b: SocketAddr) -> bool {
+        match self {
+            Duplicate::Ip => a.ip() == b.ip(),
+            Duplicate::Addr => a == b
+        }
+    }
+}
+
+impl From<&str> for Duplicate {
+    fn from(s: &str) -> Duplicate {
+        match s {
+            "addr" => Duplicate::Addr,
+            _ => Duplicate::Ip
+        }
+    }
+}
+
+/// What to do with an input message longer than `--max-message`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oversize {
+    /// Send it in several messages, independent of each other: the
+    /// receiver doesn't know they were one
+    Split,
+    /// Send only its beginning
+    Truncate,
+    /// Don't send it. When stdin is not a terminal, exit with an error
+    Reject
+}
+
+impl From<&str> for Oversize {
+    fn from(s: &str) -> Oversize {
+        match s {
+            "truncate" => Oversize::Truncate,
+            "reject" => Oversize::Reject,
+            _ => Oversize::Split
+        }
+    }
+}
+
+/// What to do with a response received while no message waits for one,
+/// with `--on-unsolicited-response`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unsolicited {
+    /// Print it as a warning, without a roundtrip time
+    Warn,
+    /// Drop it silently
+    Ignore,
+    /// Close the connection with a protocol error
+    Close
+}
+
+impl From<&str> for Unsolicited {
+    fn from(s: &str) -> Unsolicited {
+        match s {
+            "ignore" => Unsolicited::Ignore,
+            "close" => Unsolicited::Close,
+            _ => Unsolicited::Warn
+        }
+    }
+}
+
+/// Format of the transcript sent with `--replay`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayFormat {
+    /// One message per line, with an optional time
+    Lines,
+    /// Messages framed like on the wire
+    Framed
+}
+
+impl From<&str> for ReplayFormat {
+    fn from(s: &str) -> ReplayFormat {
+        match s {
+            "framed" => ReplayFormat::Framed,
+            _ => ReplayFormat::Lines
+        }
+    }
+}
+
 /// Chat configuration
 ///
-/// The structure is filled with the command line arguments
+/// The structure is filled with the command line arguments, or built
+/// with a [`ConfigBuilder`]
 #[derive(Debug, Clone)]
 pub struct Config {
     /// Run in client mode
     pub is_client: bool,
+    /// Printed in front of the peer lines, `client` or `server` by
+    /// default
+    pub label: String,
     /// Host address/hostname
     pub host: String,
-    /// Port
-    pub port: u16,
+    /// Ports the server listens on, the client connects to the first one
+    pub ports: Vec<u16>,
+    /// Stop the server if one of the ports can't be bound
+    pub require_all_ports: bool,
+    /// Listen on the sockets passed by systemd, fail if there are none
+    pub systemd: bool,
+    /// IP version the server listens on, or the client connects with
+    pub ip_version: IpVersion,
+    /// Network interface the server listens on, all when `None`
+    pub interface: Option<String>,
+    /// How long to retry binding a port already in use. `None` to
+    /// give up right away
+    pub bind_retry: Option<Duration>,
+    /// Maximum number of connections waiting to be accepted
+    pub backlog: i32,
+    /// Addresses the server accepts connections from, all when empty
+    pub allow: Vec<Cidr>,
+    /// Addresses the server rejects connections from
+    pub deny: Vec<Cidr>,
+    /// Reject a connection from the same source as a connected peer
+    pub one_per_ip: Option<Duplicate>,
     /// Display mode
-    pub display: Display
+    pub display: Display,
+    /// Display mode of the responses, binary when not set
+    pub display_response: Option<Display>,
+    /// The received messages are parts of a stream: a utf8 character
+    /// cut at the end of a message is displayed with the next one
+    pub display_stream: bool,
+    /// Print the received messages even with `--display none`
+    pub tee: bool,
+    /// Low and high watermarks of the socket write buffer, in bytes
+    ///
+    /// When a write would block and more than `high` bytes are pending,
+    /// the [`peer::Peer`] stops processing messages until the buffer
+    /// drains below `low`.
+    /// `(0, 0)` pauses the peer as soon as anything is pending: lowest
+    /// latency, but one syscall per message.
+    /// Larger values let the peer keep queueing and batch writes together.
+    pub write_buffer: (usize, usize),
+    /// Initial capacity of the buffer where received data is read, in bytes
+    pub read_buffer: usize,
+    /// Maximum time to wait for the connection to the server
+    pub connect_timeout: Duration,
+    /// Delay before reconnecting when the connection is lost or can not
+    /// be established. `None` to exit instead
+    pub reconnect: Option<Duration>,
+    /// Number of failed reconnections in a row after which the client
+    /// gives up. `None` to retry forever
+    pub max_reconnects: Option<u32>,
+    /// SOCKS5 proxy the client connects through
+    pub proxy: Option<Proxy>,
+    /// Maximum number of messages kept while the client is disconnected
+    pub max_queue: usize,
+    /// Maximum number of messages waiting for their response, the next
+    /// ones wait to be sent. 0 for no limit
+    pub window: usize,
+    /// Idle time before TCP keepalive probes are sent. `None` to
+    /// disable them
+    pub tcp_keepalive: Option<Duration>,
+    /// Close connections from which nothing was received for this long.
+    /// `None` to keep them open
+    pub idle_timeout: Option<Duration>,
+    /// Close the connections of the clients not sending a
+    /// [`Kind::Hello`] within this delay, in server mode. `None` to wait
+    /// forever
+    pub handshake_timeout: Option<Duration>,
+    /// Maximum number of bytes written per second. `None` for no limit
+    pub throttle: Option<usize>,
+    /// Maximum time to write the pending messages when closing
+    pub drain_timeout: Duration,
+    /// Number of times a write failing with a transient error is
+    /// retried before closing the connection
+    pub write_retries: u32,
+    /// File to send in chunks once connected, in client mode
+    pub file: Option<PathBuf>,
+    /// Directory where the received files are written
+    pub download_dir: PathBuf,
+    /// Bytes of files accepted per connection, `None` refuses them
+    pub receive_files: Option<u64>,
+    /// Directory where each received message is written to its own
+    /// file
+    pub dump_dir: Option<PathBuf>,
+    /// Message sent once connected instead of reading stdin, in client
+    /// mode. The client disconnects after sending it
+    pub message: Option<Vec<u8>>,
+    /// Transcript sent instead of reading stdin, in client mode. The
+    /// client disconnects once it's sent
+    pub replay: Option<PathBuf>,
+    /// Format of the transcript
+    pub replay_format: ReplayFormat,
+    /// Divide the delays between the messages of the transcript
+    pub replay_speed: f64,
+    /// Number of messages the client sends before disconnecting,
+    /// `None` for no limit
+    pub send_limit: Option<usize>,
+    /// Nickname registered on the server, in client mode
+    pub nick: Option<String>,
+    /// Kinds the server forwards to the client, all when `None`
+    pub subscribe: Option<Vec<Kind>>,
+    /// Wait for the response to `message` before disconnecting
+    pub wait_response: bool,
+    /// Maximum time to wait for the response with `wait_response`
+    pub response_timeout: Duration,
+    /// How messages are framed
+    pub framing: Framing,
+    /// Print with colors
+    pub color: bool,
+    /// Maximum number of bytes of a message printed, 0 for no limit
+    pub max_print: usize,
+    /// Print the framing of each message sent
+    pub show_framing: bool,
+    /// Print the header bytes of the received messages
+    pub show_header: bool,
+    /// Number of connections opened by the client
+    pub connections: usize,
+    /// How the client sends the input over its connections
+    pub fanout: Fanout,
+    /// What to do with a peer reading slower than we write
+    pub slow_peer: SlowPeer,
+    /// How long a peer can stay over the high watermark with
+    /// [`SlowPeer::Drop`]
+    pub slow_peer_timeout: Duration,
+    /// What to do with a peer whose mailbox is full when relaying, in
+    /// server mode
+    pub relay_full: RelayFull,
+    /// Port serving the metrics over HTTP, in server mode
+    pub metrics_port: Option<u16>,
+    /// Unix socket taking the admin commands, in server mode
+    pub admin_socket: Option<PathBuf>,
+    /// Interval between two status lines printed by the server. `None`
+    /// to print none
+    pub status_interval: Option<Duration>,
+    /// Time after which the session ends, `None` to run until stopped
+    pub duration: Option<Duration>,
+    /// File where the roundtrips are written
+    pub rtt_csv: Option<RttCsv>,
+    /// File where the connection events are written
+    pub event_log: Option<EventLog>,
+    /// Tell the peers when we are typing, and relay it in server mode
+    pub typing_indicators: bool,
+    /// Random number sent once connected with `--detect-loops`, the
+    /// connection is closed when it comes back
+    pub session_nonce: Option<u64>,
+    /// Send an empty message when stdin is empty and not a terminal,
+    /// or for the empty lines with `--lines`
+    pub allow_empty: bool,
+    /// Maximum length of a message read on stdin
+    pub max_message: usize,
+    /// What to do with a message longer than `max_message`
+    pub on_oversize: Oversize,
+    /// What to do with a response when no message waits for one
+    pub on_unsolicited_response: Unsolicited,
+    /// Tell the peers `max_message` once connected. The messages sent
+    /// to a peer taking less are split, truncated or rejected
+    /// according to `on_oversize`
+    pub advertise_max_message: bool,
+    /// Don't read stdin, the messages are only received or sent with
+    /// [`user::UserInput`]
+    pub no_stdin: bool,
+    /// Read the terminal once a piped stdin ends
+    pub follow_input: bool,
+    /// Send what a piped stdin gives as it comes, without waiting for
+    /// its end
+    pub follow: bool,
+    /// Send each line of the input as a message
+    pub lines: bool,
+    /// Keep the end of line of the messages with `lines`
+    pub keep_newline: bool,
+    /// Inputs received within this delay of each other are sent as a
+    /// single message. `None` to send each one
+    pub coalesce: Option<Duration>,
+    /// Answer each message with its own payload, in server mode
+    pub echo: bool,
+    /// Kinds of the received messages answered with a response
+    pub ack_kinds: Vec<Kind>,
+    /// Sent by the server to each client once connected
+    pub banner: Option<Vec<u8>>,
+    /// Number of messages the server replays to new clients
+    pub history: usize,
+    /// Maximum total length of the replayed messages
+    pub history_bytes: usize,
+    /// The server drops the text messages matching it instead of
+    /// relaying them
+    pub filter: Option<Regex>,
+    /// Recipients notified of the roundtrips and received messages
+    pub hooks: Hooks,
+    /// Config file given with `--config`, read again on SIGHUP
+    pub config_file: Option<PathBuf>,
+    /// Only check that the server can listen, or that the host of the
+    /// client resolves, and exit
+    pub check: bool,
+    /// Only list the kinds of messages, and exit
+    pub list_kinds: bool
 }
 
-/// Read command line arguments and return a [`Config`]
-fn get_config() -> Config {
-    let args = App::new("chat")
+/// Command line arguments definition
+fn app() -> App<'static, 'static> {
+    App::new("chat")
         .version("1.0")
         .author("Sebastien Chapuis. <sebastien@chapu.is>")
         .about("A chat client/server")
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("path")
+             .help(
+"TOML file with default values for the other options.
+Keys are the long option names, e.g. host = \"example.com\".
+Options given on the command line or with environment
+variables take precedence.\n")
+             .takes_value(true))
         .arg(Arg::with_name("client")
              .short("c")
              .long("client")
              .help("Run as client"))
+        .arg(Arg::with_name("label")
+             .long("label")
+             .value_name("name")
+             .help("Printed in front of the messages, \"client\" or \"server\" by default")
+             .takes_value(true))
         .arg(Arg::with_name("host")
              .short("H")
              .long("host")
@@ -101,46 +578,1145 @@ fn get_config() -> Config {
         .arg(Arg::with_name("port")
              .short("p")
              .long("port")
-             .help("Port number to bind/listen")
+             .help("Port number to bind/listen. The server accepts a comma separated list")
              .takes_value(true)
-             .validator(|s| u16::from_str(&s)
-                        .map_err(|_| "Should be a number between 0 and 65535".to_owned())
-                        .map(|_| ()))
+             .validator(|s| s.split(',')
+                        .try_for_each(|p| u16::from_str(p.trim()).map(|_| ()))
+                        .map_err(|_| "Should be a number between 0 and 65535".to_owned()))
              .default_value("12345"))
+        .arg(Arg::with_name("ipv4")
+             .short("4")
+             .long("ipv4")
+             .conflicts_with("ipv6")
+             .help("Only use IPv4: the server listens on IPv4, the client only connects to the IPv4 addresses of the host"))
+        .arg(Arg::with_name("ipv6")
+             .short("6")
+             .long("ipv6")
+             .help("Only use IPv6: the server listens on IPv6, the client only connects to the IPv6 addresses of the host"))
+        .arg(Arg::with_name("require-all-ports")
+             .long("require-all-ports")
+             .help("Stop the server if one of the ports can't be bound, instead of using the others"))
+        .arg(Arg::with_name("interface")
+             .long("interface")
+             .value_name("name")
+             .help("Listen only on this network interface, like eth0 (Linux only)")
+             .takes_value(true))
+        .arg(Arg::with_name("bind-retry")
+             .long("bind-retry")
+             .value_name("secs")
+             .help("Keep trying to bind a port already in use for this many seconds")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of seconds".to_owned())))
+        .arg(Arg::with_name("backlog")
+             .long("backlog")
+             .value_name("n")
+             .help("Maximum number of connections waiting to be accepted, the others are refused or dropped. The system caps it (net.core.somaxconn on Linux)")
+             .takes_value(true)
+             .validator(|s| i32::from_str(&s)
+                        .map_err(|_| "Should be a number of connections".to_owned())
+                        .and_then(|n| match n {
+                            n if n < 1 => Err("Should be at least 1".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("1024"))
+        .arg(Arg::with_name("allow")
+             .long("allow")
+             .value_name("cidr,...")
+             .help("Only accept connections from these addresses, e.g. 10.0.0.0/8,::1")
+             .takes_value(true)
+             .validator(|s| cidr::parse_list(&s).map(|_| ())))
+        .arg(Arg::with_name("deny")
+             .long("deny")
+             .value_name("cidr,...")
+             .help("Reject connections from these addresses, even if allowed by --allow")
+             .takes_value(true)
+             .validator(|s| cidr::parse_list(&s).map(|_| ())))
+        .arg(Arg::with_name("one-per-ip")
+             .long("one-per-ip")
+             .help(
+"Close new connections from the same source as a connected client
+- ip: Same IP address, any port.
+- addr: Same IP address and port.\n")
+             .possible_values(&["ip", "addr"])
+             .takes_value(true)
+             .conflicts_with("client"))
         .arg(Arg::with_name("display")
              .long("display")
              .help(
 "How to display received messages
 - binary: Display as binary.
 - utf8: Try to display as utf8 text.
+- utf8-lossy: Display as utf8 text, invalid bytes are replaced with �.
+- utf16: Try to display as utf16 text, big endian without BOM.
+- hexdump: Display as hexdump -C, with offsets and ASCII.
 - none: Don't display received messages.\n")
-             .possible_values(&["binary", "utf8", "none"])
+             .possible_values(&Display::NAMES)
              .takes_value(true)
              .default_value("binary"))
-        .get_matches();
-
-    Config {
-        is_client: args.is_present("client"),
-        host: args.value_of("host")
-                  .map(|h| h.to_owned())
-                  .unwrap(),
-        port: args.value_of("port")
-                  .and_then(|p| u16::from_str(&p).ok())
-                  .unwrap(),
-        display: args.value_of("display")
+        .arg(Arg::with_name("display-stream")
+             .long("display-stream")
+             .help("The received messages are parts of a stream (split with --on-oversize), display a utf8 character cut between 2 messages with the second one"))
+        .arg(Arg::with_name("tee")
+             .long("tee")
+             .help("Always print the payload of the received messages, as binary with --display none, while relaying and answering them as usual"))
+        .arg(Arg::with_name("display-response")
+             .long("display-response")
+             .help("How to display received responses, same modes as --display (binary by default)")
+             .possible_values(&Display::NAMES)
+             .takes_value(true))
+        .arg(Arg::with_name("write-buffer")
+             .long("write-buffer")
+             .help(
+"Low and high watermarks of the write buffer, in bytes.
+0 0 (default) writes every message to the socket right away,
+larger values batch writes together.\n")
+             .value_names(&["low", "high"])
+             .number_of_values(2)
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of bytes".to_owned())
+                        .map(|_| ())))
+        .arg(Arg::with_name("read-buffer")
+             .long("read-buffer")
+             .help("Capacity of the buffer receiving messages, in bytes")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of bytes".to_owned())
+                        .map(|_| ()))
+             .default_value("4096"))
+        .arg(Arg::with_name("connect-timeout")
+             .long("connect-timeout")
+             .help("Seconds to wait for the server in client mode")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1 second".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("10"))
+        .arg(Arg::with_name("reconnect")
+             .long("reconnect")
+             .value_name("secs")
+             .help("Reconnect to the server after this delay when the connection is lost")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .map(|_| ())))
+        .arg(Arg::with_name("max-reconnects")
+             .long("max-reconnects")
+             .value_name("n")
+             .requires("reconnect")
+             .help("Give up after <n> reconnections in a row failed, and exit. Retry forever without it")
+             .takes_value(true)
+             .validator(|s| u32::from_str(&s)
+                        .map_err(|_| "Should be a number".to_owned())
+                        .map(|_| ())))
+        .arg(Arg::with_name("proxy")
+             .long("proxy")
+             .value_name("host:port")
+             .help("Connect to the server through this SOCKS5 proxy, in client mode")
+             .takes_value(true)
+             .requires("client")
+             .validator(|s| s.parse::<Proxy>().map(|_| ())))
+        .arg(Arg::with_name("proxy-auth")
+             .long("proxy-auth")
+             .value_name("user:password")
+             .help("Username and password for the --proxy")
+             .takes_value(true)
+             .requires("proxy")
+             .validator(|s| socks::parse_auth(&s).map(|_| ())))
+        .arg(Arg::with_name("max-queue")
+             .long("max-queue")
+             .help("Number of messages kept while disconnected, the oldest are dropped first")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of messages".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("100"))
+        .arg(Arg::with_name("window")
+             .long("window")
+             .value_name("n")
+             .help("Maximum number of messages sent without response, the next ones wait. 0 for no limit")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of messages".to_owned()))
+             .default_value("0"))
+        .arg(Arg::with_name("tcp-keepalive")
+             .long("tcp-keepalive")
+             .value_name("secs")
+             .help("Enable TCP keepalive, probing connections idle for this many seconds")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1 second".to_owned()),
+                            _ => Ok(())
+                        })))
+        .arg(Arg::with_name("idle-timeout")
+             .long("idle-timeout")
+             .value_name("secs")
+             .help("Close connections silent for this long (disabled by default)")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .map(|_| ())))
+        .arg(Arg::with_name("handshake-timeout")
+             .long("handshake-timeout")
+             .value_name("secs")
+             .help("Close the connections of the clients not registering a nickname with --nick within <secs>, in server mode (0 to disable)")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .map(|_| ()))
+             .default_value("0"))
+        .arg(Arg::with_name("drain-timeout")
+             .long("drain-timeout")
+             .value_name("secs")
+             .help("When closing, seconds to write the messages still waiting for --window or --throttle. CTRL+C twice closes right away")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of seconds".to_owned()))
+             .default_value("5"))
+        .arg(Arg::with_name("write-retries")
+             .long("write-retries")
+             .value_name("n")
+             .help("Retry a write failing with a transient error (interrupted, timed out) up to <n> times, 100ms apart, before closing the connection")
+             .takes_value(true)
+             .validator(|s| u32::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number".to_owned()))
+             .default_value("0"))
+        .arg(Arg::with_name("throttle")
+             .long("throttle")
+             .value_name("bytes-per-sec")
+             .help("Limit the rate of written data, to simulate slow links")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of bytes".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1 byte".to_owned()),
+                            _ => Ok(())
+                        })))
+        .arg(Arg::with_name("file")
+             .long("file")
+             .value_name("path")
+             .help("Send a file in chunks once connected, in client mode")
+             .takes_value(true))
+        .arg(Arg::with_name("message")
+             .long("message")
+             .value_name("text")
+             .help("Send this message once connected and exit, without reading stdin, in client mode")
+             .takes_value(true)
+             .requires("client"))
+        .arg(Arg::with_name("replay")
+             .long("replay")
+             .value_name("path")
+             .help("Send the messages of a transcript once connected, keeping the delays between their recorded times, and exit, without reading stdin, in client mode")
+             .takes_value(true)
+             .requires("client")
+             .conflicts_with_all(&["message", "file", "no-stdin", "follow-input", "follow", "lines"]))
+        .arg(Arg::with_name("replay-format")
+             .long("replay-format")
+             .help(
+"Format of the transcript sent with --replay
+- lines: One message per line. A line can start with the time it was
+  sent, in seconds, followed by a tab: 1700000000.250<TAB>hello
+- framed: Messages framed like on the wire, only the data messages are
+  sent. Their times are read with --one-way-latency.\n")
+             .possible_values(&["lines", "framed"])
+             .takes_value(true)
+             .default_value("lines"))
+        .arg(Arg::with_name("replay-speed")
+             .long("replay-speed")
+             .value_name("x")
+             .help("Speed of the --replay, the delays between the messages are divided by <x>")
+             .takes_value(true)
+             .validator(|s| f64::from_str(&s)
+                        .map_err(|_| "Should be a number".to_owned())
+                        .and_then(|x| match x {
+                            x if !(x > 0.0 && x.is_finite()) => Err("Should be more than 0".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("1"))
+        .arg(Arg::with_name("send-limit")
+             .long("send-limit")
+             .value_name("n")
+             .help("Send at most <n> messages of the input, then stop reading it and disconnect, in client mode (0 for no limit)")
+             .takes_value(true)
+             .requires("client")
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of messages".to_owned())
+                        .map(|_| ())))
+        .arg(Arg::with_name("nick")
+             .long("nick")
+             .value_name("name")
+             .help("Nickname registered on the server once connected, in client mode")
+             .takes_value(true)
+             .requires("client"))
+        .arg(Arg::with_name("subscribe")
+             .long("subscribe")
+             .value_name("kind,...")
+             .help("Kinds of messages the server forwards to us: data, typing, history, banner, or none. All of them by default, in client mode")
+             .takes_value(true)
+             .requires("client")
+             .validator(|s| Kind::parse_subscribable(&s).map(|_| ())))
+        .arg(Arg::with_name("wait-response")
+             .long("wait-response")
+             .help("Wait for the response to --message and print its roundtrip time before exiting")
+             .requires("message"))
+        .arg(Arg::with_name("response-timeout")
+             .long("response-timeout")
+             .value_name("secs")
+             .help("Exit with an error when the response to --message doesn't come in time")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1 second".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("10"))
+        .arg(Arg::with_name("download-dir")
+             .long("download-dir")
+             .value_name("path")
+             .help("Directory where the received files are written, with --receive-files")
+             .takes_value(true)
+             .default_value("."))
+        .arg(Arg::with_name("receive-files")
+             .long("receive-files")
+             .value_name("max-bytes")
+             .help("Accept the files sent by the peers, up to <max-bytes> in total per connection. Without it, they are refused")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of bytes".to_owned())
+                        .map(|_| ())))
+        .arg(Arg::with_name("dump-dir")
+             .long("dump-dir")
+             .value_name("path")
+             .help("Also write each received message to its own file in <path>: msg-00001.bin, msg-00002.bin, ... Existing files are kept")
+             .takes_value(true))
+        .arg(Arg::with_name("raw")
+             .long("raw")
+             .help(
+"Don't frame the messages: write the input as it is, and print
+whatever is received as a message. Both sides must use --raw,
+it can talk to tools like netcat. There are no responses, so no
+roundtrip times, and the connection is closed without reason.\n")
+             .conflicts_with_all(HEADER_OPTIONS))
+        .arg(Arg::with_name("framing")
+             .long("framing")
+             .help(
+"How the messages are delimited, both sides must use the same
+- length: A header holding the length of the message.
+- newline: A \\n after each message, to talk to line based peers.
+  The messages can't contain a \\n, and only the data messages
+  are sent, like with --raw.\n")
+             .possible_values(&["length", "newline"])
+             .takes_value(true)
+             .default_value("length"))
+        .arg(Arg::with_name("varint")
+             .long("varint")
+             .help("Encode the length of sent messages as a varint, smaller for small messages"))
+        .arg(Arg::with_name("endian")
+             .long("endian")
+             .help("Byte order of the length in the header of messages, both sides must use the same")
+             .possible_values(&["big", "little"])
+             .takes_value(true)
+             .default_value("big"))
+        .arg(Arg::with_name("one-way-latency")
+             .long("one-way-latency")
+             .help("Send the time of each message in its header, and print the one-way latency of the received messages. Both sides must use it, and their clocks be synchronized (with NTP for example)"))
+        .arg(Arg::with_name("psk")
+             .long("psk")
+             .value_name("key")
+             .help("Encrypt messages with this pre-shared key, both sides must use the same")
+             .takes_value(true))
+        .arg(Arg::with_name("hmac-key")
+             .long("hmac-key")
+             .value_name("key")
+             .help("Sign messages with HMAC-SHA256 and close connections sending messages without a valid signature")
+             .takes_value(true))
+        .arg(Arg::with_name("color")
+             .long("color")
+             .help(
+"Print messages, responses and connection events in colors
+- auto: Only when the output is a terminal.
+- always
+- never\n")
+             .possible_values(&["auto", "always", "never"])
+             .takes_value(true)
+             .default_value("auto"))
+        .arg(Arg::with_name("max-print")
+             .long("max-print")
+             .value_name("bytes")
+             .help("Truncate received messages longer than this when printing them, 0 for no limit")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of bytes".to_owned()))
+             .default_value("256"))
+        .arg(Arg::with_name("show-framing")
+             .long("show-framing")
+             .help("Print the length of each message sent, how its length is encoded and its size on the wire"))
+        .arg(Arg::with_name("show-header")
+             .long("show-header")
+             .help("Print the header bytes of each received message in hexadecimal, before its payload"))
+        .arg(Arg::with_name("connections")
+             .long("connections")
+             .value_name("n")
+             .help("Number of connections opened to the server, in client mode")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of connections".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("1"))
+        .arg(Arg::with_name("fanout")
+             .long("fanout")
+             .help(
+"How the input is sent with several connections
+- broadcast: On every connection.
+- round-robin: On one connection, in turn.\n")
+             .possible_values(&["broadcast", "round-robin"])
+             .takes_value(true)
+             .default_value("broadcast"))
+        .arg(Arg::with_name("slow-peer-action")
+             .long("slow-peer-action")
+             .help(
+"What to do with a peer reading slower than we write
+- block: Wait for it once the write buffer reaches its high watermark.
+- drop: Close the connection when the write buffer stays over the
+  high watermark (64KiB if not set) for --slow-peer-timeout.\n")
+             .possible_values(&["block", "drop"])
+             .takes_value(true)
+             .default_value("block"))
+        .arg(Arg::with_name("relay-full")
+             .long("relay-full")
+             .help(
+"What the server does when a peer is too busy to take a relayed message
+- drop: Skip the message for this peer, with a warning.
+- disconnect: Close the connection with this peer.\n")
+             .possible_values(&["drop", "disconnect"])
+             .takes_value(true)
+             .default_value("drop"))
+        .arg(Arg::with_name("slow-peer-timeout")
+             .long("slow-peer-timeout")
+             .value_name("secs")
+             .help("How long a slow peer is kept with --slow-peer-action drop")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("5"))
+        .arg(Arg::with_name("systemd")
+             .long("systemd")
+             .conflicts_with("client")
+             .help("Listen on the sockets passed by systemd socket activation (LISTEN_FDS) instead of binding --port. They are used without this flag too, when present"))
+        .arg(Arg::with_name("metrics-port")
+             .long("metrics-port")
+             .value_name("port")
+             .help("Serve metrics on http://0.0.0.0:<port>/metrics, in server mode (disabled by default)")
+             .takes_value(true)
+             .validator(|s| u16::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number between 0 and 65535".to_owned())))
+        .arg(Arg::with_name("admin-socket")
+             .long("admin-socket")
+             .value_name("path")
+             .help("Take admin commands on the Unix socket <path>, one per connection: shutdown, status or kick <addr>. In server mode")
+             .takes_value(true))
+        .arg(Arg::with_name("status-interval")
+             .long("status-interval")
+             .value_name("secs")
+             .help("Print a status line with the connections and the traffic of all the peers every <secs>, in server mode (0 to disable)")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .map(|_| ()))
+             .default_value("0"))
+        .arg(Arg::with_name("duration")
+             .long("duration")
+             .value_name("secs")
+             .help("Close the connections and exit after <secs>, once the pending messages are written (0 to run until stopped)")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map_err(|_| "Should be a number of seconds".to_owned())
+                        .map(|_| ()))
+             .default_value("0"))
+        .arg(Arg::with_name("rtt-csv")
+             .long("rtt-csv")
+             .value_name("path")
+             .help("Append the roundtrip time of each message to this CSV file")
+             .takes_value(true))
+        .arg(Arg::with_name("event-log")
+             .long("event-log")
+             .value_name("path")
+             .help("Append the connections, disconnections, messages and errors to this file, one JSON object per line")
+             .takes_value(true))
+        .arg(Arg::with_name("echo")
+             .long("echo")
+             .conflicts_with("client")
+             .help("Answer each message with its own payload, instead of \"message received\". Messages are not relayed nor kept for --history"))
+        .arg(Arg::with_name("ack-kinds")
+             .long("ack-kinds")
+             .value_name("kind,...")
+             .help("Kinds of the received messages answered with a response: data, history, typing, hello, file-meta, chunk, chunk-end, or none")
+             .takes_value(true)
+             .validator(|s| Kind::parse_ackable(&s).map(|_| ()))
+             .default_value("data"))
+        .arg(Arg::with_name("allow-empty")
+             .long("allow-empty")
+             .help("When stdin is not a terminal and is empty, send an empty message. The receiver prints \"0 bytes received\". With --lines, send the empty lines too"))
+        .arg(Arg::with_name("max-message")
+             .long("max-message")
+             .value_name("bytes")
+             .help("Maximum length of a message read on stdin, see --on-oversize")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map_err(|_| "Should be a number of bytes".to_owned())
+                        .and_then(|n| match n {
+                            0 => Err("Should be at least 1 byte".to_owned()),
+                            _ => Ok(())
+                        }))
+             .default_value("65536"))
+        .arg(Arg::with_name("on-oversize")
+             .long("on-oversize")
+             .help(
+"What to do with a message read on stdin longer than --max-message
+- split: Send it in several messages, while reading it. They are received as independent messages.
+- truncate: Send only its first --max-message bytes.
+- reject: Don't send it. When stdin is not a terminal, exit with an error.\n")
+             .possible_values(&["split", "truncate", "reject"])
+             .takes_value(true)
+             .default_value("split"))
+        .arg(Arg::with_name("on-unsolicited-response")
+             .long("on-unsolicited-response")
+             .help(
+"What to do with a response received while no message we sent waits for one
+- warn: Print it as a warning, without a roundtrip time.
+- ignore: Drop it.
+- close: Close the connection with a protocol error.\n")
+             .possible_values(&["warn", "ignore", "close"])
+             .takes_value(true)
+             .default_value("warn"))
+        .arg(Arg::with_name("advertise-max-message")
+             .long("advertise-max-message")
+             .help("Tell the peers the --max-message we take once connected. The messages sent to a peer taking less are handled according to --on-oversize, like our input"))
+        .arg(Arg::with_name("follow")
+             .long("follow")
+             .conflicts_with_all(&["no-stdin", "lines"])
+             .help("When stdin is not a terminal, send the data as it comes, in messages of at most --max-message bytes, without waiting for the end of the input (like tail -f | chat)"))
+        .arg(Arg::with_name("follow-input")
+             .long("follow-input")
+             .conflicts_with("no-stdin")
+             .help("Once a piped stdin ends, continue reading what is typed in the terminal"))
+        .arg(Arg::with_name("lines")
+             .long("lines")
+             .conflicts_with("no-stdin")
+             .help("Send each line of the input as a message, without its end of line"))
+        .arg(Arg::with_name("keep-newline")
+             .long("keep-newline")
+             .requires("lines")
+             .help("Keep the \\n or \\r\\n at the end of the lines sent with --lines"))
+        .arg(Arg::with_name("coalesce")
+             .long("coalesce")
+             .value_name("ms")
+             .help("Send the inputs coming within <ms> of each other as a single message, joined with newlines, up to --max-message bytes (0 to send each one)")
+             .takes_value(true)
+             .validator(|s| u64::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of milliseconds".to_owned()))
+             .default_value("0"))
+        .arg(Arg::with_name("list-kinds")
+             .long("list-kinds")
+             .help("List the kinds of messages of the protocol, with their value, and exit"))
+        .arg(Arg::with_name("check")
+             .long("check")
+             .help("Check the configuration, that the server can listen or that the host resolves, and exit"))
+        .arg(Arg::with_name("no-stdin")
+             .long("no-stdin")
+             .conflicts_with("allow-empty")
+             .help("Don't read stdin, only receive messages"))
+        .arg(Arg::with_name("detect-loops")
+             .long("detect-loops")
+             .help("Send a random session nonce once connected, and close the connection when it comes back: we are connected to ourselves or through a loop"))
+        .arg(Arg::with_name("typing-indicators")
+             .long("typing-indicators")
+             .help("Tell the peers when we are typing in a terminal. The server relays it to the other clients"))
+        .arg(Arg::with_name("banner")
+             .long("banner")
+             .value_name("text|path")
+             .help("Greeting the server sends to each client once connected, like terms or a message of the day. Read from the file when <path> exists, it must fit in --max-message")
+             .takes_value(true))
+        .arg(Arg::with_name("history")
+             .long("history")
+             .value_name("n")
+             .help("Number of messages the server replays to new clients (disabled by default)")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of messages".to_owned()))
+             .default_value("0"))
+        .arg(Arg::with_name("history-bytes")
+             .long("history-bytes")
+             .value_name("bytes")
+             .help("Maximum total length of the messages kept for --history")
+             .takes_value(true)
+             .validator(|s| usize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|_| "Should be a number of bytes".to_owned()))
+             .default_value("1048576"))
+        .arg(Arg::with_name("filter")
+             .long("filter")
+             .value_name("regex")
+             .help("Drop the messages matching <regex> instead of relaying them, in server mode. Only utf8 messages are inspected")
+             .takes_value(true)
+             .validator(|s| Regex::new(&s)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())))
+}
+
+/// Environment variables used when their option is not on the
+/// command line
+const ENV_VARS: [(&str, &str); 3] = [
+    ("host", "CHAT_HOST"),
+    ("port", "CHAT_PORT"),
+    ("display", "CHAT_DISPLAY"),
+];
+
+/// Return the value of the environment variable of option `name`, if set
+fn env_value(name: &str) -> Option<OsString> {
+    ENV_VARS.iter()
+            .find(|&&(option, _)| option == name)
+            .and_then(|&(_, var)| ::std::env::var_os(var))
+}
+
+/// Convert the environment variables to command line arguments
+///
+/// Options already given on the command line are skipped.
+fn env_args(cli: &ArgMatches) -> Vec<OsString> {
+    let mut args = Vec::new();
+
+    for &(option, _) in ENV_VARS.iter() {
+        if cli.occurrences_of(option) > 0 {
+            continue;
+        }
+        if let Some(value) = env_value(option) {
+            args.push(OsString::from(format!("--{}", option)));
+            args.push(value);
+        }
+    }
+
+    args
+}
+
+/// Convert the entries of a TOML config file to command line arguments
+///
+/// Options already given on the command line or with an environment
+/// variable are skipped. Unknown keys are reported and ignored.
+fn config_file_args(path: &str, cli: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Can not read {}: {}", path, e))?;
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(format!("{} should be a TOML table", path)),
+        Err(e) => return Err(format!("Can not parse {}: {}", path, e))
+    };
+
+    let mut args = Vec::new();
+
+    for (key, value) in table {
+        if cli.occurrences_of(&key) > 0 || env_value(&key).is_some() {
+            continue;
+        }
+
+        let option = format!("--{}", key);
+        let mut values = match value {
+            toml::Value::Boolean(true) => vec![],
+            toml::Value::Boolean(false) => continue,
+            toml::Value::Array(array) => array.iter().map(toml_to_string).collect(),
+            value => vec![toml_to_string(&value)]
+        };
+
+        let mut entry = vec![option.clone()];
+        entry.append(&mut values);
+
+        // Check the key alone, to only skip unknown ones
+        let known = match key.as_str() {
+            "config" | "help" | "version" => false,
+            _ => {
+                let argv = ::std::iter::once("chat".to_owned()).chain(entry.iter().cloned());
+                match app().get_matches_from_safe(argv) {
+                    Err(ref e) => e.kind != ErrorKind::UnknownArgument,
+                    Ok(_) => true
+                }
+            }
+        };
+
+        if known {
+            args.extend(entry.into_iter().map(OsString::from));
+        } else {
+            println!("Unknown key '{}' in {}, ignored", key, path);
+        }
+    }
+
+    Ok(args)
+}
+
+/// Return true with `--framing newline`
+///
+/// Return an error like clap when an option needing the header is used
+/// with it. The check is done here, clap would reject `--framing length`
+/// too.
+fn newline_framing(args: &ArgMatches) -> Result<bool, String> {
+    if args.value_of("framing") != Some("newline") {
+        return Ok(false);
+    }
+    let used = ::std::iter::once(&"raw").chain(HEADER_OPTIONS)
+                                        .find(|&&option| args.occurrences_of(option) > 0);
+    if let Some(option) = used {
+        let error = clap::Error::with_description(&format!("The argument '--framing newline' cannot be used with '--{}'", option),
+                                                  ErrorKind::ArgumentConflict);
+        return Err(error.message);
+    }
+    Ok(true)
+}
+
+/// Convert a TOML value to a command line value
+fn toml_to_string(value: &toml::Value) -> String {
+    match *value {
+        toml::Value::String(ref s) => s.clone(),
+        ref value => value.to_string()
+    }
+}
+
+/// Read command line arguments, environment variables and the config
+/// file if any, and return a [`Config`]
+///
+/// Precedence is command line > environment > config file > default.
+/// Values from the environment and the file are validated like the
+/// command line ones.
+fn get_config() -> Config {
+    let args = app().get_matches();
+
+    match with_env_and_file(args) {
+        Ok(args) => config_from(&args),
+        Err(e) => {
+            println!("{}", e);
+            ExitCode::Failure.exit();
+        }
+    }
+}
+
+/// Read the command line, the environment and the config file again,
+/// to reload the configuration on SIGHUP
+///
+/// Errors are returned instead of exiting. The files of `--rtt-csv`
+/// and `--event-log`, and the nonce of `--detect-loops`, are the ones
+/// of `current`.
+pub fn reload_config(current: &Config) -> Result<Config, String> {
+    let args = app().get_matches_from_safe(::std::env::args_os()).map_err(|e| e.message)?;
+    let args = with_env_and_file(args)?;
+
+    let mut builder = builder_from(&args)?
+        .rtt_csv(current.rtt_csv.clone())
+        .event_log(current.event_log.clone());
+    if let Some(nonce) = current.session_nonce {
+        builder = builder.session_nonce(nonce);
+    }
+    Ok(builder.build())
+}
+
+/// Add the options from the environment and the config file to the
+/// command line ones `args`
+fn with_env_and_file(args: ArgMatches<'static>) -> Result<ArgMatches<'static>, String> {
+    let mut extra_args = env_args(&args);
+
+    if let Some(path) = args.value_of("config") {
+        extra_args.extend(config_file_args(path, &args)?);
+    }
+
+    if extra_args.is_empty() {
+        return Ok(args);
+    }
+
+    let mut argv: Vec<OsString> = ::std::env::args_os().take(1).collect();
+    argv.extend(extra_args);
+    argv.extend(::std::env::args_os().skip(1));
+    app().get_matches_from_safe(argv).map_err(|e| e.message)
+}
+
+/// Build the [`Config`] from the parsed arguments
+///
+/// Exit when an option can't be used, or the files of `--rtt-csv` and
+/// `--event-log` can't be opened.
+fn config_from(args: &ArgMatches) -> Config {
+    let mut builder = builder_from(args).unwrap_or_else(|e| {
+        println!("{}", e);
+        ExitCode::Failure.exit();
+    });
+    if let Some(path) = args.value_of("rtt-csv") {
+        builder = builder.rtt_csv(Some(RttCsv::open(path.as_ref()).unwrap_or_else(|e| {
+            println!("Can not open {}: {}", path, e);
+            ExitCode::Failure.exit();
+        })));
+    }
+    if let Some(path) = args.value_of("event-log") {
+        builder = builder.event_log(Some(EventLog::open(path.as_ref()).unwrap_or_else(|e| {
+            println!("Can not open {}: {}", path, e);
+            ExitCode::Failure.exit();
+        })));
+    }
+    if args.is_present("detect-loops") {
+        builder = builder.session_nonce(rand::random());
+    }
+    builder.build()
+}
+
+/// Take the parsed arguments in a [`ConfigBuilder`], without the files
+/// to open and the nonce, see [`config_from`]
+fn builder_from(args: &ArgMatches) -> Result<ConfigBuilder, String> {
+    let max_message = args.value_of("max-message")
+                          .and_then(|n| usize::from_str(n).ok())
+                          .unwrap();
+    let mut builder = ConfigBuilder::new()
+        .client(args.is_present("client"))
+        .host(args.value_of("host").unwrap())
+        .ports(args.value_of("port")
+                   .map(|p| p.split(',').filter_map(|p| u16::from_str(p.trim()).ok()).collect())
+                   .unwrap())
+        .display(args.value_of("display")
                      .map(Display::from)
-                     .unwrap()
+                     .unwrap())
+        .display_stream(args.is_present("display-stream"))
+        .color(match args.value_of("color") {
+            Some("always") => true,
+            Some("never") => false,
+            _ => atty::is(atty::Stream::Stdout)
+        })
+        .no_stdin(args.is_present("no-stdin"))
+        .window(args.value_of("window")
+                    .and_then(|n| usize::from_str(n).ok())
+                    .unwrap())
+        .drain_timeout(args.value_of("drain-timeout")
+                           .and_then(|n| u64::from_str(n).ok())
+                           .map(Duration::from_secs)
+                           .unwrap())
+        .max_message(max_message,
+                     args.value_of("on-oversize")
+                         .map(Oversize::from)
+                         .unwrap())
+        .advertise_max_message(args.is_present("advertise-max-message"))
+        .on_unsolicited_response(args.value_of("on-unsolicited-response")
+                                     .map(Unsolicited::from)
+                                     .unwrap())
+        .framing(Framing {
+            length: if args.is_present("varint") {
+                LengthEncoding::Varint
+            } else {
+                LengthEncoding::Fixed
+            },
+            endian: args.value_of("endian")
+                        .map(Endian::from)
+                        .unwrap(),
+            psk: args.value_of("psk")
+                     .map(Psk::new),
+            hmac: args.value_of("hmac-key")
+                      .map(|key| HmacKey::new(key.as_bytes())),
+            raw: args.is_present("raw"),
+            newline: newline_framing(args)?,
+            timestamps: args.is_present("one-way-latency")
+        })
+        .echo(args.is_present("echo"))
+        .relay_full(args.value_of("relay-full")
+                        .map(RelayFull::from)
+                        .unwrap())
+        .history(args.value_of("history")
+                     .and_then(|n| usize::from_str(n).ok())
+                     .unwrap())
+        .download_dir(args.value_of("download-dir")
+                          .map(PathBuf::from)
+                          .unwrap())
+        .receive_files(args.value_of("receive-files")
+                           .and_then(|n| u64::from_str(n).ok()));
+    if let Some(label) = args.value_of("label") {
+        builder = builder.label(label);
+    }
+    if let Some(display) = args.value_of("display-response") {
+        builder = builder.display_response(Display::from(display));
+    }
+    if let Some(duplicate) = args.value_of("one-per-ip") {
+        builder = builder.one_per_ip(Duplicate::from(duplicate));
+    }
+    if let Some(delay) = args.value_of("reconnect").and_then(|n| u64::from_str(n).ok()) {
+        builder = builder.reconnect(Duration::from_secs(delay));
+    }
+    if let Some(rate) = args.value_of("throttle").and_then(|n| usize::from_str(n).ok()) {
+        builder = builder.throttle(rate);
+    }
+    if let Some(message) = args.value_of("message") {
+        builder = builder.message(message.as_bytes());
+    }
+    if args.is_present("wait-response") {
+        builder = builder.wait_response(args.value_of("response-timeout")
+                                            .and_then(|n| u64::from_str(n).ok())
+                                            .map(Duration::from_secs)
+                                            .unwrap());
+    }
+    if let Some(nick) = args.value_of("nick") {
+        builder = builder.nick(nick);
+    }
+    if let Some(timeout) = args.value_of("handshake-timeout")
+                               .and_then(|n| u64::from_str(n).ok())
+                               .filter(|&n| n > 0) {
+        builder = builder.handshake_timeout(Duration::from_secs(timeout));
+    }
+    let banner = match args.value_of("banner") {
+        Some(banner) => Some(read_banner(banner, max_message)?).filter(|banner| !banner.is_empty()),
+        None => None
+    };
+
+    Ok(builder
+        .require_all_ports(args.is_present("require-all-ports"))
+        .systemd(args.is_present("systemd"))
+        .ip_version(if args.is_present("ipv4") {
+            IpVersion::V4
+        } else if args.is_present("ipv6") {
+            IpVersion::V6
+        } else {
+            IpVersion::Any
+        })
+        .interface(args.value_of("interface")
+                       .map(|i| i.to_owned()))
+        .bind_retry(args.value_of("bind-retry")
+                        .and_then(|n| u64::from_str(n).ok())
+                        .map(Duration::from_secs))
+        .backlog(args.value_of("backlog")
+                     .and_then(|n| i32::from_str(n).ok())
+                     .unwrap())
+        .allow(args.value_of("allow")
+                   .and_then(|s| cidr::parse_list(s).ok())
+                   .unwrap_or_default())
+        .deny(args.value_of("deny")
+                  .and_then(|s| cidr::parse_list(s).ok())
+                  .unwrap_or_default())
+        .tee(args.is_present("tee"))
+        .write_buffer(args.values_of("write-buffer")
+                          .map(|v| v.filter_map(|n| usize::from_str(n).ok()).collect())
+                          .and_then(|v: Vec<usize>| match v.as_slice() {
+                              [low, high] => Some((*low, *high)),
+                              _ => None
+                          })
+                          .unwrap_or((0, 0)))
+        .read_buffer(args.value_of("read-buffer")
+                         .and_then(|n| usize::from_str(n).ok())
+                         .unwrap())
+        .connect_timeout(args.value_of("connect-timeout")
+                             .and_then(|n| u64::from_str(n).ok())
+                             .map(Duration::from_secs)
+                             .unwrap())
+        .max_reconnects(args.value_of("max-reconnects")
+                            .and_then(|n| u32::from_str(n).ok())
+                            .filter(|&n| n > 0))
+        .proxy(args.value_of("proxy")
+                   .and_then(|proxy| proxy.parse::<Proxy>().ok())
+                   .map(|proxy| Proxy {
+                       auth: args.value_of("proxy-auth").and_then(|auth| socks::parse_auth(auth).ok()),
+                       ..proxy
+                   }))
+        .max_queue(args.value_of("max-queue")
+                       .and_then(|n| usize::from_str(n).ok())
+                       .unwrap())
+        .tcp_keepalive(args.value_of("tcp-keepalive")
+                           .and_then(|n| u64::from_str(n).ok())
+                           .map(Duration::from_secs))
+        .idle_timeout(args.value_of("idle-timeout")
+                          .and_then(|n| u64::from_str(n).ok())
+                          .map(Duration::from_secs))
+        .write_retries(args.value_of("write-retries")
+                           .and_then(|n| u32::from_str(n).ok())
+                           .unwrap())
+        .file(args.value_of("file")
+                  .map(PathBuf::from))
+        .dump_dir(args.value_of("dump-dir")
+                      .map(PathBuf::from))
+        .replay(args.value_of("replay")
+                    .map(PathBuf::from))
+        .replay_format(args.value_of("replay-format")
+                           .map(ReplayFormat::from)
+                           .unwrap())
+        .replay_speed(args.value_of("replay-speed")
+                          .and_then(|n| f64::from_str(n).ok())
+                          .unwrap())
+        .send_limit(args.value_of("send-limit")
+                        .and_then(|n| usize::from_str(n).ok())
+                        .filter(|&n| n > 0))
+        .subscribe(args.value_of("subscribe")
+                       .and_then(|s| Kind::parse_subscribable(s).ok()))
+        .max_print(args.value_of("max-print")
+                       .and_then(|n| usize::from_str(n).ok())
+                       .unwrap())
+        .show_framing(args.is_present("show-framing"))
+        .show_header(args.is_present("show-header"))
+        .connections(args.value_of("connections")
+                         .and_then(|n| usize::from_str(n).ok())
+                         .unwrap())
+        .fanout(args.value_of("fanout")
+                    .map(Fanout::from)
+                    .unwrap())
+        .slow_peer(args.value_of("slow-peer-action")
+                       .map(SlowPeer::from)
+                       .unwrap())
+        .slow_peer_timeout(args.value_of("slow-peer-timeout")
+                               .and_then(|n| u64::from_str(n).ok())
+                               .map(Duration::from_secs)
+                               .unwrap())
+        .metrics_port(args.value_of("metrics-port")
+                          .and_then(|p| u16::from_str(p).ok()))
+        .admin_socket(args.value_of("admin-socket")
+                          .map(PathBuf::from))
+        .status_interval(args.value_of("status-interval")
+                             .and_then(|n| u64::from_str(n).ok())
+                             .filter(|&n| n > 0)
+                             .map(Duration::from_secs))
+        .duration(args.value_of("duration")
+                      .and_then(|n| u64::from_str(n).ok())
+                      .filter(|&n| n > 0)
+                      .map(Duration::from_secs))
+        .typing_indicators(args.is_present("typing-indicators"))
+        .allow_empty(args.is_present("allow-empty"))
+        .follow_input(args.is_present("follow-input"))
+        .follow(args.is_present("follow"))
+        .lines(args.is_present("lines"))
+        .keep_newline(args.is_present("keep-newline"))
+        .coalesce(args.value_of("coalesce")
+                      .and_then(|n| u64::from_str(n).ok())
+                      .filter(|&n| n > 0)
+                      .map(Duration::from_millis))
+        .ack_kinds(args.value_of("ack-kinds")
+                       .and_then(|s| Kind::parse_ackable(s).ok())
+                       .unwrap_or_default())
+        .banner(banner)
+        .history_bytes(args.value_of("history-bytes")
+                           .and_then(|n| usize::from_str(n).ok())
+                           .unwrap())
+        .filter(args.value_of("filter")
+                    .and_then(|s| Regex::new(s).ok()))
+        .check(args.is_present("check"))
+        .list_kinds(args.is_present("list-kinds"))
+        .config_file(args.value_of("config")
+                         .map(PathBuf::from)))
+}
+
+/// Return the content of the file `banner`, or `banner` itself when
+/// there is no such file
+///
+/// Fail when the file can't be read, or it's longer than `max_message`.
+fn read_banner(banner: &str, max_message: usize) -> Result<Vec<u8>, String> {
+    let banner = if Path::new(banner).exists() {
+        fs::read(banner).map_err(|e| format!("Can not read the banner {}: {}", banner, e))?
+    } else {
+        banner.as_bytes().to_vec()
+    };
+    if banner.len() > max_message {
+        return Err(format!("The banner is {} bytes long, more than --max-message ({} bytes)",
+                           banner.len(), max_message));
+    }
+    Ok(banner)
+}
+
+/// Check that `config` can be used, without starting the actors
+///
+/// The server binds its ports and releases them, the client resolves
+/// its host. Return the exit code.
+fn check(config: Config) -> ExitCode {
+    if config.is_client {
+        let resolved = client::resolve(&config.host, config.ports[0], config.ip_version);
+        match tokio::runtime::current_thread::block_on_all(resolved) {
+            Ok(addrs) => {
+                for addr in addrs {
+                    println!("{} resolves to {}", config.host, addr);
+                }
+                ExitCode::Success
+            }
+            Err(e) => {
+                println!("Can not resolve {}: {}", config.host, e);
+                ExitCode::Connect
+            }
+        }
+    } else {
+        match Server::new(config) {
+            Ok(server) => {
+                for addr in server.bound_addrs() {
+                    println!("Can listen on {}", addr);
+                }
+                // Nothing is retried without running the server
+                for addr in server.retrying_addrs() {
+                    println!("Can not listen on {}, the port is in use", addr);
+                }
+                match server.retrying_addrs().is_empty() {
+                    true => ExitCode::Success,
+                    false => ExitCode::Bind
+                }
+            }
+            Err(e) => {
+                println!("{}", e);
+                e.exit_code()
+            }
+        }
+    }
+}
+
+/// Print the kinds of messages, with their value and description
+fn list_kinds() {
+    for &kind in Kind::ALL.iter() {
+        println!("{:>2}  {:<10}  {}", u8::from(kind), kind.name(), kind.description());
     }
 }
 
 fn main() {
     let config = get_config();
+    if config.list_kinds {
+        list_kinds();
+        ExitCode::Success.exit();
+    }
+    if config.check {
+        check(config).exit();
+    }
+    let error = Rc::new(RefCell::new(None));
+    let server_error = error.clone();
 
-    System::run(|| {
+    System::run(move || {
         if config.is_client {
             Client::new(config).start();
         } else {
-            Server::new(config).start();
+            match Server::new(config) {
+                Ok(server) => {
+                    server.start();
+                }
+                Err(e) => {
+                    *server_error.borrow_mut() = Some(e);
+                    System::current().stop();
+                }
+            }
         }
     });
+
+    let error = error.borrow_mut().take();
+    if let Some(e) = error {
+        println!("{}", e);
+        e.exit_code().exit();
+    }
 }
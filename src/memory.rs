@@ -0,0 +1,530 @@
+//! In-memory transport, to test the [`Peer`]s without sockets
+//!
+//! [`connect`] links two peers with a pair of pipes: what one writes,
+//! the other reads. Nothing is bound, and the bytes are exchanged on
+//! the thread of the actors.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use actix::prelude::*;
+use actix::dev::ToEnvelope;
+use futures::task::{self, Task};
+use futures::{Async, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use peer::{Peer, PeerClose, Relay};
+use reader::Transport;
+use Config;
+
+/// Bytes written and not read yet
+#[derive(Default)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    /// The write end is closed, the reader gets EOF once `buffer` is
+    /// empty
+    closed: bool,
+    /// Task waiting for data to read
+    reader: Option<Task>
+}
+
+impl Pipe {
+    fn wake_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.notify();
+        }
+    }
+}
+
+/// Read end of a pipe
+pub struct MemoryRead(Rc<RefCell<Pipe>>);
+
+/// Write end of a pipe, closed when dropped
+pub struct MemoryWrite(Rc<RefCell<Pipe>>);
+
+impl Read for MemoryRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.0.borrow_mut();
+        if pipe.buffer.is_empty() {
+            if pipe.closed || buf.is_empty() {
+                return Ok(0);
+            }
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(pipe.buffer.len());
+        for (byte, read) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+            *byte = read;
+        }
+        Ok(len)
+    }
+}
+
+impl AsyncRead for MemoryRead {}
+
+impl Transport for MemoryRead {
+    fn closed(&self) -> bool {
+        self.0.borrow().closed
+    }
+}
+
+impl Write for MemoryWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.0.borrow_mut();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.buffer.extend(buf);
+        pipe.wake_reader();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for MemoryWrite {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let mut pipe = self.0.borrow_mut();
+        pipe.closed = true;
+        pipe.wake_reader();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MemoryWrite {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Return the two ends of a new pipe
+pub fn pipe() -> (MemoryRead, MemoryWrite) {
+    let pipe = Rc::new(RefCell::new(Pipe::default()));
+    (MemoryRead(pipe.clone()), MemoryWrite(pipe))
+}
+
+/// Create two peers connected to each other, with their configuration
+/// and parent
+///
+/// It must be called from a running actix system.
+pub fn connect<A, B>(config_a: Config, parent_a: Addr<A>, config_b: Config, parent_b: Addr<B>)
+                     -> (Addr<Peer<A>>, Addr<Peer<B>>)
+where
+    A: Actor + Handler<PeerClose> + Handler<Relay>,
+    B: Actor + Handler<PeerClose> + Handler<Relay>,
+    A::Context: ToEnvelope<A, PeerClose> + ToEnvelope<A, Relay>,
+    B::Context: ToEnvelope<B, PeerClose> + ToEnvelope<B, Relay>
+{
+    let (read_a, write_b) = pipe();
+    let (read_b, write_a) = pipe();
+    let a = Peer::with_transport(config_a, parent_a, read_a, write_a, None, None, 0);
+    let b = Peer::with_transport(config_b, parent_b, read_b, write_b, None, None, 1);
+    (a, b)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use actix::prelude::*;
+    use futures::{future, Future};
+
+    use config::ConfigBuilder;
+    use bytes::Bytes;
+    use peer::{Disconnect, Forward, HalfClose, Hooks, Pause, PeerClose, Received, Reconfigure, Relay, Roundtrip};
+    use reader::Kind;
+    use tokio::timer::Delay;
+    use user::UserInput;
+    use {Oversize, Unsolicited};
+    use super::{connect, pipe};
+
+    #[test]
+    fn pipe_ends() {
+        System::run(|| {
+            Arbiter::spawn(future::lazy(|| {
+                let (mut read, mut write) = pipe();
+                let mut buffer = [0; 4];
+
+                write.write_all(b"hello").unwrap();
+                assert_eq!(read.read(&mut buffer).unwrap(), 4);
+                assert_eq!(&buffer, b"hell");
+                assert_eq!(read.read(&mut buffer).unwrap(), 1);
+                // Nothing more for now
+                assert_eq!(read.read(&mut buffer).unwrap_err().kind(), ::std::io::ErrorKind::WouldBlock);
+
+                drop(write);
+                assert_eq!(read.read(&mut buffer).unwrap(), 0);
+
+                System::current().stop();
+                Ok(())
+            }));
+        });
+    }
+
+    /// What the peers were notified of
+    #[derive(Default)]
+    struct Notified {
+        received: Vec<(Kind, Vec<u8>)>,
+        roundtrips: Vec<(u32, usize)>,
+        /// Reasons given by the other side of the closed peers
+        closed: Vec<Option<String>>,
+        /// Peers closed because of invalid data
+        protocol_errors: usize,
+        /// Messages written by the closed peers
+        sent: Vec<u64>
+    }
+
+    /// Parent and hooks of both peers, stops the system once `roundtrips`
+    /// were measured, or both peers are closed
+    struct Observer {
+        notified: Arc<Mutex<Notified>>,
+        roundtrips: usize,
+        /// Run on the first roundtrip, see [`AfterRoundtrip`]
+        after_roundtrip: Option<Box<dyn FnOnce() + Send>>,
+        /// Run on the first received message, see [`AfterReceived`]
+        after_received: Option<Box<dyn FnOnce() + Send>>
+    }
+
+    /// Run a function once the first roundtrip was measured, when the
+    /// peers know of each other
+    #[derive(Message)]
+    struct AfterRoundtrip(Box<dyn FnOnce() + Send>);
+
+    /// Run a function once a message was received, when what was sent
+    /// before it was received too
+    #[derive(Message)]
+    struct AfterReceived(Box<dyn FnOnce() + Send>);
+
+    impl Actor for Observer {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Context<Self>) {
+            // Never hang, the assertions tell what's missing
+            ctx.run_later(Duration::from_secs(5), |_, _| System::current().stop());
+        }
+    }
+
+    impl Handler<Received> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, msg: Received, _: &mut Context<Self>) {
+            self.notified.lock().unwrap().received.push((msg.kind, msg.payload.to_vec()));
+            if let Some(f) = self.after_received.take() {
+                f();
+            }
+        }
+    }
+
+    impl Handler<Roundtrip> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, msg: Roundtrip, _: &mut Context<Self>) {
+            let mut notified = self.notified.lock().unwrap();
+            notified.roundtrips.push((msg.seq, msg.len));
+            if notified.roundtrips.len() == self.roundtrips {
+                System::current().stop();
+            }
+            if let Some(f) = self.after_roundtrip.take() {
+                f();
+            }
+        }
+    }
+
+    impl Handler<AfterRoundtrip> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, AfterRoundtrip(f): AfterRoundtrip, _: &mut Context<Self>) {
+            self.after_roundtrip = Some(f);
+        }
+    }
+
+    impl Handler<AfterReceived> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, AfterReceived(f): AfterReceived, _: &mut Context<Self>) {
+            self.after_received = Some(f);
+        }
+    }
+
+    impl Handler<PeerClose> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, close: PeerClose, _: &mut Context<Self>) {
+            let mut notified = self.notified.lock().unwrap();
+            notified.closed.push(close.reason);
+            notified.sent.push(close.stats.messages_sent);
+            if close.protocol_error {
+                notified.protocol_errors += 1;
+            }
+            if notified.closed.len() == 2 {
+                System::current().stop();
+            }
+        }
+    }
+
+    impl Handler<Relay> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, _: Relay, _: &mut Context<Self>) {}
+    }
+
+    /// Connect a client peer, configured with `client`, to a server
+    /// peer, and run `f` with both once started
+    ///
+    /// Return what was notified once the system stopped.
+    fn run<F>(roundtrips: usize, client: ConfigBuilder, f: F) -> Notified
+    where
+        F: FnOnce(Addr<::peer::Peer<Observer>>, Addr<::peer::Peer<Observer>>) + 'static
+    {
+        run_with(roundtrips, client, ConfigBuilder::new(), |client, server, _| f(client, server))
+    }
+
+    /// Like [`run`], with the server peer configured with `server`, and
+    /// `f` also given the [`Observer`]
+    fn run_with<F>(roundtrips: usize, client: ConfigBuilder, server: ConfigBuilder, f: F) -> Notified
+    where
+        F: FnOnce(Addr<::peer::Peer<Observer>>, Addr<::peer::Peer<Observer>>, Addr<Observer>) + 'static
+    {
+        let notified = Arc::new(Mutex::new(Notified::default()));
+        let observer_notified = notified.clone();
+
+        System::run(move || {
+            let observer = Observer { notified: observer_notified, roundtrips, after_roundtrip: None, after_received: None }.start();
+            let hooks = Hooks {
+                received: Some(observer.clone().recipient()),
+                roundtrip: Some(observer.clone().recipient())
+            };
+            let client = client.client(true).hooks(hooks.clone()).build();
+            let server = server.hooks(hooks).build();
+            let (client, server) = connect(client, observer.clone(), server, observer.clone());
+            f(client, server, observer);
+        });
+
+        let mut notified = notified.lock().unwrap();
+        ::std::mem::take(&mut *notified)
+    }
+
+    #[test]
+    fn message_and_response() {
+        let notified = run(2, ConfigBuilder::new(), |client, _| {
+            Arbiter::spawn(client.send(UserInput::new(b"hello".to_vec()))
+                .and_then(move |_| client.send(UserInput::new(b"world".to_vec())))
+                .map(|_| ())
+                .map_err(|_| ()));
+        });
+
+        assert_eq!(notified.received, vec![(Kind::Data, b"hello".to_vec()), (Kind::Data, b"world".to_vec())]);
+        assert_eq!(notified.roundtrips, vec![(0, 5), (1, 5)]);
+        assert!(notified.closed.is_empty());
+    }
+
+    #[test]
+    fn close_with_reason() {
+        let notified = run(0, ConfigBuilder::new(), |client, _| client.do_send(Disconnect("bye".to_owned())));
+
+        // The client stops first, then the server with the reason it got
+        assert_eq!(notified.closed, vec![None, Some("bye".to_owned())]);
+    }
+
+    #[test]
+    fn unsolicited_response_warned() {
+        let notified = run_with(1, ConfigBuilder::new(), ConfigBuilder::new(), |client, server, observer| {
+            // Not a response to anything, received before "hello" is sent
+            observer.do_send(AfterReceived(Box::new(move || {
+                client.do_send(UserInput::new(b"hello".to_vec()))
+            })));
+            server.do_send(Forward(Kind::Response, Bytes::from("bogus")));
+            server.do_send(Forward(Kind::History, Bytes::from("after")));
+        });
+
+        // The roundtrip of "hello" is measured with its own response
+        assert_eq!(notified.received, vec![(Kind::History, b"after".to_vec()),
+                                           (Kind::Data, b"hello".to_vec())]);
+        assert_eq!(notified.roundtrips, vec![(0, 5)]);
+        assert!(notified.closed.is_empty());
+    }
+
+    #[test]
+    fn unsolicited_response_closes() {
+        let client = ConfigBuilder::new().on_unsolicited_response(Unsolicited::Close);
+        let notified = run(0, client, |_, server| {
+            server.do_send(Forward(Kind::Response, Bytes::from("bogus")));
+        });
+
+        assert!(notified.roundtrips.is_empty());
+        assert_eq!(notified.closed, vec![None, Some("unsolicited response".to_owned())]);
+        assert_eq!(notified.protocol_errors, 1);
+    }
+
+    #[test]
+    fn forwarded_response_in_order() {
+        let client = ConfigBuilder::new().on_unsolicited_response(Unsolicited::Close);
+        let notified = run(1, client, |client, _| {
+            client.do_send(Forward(Kind::Data, Bytes::from("relayed")));
+            client.do_send(UserInput::new(b"hello".to_vec()));
+        });
+
+        // The first response is the one to the forwarded message
+        assert_eq!(notified.roundtrips, vec![(1, 5)]);
+        assert!(notified.closed.is_empty());
+    }
+
+    #[test]
+    fn close_after_throttled_data() {
+        // 100 bytes at once at most, the first message is written in 2
+        // rounds, the second waits. They get no response
+        let client = ConfigBuilder::new().throttle(1000).on_unsolicited_response(Unsolicited::Close);
+        let notified = run(0, client, |client, server| {
+            client.do_send(Forward(Kind::History, Bytes::from(vec![b'a'; 150])));
+            client.do_send(Forward(Kind::History, Bytes::from("second")));
+            server.do_send(Forward(Kind::Response, Bytes::from("bogus")));
+        });
+
+        // The first message is finished before the Close, the second is
+        // never written
+        assert_eq!(notified.received, vec![(Kind::History, vec![b'a'; 150])]);
+        assert_eq!(notified.closed, vec![None, Some("unsolicited response".to_owned())]);
+        assert_eq!(notified.sent[0], 2);
+    }
+
+    #[test]
+    fn split_parts_arrive_apart() {
+        let server = ConfigBuilder::new().max_message(4, Oversize::Split).advertise_max_message(true);
+        let notified = run_with(4, ConfigBuilder::new(), server, |client, _, observer| {
+            // Once the response to "x" came, the client got the maximum
+            // of the server, sent first
+            let input = client.clone();
+            observer.do_send(AfterRoundtrip(Box::new(move || {
+                input.do_send(UserInput::new(b"hello world".to_vec()))
+            })));
+            client.do_send(UserInput::new(b"x".to_vec()));
+        });
+
+        // Each part is a message of its own, answered on its own
+        assert_eq!(notified.received, vec![(Kind::Data, b"x".to_vec()),
+                                           (Kind::Data, b"hell".to_vec()),
+                                           (Kind::Data, b"o wo".to_vec()),
+                                           (Kind::Data, b"rld".to_vec())]);
+        assert_eq!(notified.roundtrips, vec![(0, 1), (1, 4), (2, 4), (3, 3)]);
+    }
+
+    #[test]
+    fn unthrottled_after_queued_data() {
+        let client = ConfigBuilder::new().throttle(1000);
+        let notified = run(2, client, |client, _| {
+            // 100 bytes are written at once, the rest is queued
+            client.do_send(UserInput::new(vec![b'a'; 150]));
+            client.do_send(Reconfigure(ConfigBuilder::new().client(true).build()));
+            client.do_send(UserInput::new(b"second".to_vec()));
+        });
+
+        // The end of the first message is written before the second
+        assert_eq!(notified.received, vec![(Kind::Data, vec![b'a'; 150]), (Kind::Data, b"second".to_vec())]);
+        assert_eq!(notified.roundtrips, vec![(0, 150), (1, 6)]);
+        assert_eq!(notified.protocol_errors, 0);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let resumed = Rc::new(Cell::new(false));
+        // Whether the first input was taken after the resume
+        let taken = Rc::new(Cell::new(None));
+        let taken_after = taken.clone();
+
+        let notified = run(2, ConfigBuilder::new(), move |client, _| {
+            client.do_send(Pause::Toggle);
+            let resumed_before = resumed.clone();
+            Arbiter::spawn(client.send(UserInput::new(b"one".to_vec())).then(move |_| {
+                taken_after.set(Some(resumed_before.get()));
+                Ok(())
+            }));
+            client.do_send(UserInput::new(b"two".to_vec()));
+
+            let resume = client.clone();
+            Arbiter::spawn(Delay::new(Instant::now() + Duration::from_millis(100)).then(move |_| {
+                resumed.set(true);
+                resume.do_send(Pause::Resume);
+                Ok(())
+            }));
+        });
+
+        // Nothing is written while paused, then the inputs in order
+        assert_eq!(notified.received, vec![(Kind::Data, b"one".to_vec()), (Kind::Data, b"two".to_vec())]);
+        assert_eq!(notified.roundtrips, vec![(0, 3), (1, 3)]);
+        assert_eq!(taken.get(), Some(true));
+    }
+
+    #[test]
+    fn connected_to_itself() {
+        // Both ends are the same process
+        let notified = run_with(0, ConfigBuilder::new().session_nonce(42), ConfigBuilder::new().session_nonce(42),
+                                |_, _, _| ());
+
+        assert_eq!(notified.closed.len(), 2);
+        assert_eq!(notified.protocol_errors, 2);
+    }
+
+    #[test]
+    fn connected_to_another() {
+        let notified = run_with(1, ConfigBuilder::new().session_nonce(1), ConfigBuilder::new().session_nonce(2),
+                                |client, _, _| client.do_send(UserInput::new(b"hello".to_vec())));
+
+        // After the nonce
+        assert_eq!(notified.roundtrips, vec![(1, 5)]);
+        assert!(notified.closed.is_empty());
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let server = ConfigBuilder::new().handshake_timeout(Duration::from_millis(100));
+        // The client never registers a nickname
+        let notified = run_with(0, ConfigBuilder::new(), server, |_, _, _| ());
+
+        assert!(notified.closed.contains(&Some("handshake timeout".to_owned())), "{:?}", notified.closed);
+    }
+
+    #[test]
+    fn hello_cancels_handshake_timeout() {
+        let server = ConfigBuilder::new().handshake_timeout(Duration::from_millis(100));
+        let notified = run_with(1, ConfigBuilder::new(), server, |client, _, _| {
+            client.do_send(Forward(Kind::Hello, Bytes::from("alice")));
+            // Still connected after the timeout
+            Arbiter::spawn(Delay::new(Instant::now() + Duration::from_millis(200)).then(move |_| {
+                client.do_send(UserInput::new(b"hello".to_vec()));
+                Ok(())
+            }));
+        });
+
+        // After the Hello
+        assert_eq!(notified.roundtrips, vec![(1, 5)]);
+        assert!(notified.closed.is_empty());
+    }
+
+    #[test]
+    fn no_response_after_half_close() {
+        let server = ConfigBuilder::new().window(1);
+        let notified = run_with(0, ConfigBuilder::new(), server, |client, server, _| {
+            client.do_send(HalfClose);
+            // The client can't answer these, the window doesn't wait
+            // for its responses
+            Arbiter::spawn(Delay::new(Instant::now() + Duration::from_millis(100)).then(move |_| {
+                server.do_send(UserInput::new(b"one".to_vec()));
+                server.do_send(UserInput::new(b"two".to_vec()));
+                Delay::new(Instant::now() + Duration::from_millis(200))
+            }).then(|_| {
+                System::current().stop();
+                Ok(())
+            }));
+        });
+
+        assert_eq!(notified.received, vec![(Kind::Data, b"one".to_vec()), (Kind::Data, b"two".to_vec())]);
+        assert!(notified.closed.is_empty());
+    }
+}
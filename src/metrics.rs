@@ -0,0 +1,203 @@
+//! Counters exposed over HTTP with `--metrics-port`
+//!
+//! The counters are updated by the [`Peer`](::peer::Peer)s and the
+//! [`Server`](::server::Server), and served in the Prometheus text
+//! format on `/metrics`.
+
+use std::fmt::Write;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use actix::Arbiter;
+use futures::{Future, Stream};
+use tokio_tcp::{TcpListener, TcpStream};
+
+/// Upper bounds of the roundtrip histogram buckets, in seconds
+const BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Counters of the process
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counters, see [`METRICS`]
+pub struct Metrics {
+    /// Number of connections accepted by the server
+    pub connections: AtomicU64,
+    /// Number of messages sent
+    pub messages_sent: AtomicU64,
+    /// Number of bytes sent
+    pub bytes_sent: AtomicU64,
+    /// Number of messages received
+    pub messages_received: AtomicU64,
+    /// Number of bytes received
+    pub bytes_received: AtomicU64,
+    /// Number of roundtrips in each bucket of [`BUCKETS`], the last
+    /// one is for the longer ones
+    roundtrips: [AtomicU64; 9],
+    /// Sum of the roundtrip times, in microseconds
+    roundtrip_micros: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            connections: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            roundtrips: [
+                AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+            ],
+            roundtrip_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Add `n` to `counter`
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Return the value of `counter`
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Count a roundtrip
+    pub fn roundtrip(&self, delay: Duration) {
+        let secs = delay.as_secs_f64();
+        let bucket = BUCKETS.iter()
+                            .position(|&bound| secs <= bound)
+                            .unwrap_or(BUCKETS.len());
+        Metrics::add(&self.roundtrips[bucket], 1);
+        Metrics::add(&self.roundtrip_micros, delay.as_micros() as u64);
+    }
+
+    /// Format the counters in the Prometheus text format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("chat_connections_total", "Connections accepted", &self.connections),
+            ("chat_messages_sent_total", "Messages sent", &self.messages_sent),
+            ("chat_bytes_sent_total", "Bytes sent", &self.bytes_sent),
+            ("chat_messages_received_total", "Messages received", &self.messages_received),
+            ("chat_bytes_received_total", "Bytes received", &self.bytes_received),
+        ];
+
+        for &(name, help, counter) in counters.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let name = "chat_roundtrip_seconds";
+        let _ = writeln!(out, "# HELP {} Time between a message and its response", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        // Buckets are cumulative
+        let mut count = 0;
+        for (index, bucket) in self.roundtrips.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match BUCKETS.get(index) {
+                Some(bound) => { let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count); }
+                None => { let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count); }
+            }
+        }
+        let micros = self.roundtrip_micros.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_sum {}", name, micros as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        out
+    }
+}
+
+/// Serve the metrics over HTTP on `port`, in the current Arbiter
+///
+/// Return the address we are listening on.
+pub fn serve(port: u16) -> io::Result<SocketAddr> {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
+    let listener = TcpListener::bind(&addr.into())?;
+    let addr = listener.local_addr()?;
+
+    Arbiter::spawn(listener.incoming()
+        .map_err(|e| println!("Metrics listener error: {}", e))
+        .for_each(|socket| {
+            Arbiter::spawn(respond(socket));
+            Ok(())
+        }));
+
+    Ok(addr)
+}
+
+/// Read a request and answer it
+///
+/// The request is expected to fit in a single read, which is the case
+/// for the usual scrapers.
+fn respond(socket: TcpStream) -> impl Future<Item = (), Error = ()> {
+    ::tokio_io::io::read(socket, vec![0; 1024])
+        .and_then(|(socket, buffer, n)| {
+            let response = match request_path(&buffer[..n]) {
+                Some("/metrics") => http_response("200 OK", &METRICS.render()),
+                _ => http_response("404 Not Found", "")
+            };
+            ::tokio_io::io::write_all(socket, response)
+        })
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Return the path of a GET request
+fn request_path(request: &[u8]) -> Option<&str> {
+    let line = ::std::str::from_utf8(request).ok()?.lines().next()?;
+    let mut parts = line.split(' ');
+
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Some(path),
+        _ => None
+    }
+}
+
+/// Make a HTTP/1.0 response, the connection is closed after it
+fn http_response(status: &str, body: &str) -> Vec<u8> {
+    format!("HTTP/1.0 {}\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {}", status, body.len(), body).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use super::{Metrics, request_path};
+
+    #[test]
+    fn render_counters_and_histogram() {
+        let metrics = Metrics::new();
+        metrics.connections.store(3, Ordering::Relaxed);
+        metrics.roundtrip(Duration::from_micros(500));
+        metrics.roundtrip(Duration::from_millis(20));
+        metrics.roundtrip(Duration::from_secs(10));
+
+        let out = metrics.render();
+
+        assert!(out.contains("\nchat_connections_total 3\n"));
+        assert!(out.contains("\nchat_roundtrip_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.contains("\nchat_roundtrip_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("\nchat_roundtrip_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("\nchat_roundtrip_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("\nchat_roundtrip_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn parse_request_path() {
+        assert_eq!(request_path(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"), Some("/metrics"));
+        assert_eq!(request_path(b"POST /metrics HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(request_path(b"\xFF"), None);
+    }
+}
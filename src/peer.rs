@@ -1,18 +1,63 @@
 //! Peer Actor
 
 use actix::dev::ToEnvelope;
-use tokio_io::io::WriteHalf;
 use actix::prelude::*;
 use actix::io::{Writer, WriteHandler};
 use tokio_tcp::TcpStream;
 use bytes::Bytes;
-use std::time::{Instant};
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use std::time::{Duration, Instant};
 use std::collections::VecDeque;
-use tokio_io::AsyncRead;
+use std::borrow::Cow;
+use std::fmt;
+use std::mem;
+use std::fs::{self, OpenOptions};
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::net::{Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio_io::{AsyncRead, AsyncWrite};
+use futures::{future, task, Future, Poll, Stream};
+use futures::sync::oneshot;
+use tokio::timer::Delay;
 
-use reader::{Reader, ReaderError, Kind, to_binary};
+use atty;
+use events::Event;
+use metrics::{Metrics, METRICS};
+use crypto::TAG_LEN;
+use reader::{Pending, Reader, ReaderError, Kind, Transport, to_binary, length_flag_name, now_micros};
+use transfer::{self, Finished, Outgoing, TransferError, Transfers};
 use user::UserInput;
-use {Config, Display};
+use {Config, Display, Oversize, SlowPeer, Unsolicited};
+
+/// Color of the received messages
+const DATA_COLOR: &str = "\x1b[32m";
+/// Color of the responses and roundtrip times
+const RESPONSE_COLOR: &str = "\x1b[36m";
+/// Color of the connection events
+const EVENT_COLOR: &str = "\x1b[33m";
+/// Reset the color
+const RESET_COLOR: &str = "\x1b[0m";
+/// Lines printed without color
+const NO_COLOR: &str = "";
+
+/// High watermark used to detect slow peers when `--write-buffer` is
+/// not set
+const SLOW_PEER_WATERMARK: usize = 64 * 1024;
+
+/// Delay before writing again after a transient error, with
+/// `--write-retries`
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How long a typing indicator stays displayed without a new one,
+/// longer than the time between two of them while typing
+const TYPING_DISPLAY: Duration = Duration::from_secs(5);
+
+/// Messages a Peer's mailbox holds before `--relay-full` applies, the
+/// default of actix. More with `--history`
+const MAILBOX_CAPACITY: usize = 16;
 
 /// Peer Actor
 ///
@@ -21,71 +66,1312 @@ pub struct Peer<T>
 where
     T: Actor,
     T: Handler<PeerClose>,
-    T::Context: ToEnvelope<T, PeerClose>
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    /// Parent Actor
+    parent: Addr<T>,
+    /// An handle to a writable socket
+    writer: Writer<Counted<Box<dyn AsyncWrite>>, ::std::io::Error>,
+    /// Number of bytes given to `writer`
+    queued: u64,
+    /// Number of bytes `writer` wrote on the socket
+    flushed: Rc<Cell<u64>>,
+    /// Messages waiting for a response and not fully written yet, see
+    /// [`Counted`]
+    unwritten: Unwritten,
+    /// Number of times the last failed write was retried
+    write_retried: Rc<Cell<u32>>,
+    /// State of the pending buffer of the [`Reader`], reported on
+    /// [`Inspect`]
+    reader: Rc<Cell<Pending>>,
+    /// Since when the write buffer is over its high watermark, with
+    /// [`SlowPeer::Drop`]
+    slow_since: Option<Instant>,
+    /// Another handle to the socket, to shut down its write half. `None`
+    /// when the transport isn't a TCP socket
+    socket: Option<TcpStream>,
+    /// Messages waiting for their response, used to determine the
+    /// roundtrip time of a message
+    delays: VecDeque<Sent>,
+    /// Messages waiting for a free slot in the `--window`
+    waiting: VecDeque<Arc<[u8]>>,
+    /// A [`HalfClose`] was requested while messages were waiting
+    half_close_waiting: bool,
+    /// Sending the user input is paused with [`Pause`], it waits in
+    /// `waiting`
+    paused: bool,
+    /// Senders of the inputs taken while paused, they wait for the
+    /// resume
+    resumed: Vec<oneshot::Sender<()>>,
+    /// User input received within the `--coalesce` window, sent as a
+    /// single message
+    coalesced: Vec<u8>,
+    /// Sends `coalesced` once the `--coalesce` window is over
+    coalesce_timer: Option<SpawnHandle>,
+    /// Closes the connection if the client doesn't send a
+    /// [`Kind::Hello`] in time, with `--handshake-timeout`
+    handshake_timer: Option<SpawnHandle>,
+    /// A typing indicator is displayed, on a line we erase before
+    /// printing anything else
+    typing_shown: Cell<bool>,
+    /// Erases the typing indicator after [`TYPING_DISPLAY`]
+    typing_timer: Option<SpawnHandle>,
+    /// Counters of the data sent and received
+    stats: Stats,
+    /// Reason received from the other side when it closed the connection
+    close_reason: Option<String>,
+    /// We sent a [`Kind::Close`] message and are closing the socket
+    closing: bool,
+    /// Reason of a [`Disconnect`] waiting for the pending messages to
+    /// be written
+    draining: Option<String>,
+    /// We sent a [`Kind::HalfClose`] message, we only read from now on
+    half_closed: bool,
+    /// The other side sent a [`Kind::HalfClose`] message, we only write
+    remote_half_closed: bool,
+    /// When the connection was established
+    connected_at: Instant,
+    /// Invalid data was received
+    protocol_error: bool,
+    /// Last time we received something from the other side
+    last_activity: Instant,
+    /// Messages waiting to be written when `--throttle` is set
+    throttled: VecDeque<(Kind, Bytes)>,
+    /// Control messages waiting to be written with `--throttle`, they
+    /// are written before `throttled`
+    urgent: VecDeque<(Kind, Bytes)>,
+    /// Rest of a message partially written with `--throttle`, written
+    /// before anything else
+    partial: Option<Bytes>,
+    /// Bytes `--throttle` allows to write now, refilled with the time
+    /// elapsed since `throttle_at`
+    throttle_budget: f64,
+    /// Last time `throttle_budget` was refilled
+    throttle_at: Instant,
+    /// End of the last message, cut in the middle of a utf8 character,
+    /// with `--display-stream`
+    utf8_tail: Vec<u8>,
+    /// Files being received
+    transfers: Transfers,
+    /// Id of the next file we send
+    next_transfer: u32,
+    /// Files being sent, a chunk at a time
+    outgoing: VecDeque<Outgoing>,
+    /// Number of the last file written in `--dump-dir`
+    dumped: u32,
+    /// Maximum length of the messages the other side takes, when it
+    /// told it with a [`Kind::MaxMessage`]
+    peer_max_message: Option<usize>,
+    /// Mask of the kinds the client subscribed to with a
+    /// [`Kind::Subscribe`], in server mode. All the kinds without it
+    subscribed: Option<u16>,
+    /// Sequence number of the next message we send
+    next_seq: u32,
+    /// Sequence number we expect on the next received message
+    expected_seq: u32,
+    /// Id of the connection, printed in front of our lines when the
+    /// client has several connections
+    id: usize,
+    /// Address of the other side
+    addr: Option<SocketAddr>,
+    /// Nickname the server registered for the client, printed in front
+    /// of our lines in server mode
+    nick: Option<String>,
+    /// Configuration
+    config: Config
+}
+
+/// A message sent, waiting for its response
+struct Sent {
+    /// When it was sent
+    at: Instant,
+    /// When its last byte was written on the socket
+    written_at: WrittenAt,
+    /// Its sequence number, set once framed
+    seq: Option<u32>,
+    /// Length of its payload
+    len: usize,
+    /// Forwarded with [`Forward`], its roundtrip time is not measured
+    forwarded: bool
+}
+
+impl Sent {
+    /// Return the roundtrip time of the message, for a response read at
+    /// `received_at`
+    ///
+    /// It counts from when the message was on the wire, the time it
+    /// waited in our buffers is left out.
+    fn rtt(&self, received_at: Option<Instant>) -> Duration {
+        let from = self.written_at.get().unwrap_or(self.at);
+        received_at.unwrap_or_else(Instant::now).saturating_duration_since(from)
+    }
+}
+
+/// Set once the last byte of a message is written on the socket
+type WrittenAt = Rc<Cell<Option<Instant>>>;
+
+/// Messages not fully written yet, with the number of bytes written on
+/// the socket once they are
+type Unwritten = Rc<RefCell<VecDeque<(u64, WrittenAt)>>>;
+
+/// Counters of the data exchanged with a peer
+///
+/// Byte counts include the headers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    /// Number of messages written, including responses
+    pub messages_sent: u64,
+    /// Number of bytes written
+    pub bytes_sent: u64,
+    /// Number of messages received, including responses
+    pub messages_received: u64,
+    /// Number of bytes received
+    pub bytes_received: u64,
+    /// Number of responses received to the messages we sent
+    pub responses: u64
+}
+
+impl Stats {
+    /// Add the counters of `other` to ours
+    pub fn add(&mut self, other: &Stats) {
+        self.messages_sent += other.messages_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.messages_received += other.messages_received;
+        self.bytes_received += other.bytes_received;
+        self.responses += other.responses;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sent {} messages ({} bytes), received {} messages ({} bytes)",
+               self.messages_sent, self.bytes_sent,
+               self.messages_received, self.bytes_received)
+    }
+}
+
+/// A Actix message to notify that the Peer as been stopped
+#[derive(Message)]
+pub struct PeerClose {
+    /// Counters of the connection
+    pub stats: Stats,
+    /// Reason given by the other side when it closed the connection.
+    /// `None` when the connection was dropped
+    pub reason: Option<String>,
+    /// Id of the connection given to [`Peer::new`]
+    pub id: usize,
+    /// Address of the other side
+    pub addr: Option<SocketAddr>,
+    /// How long the connection lasted
+    pub uptime: Duration,
+    /// The connection was closed because of invalid data
+    pub protocol_error: bool
+}
+
+/// A Actix message giving the parent a message received in server mode
+///
+/// The parent decides whether to relay it to the other peers.
+#[derive(Message)]
+pub struct Relay {
+    /// Id of the connection it comes from
+    pub id: usize,
+    /// [`Kind`] of the message
+    pub kind: Kind,
+    /// Payload of the message
+    pub payload: Bytes
+}
+
+/// A Actix message asking the Peer to send a message of a [`Kind`]
+#[derive(Message)]
+pub struct Forward(pub Kind, pub Bytes);
+
+/// A Actix message asking the Peer to close the connection
+///
+/// The reason is sent to the other side in a [`Kind::Close`] message
+#[derive(Message)]
+pub struct Disconnect(pub String);
+
+/// A Actix message asking the Peer to shut down the write half of the
+/// socket, once the pending data is written
+///
+/// The Peer keeps reading until the other side closes the connection.
+#[derive(Message)]
+pub struct HalfClose;
+
+/// A Actix message asking the Peer to send a file, in chunks
+#[derive(Message)]
+pub struct SendFile(pub PathBuf);
+
+/// A Actix message pausing or resuming the sending of the user input
+///
+/// Sent on SIGUSR1 and SIGUSR2, see [`pause_signals`]. While paused,
+/// the input is queued; it's written when sending resumes. The
+/// [`UserInput`] taken meanwhile resolve on the resume.
+#[derive(Message, Debug, Clone, Copy, PartialEq)]
+pub enum Pause {
+    /// Pause, or resume when paused
+    Toggle,
+    /// Resume and write the queued input
+    Resume
+}
+
+/// Stream of the [`Pause`] messages: SIGUSR1 toggles the pause and
+/// SIGUSR2 resumes
+#[cfg(unix)]
+pub fn pause_signals() -> impl Stream<Item = Pause, Error = ()> {
+    use tokio_signal::unix::{Signal, SIGUSR1, SIGUSR2};
+
+    let toggle = Signal::new(SIGUSR1).flatten_stream().map(|_| Pause::Toggle);
+    let resume = Signal::new(SIGUSR2).flatten_stream().map(|_| Pause::Resume);
+    toggle.select(resume).map_err(|_| ())
+}
+
+/// There are no such signals outside of Unix
+#[cfg(not(unix))]
+pub fn pause_signals() -> impl Stream<Item = Pause, Error = ()> {
+    ::futures::stream::empty()
+}
+
+/// A Actix message asking the Peer to print the state of its
+/// [`Reader`], to tell whether it waits for the end of a frame
+///
+/// Sent on SIGALRM, see [`inspect_signals`].
+#[derive(Message, Debug, Clone, Copy)]
+pub struct Inspect;
+
+/// Stream of the [`Inspect`] messages, one per SIGALRM
+#[cfg(unix)]
+pub fn inspect_signals() -> impl Stream<Item = Inspect, Error = ()> {
+    use tokio_signal::unix::{Signal, SIGALRM};
+
+    Signal::new(SIGALRM).flatten_stream().map(|_| Inspect).map_err(|_| ())
+}
+
+/// There is no such signal outside of Unix
+#[cfg(not(unix))]
+pub fn inspect_signals() -> impl Stream<Item = Inspect, Error = ()> {
+    ::futures::stream::empty()
+}
+
+/// A Actix message changing how the Peer displays the received
+/// messages, sent for `/display <mode>`
+#[derive(Message, Clone)]
+pub struct SetDisplay(pub Display);
+
+/// A Actix message giving the Peer a reloaded configuration
+///
+/// Only the options [`Config::reconfigure`] takes are changed.
+#[derive(Message)]
+pub struct Reconfigure(pub Config);
+
+/// A Actix message sent to [`Hooks::roundtrip`] for each response
+/// received
+#[derive(Message)]
+#[allow(dead_code)]
+pub struct Roundtrip {
+    /// Id of the connection
+    pub id: usize,
+    /// Sequence number of the message
+    pub seq: u32,
+    /// Time between sending the message and receiving its response
+    pub rtt: Duration,
+    /// Length of the message
+    pub len: usize,
+    /// Payload of the response
+    pub response: Bytes
+}
+
+/// A Actix message sent to [`Hooks::received`] for each message
+/// received from the other side
+#[derive(Message)]
+#[allow(dead_code)]
+pub struct Received {
+    /// Id of the connection
+    pub id: usize,
+    /// [`Kind::Data`] or [`Kind::History`]
+    pub kind: Kind,
+    /// Payload of the message
+    pub payload: Bytes
+}
+
+/// Recipients notified of what happens on the connections, to build
+/// on top of the actors without parsing what they print
+///
+/// They are all `None` in the binary.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    /// Notified of each roundtrip measured
+    pub roundtrip: Option<Recipient<Roundtrip>>,
+    /// Notified of each message received
+    pub received: Option<Recipient<Received>>
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+         .field("roundtrip", &self.roundtrip.is_some())
+         .field("received", &self.received.is_some())
+         .finish()
+    }
+}
+
+impl<T> Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    /// Create a Peer
+    /// It takes ownership of the socket and add the stream of the
+    /// socket to its Context Actor.
+    ///
+    /// `id` identifies the connection in the parent.
+    pub fn new(config: Config, parent: Addr<T>, socket: TcpStream, id: usize) -> Addr<Peer<T>> {
+        let handle = socket.try_clone().ok();
+        let addr = socket.peer_addr().ok();
+        let (read, write) = socket.split();
+        Peer::with_transport(config, parent, read, write, addr, handle, id)
+    }
+
+    /// Create a Peer on any transport, read with `read` and written with
+    /// `write`
+    ///
+    /// `addr` is the address of the other side, and `socket` a handle
+    /// to shut down the write half of a TCP connection after a
+    /// [`HalfClose`]. Other transports are only closed.
+    pub fn with_transport<R, W>(config: Config, parent: Addr<T>, read: R, write: W,
+                                addr: Option<SocketAddr>, handle: Option<TcpStream>, id: usize) -> Addr<Peer<T>>
+    where
+        R: Transport + 'static,
+        W: AsyncWrite + 'static
+    {
+        let write: Box<dyn AsyncWrite> = Box::new(write);
+        let flushed = Rc::new(Cell::new(0));
+        let write_retried = Rc::new(Cell::new(0));
+        let unwritten = Unwritten::default();
+        let write = Counted {
+            inner: write,
+            count: flushed.clone(),
+            max_retries: config.write_retries,
+            retried: write_retried.clone(),
+            unwritten: unwritten.clone()
+        };
+
+        Peer::create(move |ctx| {
+            let reader = Reader::new(read, config.read_buffer, config.framing.clone())
+                .max_line(config.max_message);
+            let reader_probe = reader.probe();
+            ctx.add_stream(reader);
+            // The server gives a newcomer its banner and history at once
+            ctx.set_mailbox_capacity(MAILBOX_CAPACITY.max(config.history + 1));
+            let mut writer = actix::io::Writer::new(write, ctx);
+            let (low, high) = config.write_buffer;
+
+            if config.slow_peer == SlowPeer::Drop {
+                // The writer never blocks, we watch its buffer instead
+                writer.set_buffer_capacity(low, usize::MAX);
+                let watermark = if high > 0 { high } else { SLOW_PEER_WATERMARK };
+                ctx.run_interval(Duration::from_secs(1), move |peer, ctx| {
+                    peer.check_slow(watermark as u64, ctx);
+                });
+            } else {
+                writer.set_buffer_capacity(low, high);
+            }
+
+            if let Some(timeout) = config.idle_timeout {
+                ctx.run_interval(Duration::from_secs(1), move |peer, ctx| {
+                    if peer.last_activity.elapsed() > timeout {
+                        peer.print(EVENT_COLOR, &format!("No activity for {}s, closing",
+                                                         timeout.as_secs()));
+                        peer.close("idle timeout", ctx);
+                    }
+                });
+            }
+
+            Peer {
+                parent,
+                writer,
+                queued: 0,
+                flushed,
+                unwritten,
+                write_retried,
+                reader: reader_probe,
+                slow_since: None,
+                socket: handle,
+                delays: VecDeque::new(),
+                waiting: VecDeque::new(),
+                half_close_waiting: false,
+                paused: false,
+                resumed: Vec::new(),
+                coalesced: Vec::new(),
+                coalesce_timer: None,
+                handshake_timer: None,
+                typing_shown: Cell::new(false),
+                typing_timer: None,
+                stats: Stats::default(),
+                close_reason: None,
+                closing: false,
+                draining: None,
+                half_closed: false,
+                remote_half_closed: false,
+                connected_at: Instant::now(),
+                protocol_error: false,
+                last_activity: Instant::now(),
+                throttled: VecDeque::new(),
+                urgent: VecDeque::new(),
+                partial: None,
+                // Full, capped on the first refill
+                throttle_budget: f64::INFINITY,
+                throttle_at: Instant::now(),
+                utf8_tail: Vec::new(),
+                transfers: Transfers::new(config.download_dir.clone(), config.receive_files),
+                next_transfer: 0,
+                outgoing: VecDeque::new(),
+                dumped: 0,
+                peer_max_message: None,
+                subscribed: None,
+                next_seq: 0,
+                expected_seq: 0,
+                id,
+                addr,
+                nick: None,
+                config
+            }
+        })
+    }
+
+    /// Print a line, in `color` with `--color`
+    ///
+    /// The line starts with the `--label`, then the connection id when
+    /// the client has several connections, or the nickname of the
+    /// client in server mode.
+    fn print(&self, color: &str, line: &str) {
+        self.erase_typing();
+        let prefix = self.prefix();
+        if self.config.color && !color.is_empty() {
+            println!("{}{}{}{}", color, prefix, line, RESET_COLOR);
+        } else {
+            println!("{}{}", prefix, line);
+        }
+    }
+
+    /// Return what is printed in front of our lines
+    fn prefix(&self) -> String {
+        let mut prefix = format!("[{}] ", self.config.label);
+        if self.config.connections > 1 {
+            prefix.push_str(&format!("[#{}] ", self.id));
+        }
+        if let Some(ref nick) = self.nick {
+            prefix.push_str(&format!("[{}] ", nick));
+        }
+        prefix
+    }
+
+    /// Display a typing indicator until the next line we print, or for
+    /// [`TYPING_DISPLAY`]
+    ///
+    /// When the output is not a terminal, it's printed as a line.
+    fn show_typing(&mut self, line: &str, ctx: &mut Context<Self>) {
+        if !atty::is(atty::Stream::Stdout) {
+            self.print(EVENT_COLOR, line);
+            return;
+        }
+        self.erase_typing();
+        let prefix = self.prefix();
+        if self.config.color {
+            print!("{}{}{}{}", EVENT_COLOR, prefix, line, RESET_COLOR);
+        } else {
+            print!("{}{}", prefix, line);
+        }
+        let _ = io::stdout().flush();
+        self.typing_shown.set(true);
+
+        if let Some(timer) = self.typing_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        self.typing_timer = Some(ctx.run_later(TYPING_DISPLAY, |peer, _| {
+            peer.typing_timer = None;
+            peer.erase_typing();
+            let _ = io::stdout().flush();
+        }));
+    }
+
+    /// Erase the typing indicator, if displayed
+    fn erase_typing(&self) {
+        if self.typing_shown.replace(false) {
+            print!("\r\x1b[2K");
+        }
+    }
+
+    /// Frame `data` as a message of `kind` and write it
+    ///
+    /// With `--throttle`, the message is queued and framed once it's
+    /// written over time by [`Peer::write_throttled`]: the control
+    /// messages go first, and the sequence numbers follow the order on
+    /// the wire.
+    fn send(&mut self, data: &[u8], kind: Kind, ctx: &mut Context<Self>) {
+        if !self.config.framing.has_header() && kind != Kind::Data {
+            // Nothing but the payloads without header
+            return;
+        }
+        if self.config.throttle.is_none() {
+            if let Some(bytes) = self.frame(data, kind) {
+                self.write_socket(&bytes);
+            }
+            return;
+        }
+
+        let idle = self.throttled_empty();
+        if kind.is_control() {
+            self.urgent.push_back((kind, Bytes::from(data)));
+        } else {
+            self.throttled.push_back((kind, Bytes::from(data)));
+        }
+        if idle {
+            self.write_throttled(ctx);
+        }
+    }
+
+    /// Frame `data` with the next sequence number and count it
+    ///
+    /// A message that can't be framed is dropped, `None` is returned.
+    fn frame(&mut self, data: &[u8], kind: Kind) -> Option<Bytes> {
+        // Data messages are framed in the order they are sent
+        let sent = match kind {
+            Kind::Data => self.delays.iter().position(|sent| sent.seq.is_none()),
+            _ => None
+        };
+
+        let bytes = match to_binary(data, kind, self.next_seq, &self.config.framing) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.print(EVENT_COLOR, &format!("Can not send a message: {}", e));
+                if let Some(index) = sent {
+                    // No response will come for it
+                    self.delays.remove(index);
+                }
+                return None;
+            }
+        };
+        if let Some(index) = sent {
+            self.delays[index].seq = Some(self.next_seq);
+            // The frame is the next one given to the writer
+            let end = self.queued + bytes.len() as u64;
+            self.unwritten.borrow_mut().push_back((end, self.delays[index].written_at.clone()));
+        }
+        if self.config.show_framing {
+            let tag = match self.config.framing.hmac {
+                Some(_) => format!(", {} bytes tag", TAG_LEN),
+                None => String::new()
+            };
+            self.print(EVENT_COLOR, &format!(
+                "Framing {} #{}: {} bytes payload, {} length{}, {} bytes on the wire ({} bytes overhead)",
+                kind.name(), self.next_seq, data.len(), length_flag_name(bytes[0]), tag,
+                bytes.len(), bytes.len() - data.len()
+            ));
+        }
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        self.stats.messages_sent += 1;
+        self.stats.bytes_sent += bytes.len() as u64;
+        Metrics::add(&METRICS.messages_sent, 1);
+        Metrics::add(&METRICS.bytes_sent, bytes.len() as u64);
+        Some(bytes)
+    }
+
+    /// Return true when nothing waits to be written with `--throttle`
+    fn throttled_empty(&self) -> bool {
+        self.partial.is_none() && self.urgent.is_empty() && self.throttled.is_empty()
+    }
+
+    /// Return the next bytes to write with `--throttle`
+    fn next_throttled(&mut self) -> Option<Bytes> {
+        if let Some(bytes) = self.partial.take() {
+            return Some(bytes);
+        }
+        loop {
+            let (kind, data) = self.urgent.pop_front().or_else(|| self.throttled.pop_front())?;
+            if let Some(bytes) = self.frame(&data, kind) {
+                return Some(bytes);
+            }
+        }
+    }
+
+    /// Send a [`Kind::Data`] message and wait for its response
+    fn send_data(&mut self, data: &[u8], ctx: &mut Context<Self>) {
+        if !self.config.framing.has_header() || self.remote_half_closed {
+            // No response will come for it
+            self.send(data, Kind::Data, ctx);
+            return;
+        }
+        self.delays.push_back(Sent {
+            at: Instant::now(),
+            written_at: WrittenAt::default(),
+            seq: None,
+            len: data.len(),
+            forwarded: false
+        });
+        self.send(data, Kind::Data, ctx);
+    }
+
+    /// Number of our [`Kind::Data`] messages waiting for their response,
+    /// the forwarded ones left out
+    fn unanswered(&self) -> usize {
+        self.delays.iter().filter(|sent| !sent.forwarded).count()
+    }
+
+    /// Return true if `--window` allows to send another message now
+    fn window_open(&self) -> bool {
+        self.config.window == 0 || self.unanswered() < self.config.window
+    }
+
+    /// Send the messages waiting for the `--window`, as long as it
+    /// allows it
+    fn send_waiting(&mut self, ctx: &mut Context<Self>) {
+        while self.window_open() && !self.paused && !self.closing && !self.half_closed {
+            match self.waiting.pop_front() {
+                Some(data) => self.send_data(&data, ctx),
+                None => break
+            }
+        }
+        if self.waiting.is_empty() && self.half_close_waiting {
+            self.half_close_waiting = false;
+            self.half_close(ctx);
+        }
+        self.check_drained(ctx);
+    }
+
+    /// Send the user input, see [`Peer::queue_input`]
+    ///
+    /// Input longer than the [`Kind::MaxMessage`] of the other side is
+    /// handled according to `--on-oversize`.
+    fn send_input(&mut self, data: Arc<[u8]>, ctx: &mut Context<Self>) {
+        match self.peer_max_message {
+            Some(max) if data.len() > max => {
+                let parts = fit_message(&data, max, self.config.on_oversize);
+                if parts.is_empty() {
+                    self.print(EVENT_COLOR, &format!("Message of {} bytes not sent, the peer takes at most {} bytes",
+                                                     data.len(), max));
+                }
+                for part in parts {
+                    self.queue_input(part.into(), ctx);
+                }
+            }
+            _ => self.queue_input(data, ctx)
+        }
+    }
+
+    /// Send `data` now, or once the `--window` or the [`Pause`] allows
+    /// it
+    fn queue_input(&mut self, data: Arc<[u8]>, ctx: &mut Context<Self>) {
+        if self.window_open() && !self.paused && self.waiting.is_empty() {
+            self.send_data(&data, ctx);
+        } else {
+            self.waiting.push_back(data);
+        }
+    }
+
+    /// Add the user input to the message being coalesced, and send it
+    /// once nothing else came for `window`
+    ///
+    /// The inputs are joined with a newline. The message is sent
+    /// before it would grow over `--max-message`.
+    fn coalesce(&mut self, data: &[u8], window: Duration, ctx: &mut Context<Self>) {
+        if !self.coalesced.is_empty() && self.coalesced.len() + 1 + data.len() > self.config.max_message {
+            self.flush_coalesced(ctx);
+        }
+        if !self.coalesced.is_empty() && !self.coalesced.ends_with(b"\n") {
+            self.coalesced.push(b'\n');
+        }
+        self.coalesced.extend_from_slice(data);
+
+        if let Some(timer) = self.coalesce_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        self.coalesce_timer = Some(ctx.run_later(window, |peer, ctx| {
+            peer.coalesce_timer = None;
+            peer.flush_coalesced(ctx);
+        }));
+    }
+
+    /// Send the input coalesced so far
+    fn flush_coalesced(&mut self, ctx: &mut Context<Self>) {
+        if let Some(timer) = self.coalesce_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        if !self.coalesced.is_empty() {
+            let data = mem::take(&mut self.coalesced);
+            self.send_input(data.into(), ctx);
+        }
+    }
+
+    /// Send a [`Kind::HalfClose`] message and stop writing
+    fn half_close(&mut self, ctx: &mut Context<Self>) {
+        if self.closing || self.half_closed {
+            return;
+        }
+        self.flush_coalesced(ctx);
+        if !self.waiting.is_empty() {
+            // Send the waiting messages first
+            self.half_close_waiting = true;
+            return;
+        }
+        self.send(&[], Kind::HalfClose, ctx);
+        self.half_closed = true;
+        if self.throttled_empty() {
+            self.writer.close();
+        }
+    }
+
+    /// Prepend the end of the previous message to `message`, and keep
+    /// its own end if it is cut in the middle of a utf8 character
+    fn join_stream(&mut self, message: Bytes) -> Bytes {
+        if self.utf8_tail.is_empty() && incomplete_utf8(&message) == 0 {
+            return message;
+        }
+        let mut data = mem::take(&mut self.utf8_tail);
+        data.extend_from_slice(&message);
+        let end = data.len() - incomplete_utf8(&data);
+        self.utf8_tail = data.split_off(end);
+        Bytes::from(data)
+    }
+
+    /// Return true when the client subscribed to `kind`, or didn't
+    /// subscribe at all
+    fn subscribed_to(&self, kind: Kind) -> bool {
+        self.subscribed.map_or(true, |mask| mask & Kind::mask(&[kind]) != 0)
+    }
+
+    /// Put the header bytes of `msg` in front of `line`, with
+    /// `--show-header`
+    fn with_header(&self, msg: &Msg, line: String) -> String {
+        let header = msg.header();
+        if !self.config.show_header || header.is_empty() {
+            return line;
+        }
+        format!("HDR: {} | {}", hex_bytes(&header), line)
+    }
+
+    /// Format a received payload according to `display`, the line
+    /// starts with `label`
+    ///
+    /// The payload is truncated to `--max-print` bytes.
+    fn format_payload(&self, label: &str, message: &Bytes, display: &Display) -> String {
+        let max = match self.config.max_print {
+            0 => message.len(),
+            max => max.min(message.len())
+        };
+        let suffix = if max < message.len() {
+            format!("… ({} total bytes)", message.len())
+        } else {
+            String::new()
+        };
+        let binary = format!("{}: {:?}{}", label, message.slice_to(max), suffix);
+        match *display {
+            Display::None => format!("{} bytes received", message.len()),
+            _ if message.is_empty() => format!("{}: 0 bytes received", label),
+            Display::Binary => binary,
+            Display::Utf8 => {
+                match String::from_utf8(message.to_vec()) {
+                    Ok(utf8) => format!("{}[utf8]: {}{}", label, truncate(&utf8, max), suffix),
+                    _ => binary
+                }
+            },
+            Display::Utf8Lossy => {
+                format!("{}[utf8]: {}{}", label, lossy_prefix(message, max), suffix)
+            },
+            Display::Utf16 => {
+                match decode_utf16(message) {
+                    Some(utf16) => format!("{}[utf16]: {}{}", label, truncate(&utf16, max), suffix),
+                    _ => binary
+                }
+            },
+            Display::HexDump => {
+                format!("{}[hexdump]: {} bytes{}\n{}", label, max, suffix, hexdump(&message[..max]))
+            }
+        }
+    }
+
+    /// Write an event with `--event-log`
+    fn log_event(&self, event: &Event) {
+        if let Some(ref log) = self.config.event_log {
+            log.record(event);
+        }
+    }
+
+    /// Give a received message to [`Hooks::received`]
+    fn notify_received(&self, msg: &Msg) {
+        if let Some(ref hook) = self.config.hooks.received {
+            let _ = hook.do_send(Received {
+                id: self.id,
+                kind: msg.kind,
+                payload: msg.message()
+            });
+        }
+    }
+
+    /// Handle a [`Kind::Response`] received while none of our messages
+    /// waits for one, according to `--on-unsolicited-response`
+    fn unsolicited_response(&mut self, msg: &Msg, ctx: &mut Context<Self>) {
+        match self.config.on_unsolicited_response {
+            Unsolicited::Ignore => {},
+            Unsolicited::Warn => {
+                self.print(EVENT_COLOR, &format!("Unsolicited response: {:?}", msg.message()));
+                self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: "unsolicited response" });
+            },
+            Unsolicited::Close => {
+                self.print(EVENT_COLOR, "Unsolicited response, closing");
+                self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: "unsolicited response" });
+                self.protocol_error = true;
+                self.close("unsolicited response", ctx);
+            }
+        }
+    }
+
+    /// Hand a received message to the parent, in server mode
+    fn relay(&self, msg: &Msg) {
+        if !self.config.is_client {
+            self.parent.do_send(Relay {
+                id: self.id,
+                kind: msg.kind,
+                payload: msg.message()
+            });
+        }
+    }
+
+    /// Give `bytes` to the writer
+    fn write_socket(&mut self, bytes: &[u8]) {
+        self.queued += bytes.len() as u64;
+        self.writer.write(bytes);
+    }
+
+    /// Close the connection when the write buffer stays over `watermark`
+    /// for longer than `--slow-peer-timeout`
+    fn check_slow(&mut self, watermark: u64, ctx: &mut Context<Self>) {
+        let pending = self.queued - self.flushed.get();
+
+        if pending <= watermark {
+            self.slow_since = None;
+            return;
+        }
+
+        let since = *self.slow_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= self.config.slow_peer_timeout {
+            self.print(EVENT_COLOR, &format!("Peer too slow, {} bytes not written, dropping",
+                                             pending));
+            ctx.stop();
+        }
+    }
+
+    /// Refill the `--throttle` budget with the time elapsed since the
+    /// last refill, and return the number of bytes it allows to write
+    fn refill_throttle(&mut self) -> usize {
+        let rate = self.config.throttle.unwrap_or(usize::MAX);
+        let now = Instant::now();
+        self.throttle_budget = refill(self.throttle_budget, rate, now.duration_since(self.throttle_at));
+        self.throttle_at = now;
+        self.throttle_budget as usize
+    }
+
+    /// Write the throttled data, at most `--throttle` bytes per second
+    ///
+    /// It writes what the budget allows, and reschedules itself every
+    /// 100ms until the queues are empty. Once `--throttle` is removed by
+    /// a [`Reconfigure`], what's left is written at once by its handler.
+    fn write_throttled(&mut self, ctx: &mut Context<Self>) {
+        let mut budget = self.refill_throttle();
+        let available = budget;
+
+        while budget > 0 {
+            let mut bytes = match self.next_throttled() {
+                Some(bytes) => bytes,
+                None => break
+            };
+            if bytes.len() > budget {
+                // Write what we can, the rest goes first on the next
+                // round: messages can't be interleaved
+                let now = bytes.split_to(budget);
+                self.write_socket(&now);
+                self.partial = Some(bytes);
+                budget = 0;
+                break;
+            }
+            budget -= bytes.len();
+            self.write_socket(&bytes);
+        }
+        self.throttle_budget -= (available - budget) as f64;
+
+        if !self.throttled_empty() {
+            ctx.run_later(Duration::from_millis(100), |peer, ctx| {
+                peer.write_throttled(ctx);
+            });
+        } else if self.closing || self.half_closed {
+            self.writer.close();
+        } else {
+            self.check_drained(ctx);
+        }
+    }
+
+    /// Send a file as [`Kind::Chunk`] messages followed by a
+    /// [`Kind::ChunkEnd`] message
+    ///
+    /// Only the metadata is sent here, the chunks are read and sent by
+    /// [`send_chunks`](#method.send_chunks).
+    fn send_file(&mut self, path: &Path, ctx: &mut Context<Self>) -> io::Result<()> {
+        let id = self.next_transfer;
+        let (outgoing, meta) = Outgoing::open(path, id)?;
+        self.next_transfer = self.next_transfer.wrapping_add(1);
+
+        self.print(NO_COLOR, &format!("Sending {} ({} bytes)", path.display(), outgoing.total()));
+        self.send(&meta, Kind::FileMeta, ctx);
+
+        self.outgoing.push_back(outgoing);
+        if self.outgoing.len() == 1 {
+            self.send_chunks(ctx);
+        }
+        Ok(())
+    }
+
+    /// Send the next chunk of the file at the front of `outgoing`, then
+    /// schedule the following one
+    ///
+    /// A chunk is sent once the previous one is written to the socket,
+    /// the file is not read faster than the peer takes it.
+    fn send_chunks(&mut self, ctx: &mut Context<Self>) {
+        if self.closing || self.half_closed {
+            self.outgoing.clear();
+            return;
+        }
+        let busy = self.queued - self.flushed.get() > transfer::CHUNK_SIZE as u64 || !self.throttled_empty();
+        if !busy {
+            let next = match self.outgoing.front_mut() {
+                Some(outgoing) => outgoing.next_message(),
+                None => return
+            };
+            match next {
+                Ok(Some((kind, payload))) => self.send(&payload, kind, ctx),
+                Ok(None) => {
+                    self.outgoing.pop_front();
+                },
+                Err(e) => {
+                    self.print(NO_COLOR, &format!("Can not send the file: {}", e));
+                    self.outgoing.pop_front();
+                }
+            }
+            if self.outgoing.is_empty() {
+                return;
+            }
+        }
+        let delay = if busy { Duration::from_millis(10) } else { Duration::from_millis(0) };
+        ctx.run_later(delay, |peer, ctx| peer.send_chunks(ctx));
+    }
+
+    /// Close the connection once the messages waiting for the
+    /// `--window` or for `--throttle` are written
+    ///
+    /// They are dropped after `--drain-timeout`, or on a second call.
+    fn drain(&mut self, reason: &str, ctx: &mut Context<Self>) {
+        self.flush_coalesced(ctx);
+        if self.draining.take().is_some() || (self.waiting.is_empty() && self.throttled_empty()) {
+            self.close(reason, ctx);
+            return;
+        }
+        self.print(EVENT_COLOR, "Sending the pending messages before closing");
+        self.draining = Some(reason.to_owned());
+
+        let timeout = self.config.drain_timeout;
+        ctx.run_later(timeout, move |peer, ctx| {
+            if let Some(reason) = peer.draining.take() {
+                peer.print(EVENT_COLOR, &format!("Messages still pending after {}s, closing",
+                                                 timeout.as_secs()));
+                peer.close(&reason, ctx);
+            }
+        });
+    }
+
+    /// Close the connection if we were draining it and nothing is
+    /// pending anymore
+    fn check_drained(&mut self, ctx: &mut Context<Self>) {
+        if self.draining.is_some() && self.waiting.is_empty() && self.throttled_empty() {
+            let reason = self.draining.take().unwrap_or_default();
+            self.close(&reason, ctx);
+        }
+    }
+
+    /// Send a [`Kind::Close`] message and close the socket
+    ///
+    /// The actor stops once the pending data has been written. With
+    /// `--throttle`, the queued messages are dropped, nothing can follow
+    /// the Close: see [`Peer::drain`] to write them first. A message
+    /// partially written is finished, the other side can't parse the
+    /// Close otherwise.
+    fn close(&mut self, reason: &str, ctx: &mut Context<Self>) {
+        if self.closing {
+            return;
+        }
+        self.closing = true;
+        if self.half_closed {
+            // We can't write anymore
+            ctx.stop();
+            return;
+        }
+        self.throttled.clear();
+        self.send(reason.as_bytes(), Kind::Close, ctx);
+        if self.throttled_empty() {
+            self.writer.close();
+        }
+    }
+}
+
+impl<T> Actor for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.config.advertise_max_message {
+            let mut payload = [0; 8];
+            BigEndian::write_u64(&mut payload, self.config.max_message as u64);
+            self.send(&payload, Kind::MaxMessage, ctx);
+        }
+        if let Some(nonce) = self.config.session_nonce {
+            let mut payload = [0; 8];
+            BigEndian::write_u64(&mut payload, nonce);
+            self.send(&payload, Kind::Nonce, ctx);
+        }
+        match self.config.handshake_timeout {
+            Some(timeout) if !self.config.is_client => {
+                self.handshake_timer = Some(ctx.run_later(timeout, move |peer, ctx| {
+                    peer.handshake_timer = None;
+                    peer.print(EVENT_COLOR, &format!("No nickname registered after {}s, closing",
+                                                     timeout.as_secs()));
+                    peer.log_event(&Event::Error { id: peer.id, addr: peer.addr, reason: "handshake timeout" });
+                    peer.close("handshake timeout", ctx);
+                }));
+            }
+            _ => ()
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        // Socket as been closed, notify the parent
+        if let Some(ref csv) = self.config.rtt_csv {
+            csv.flush();
+        }
+        self.print(EVENT_COLOR, &format!("Peer stats: {}", self.stats));
+        self.parent.do_send(PeerClose {
+            stats: self.stats,
+            reason: self.close_reason.take(),
+            id: self.id,
+            addr: self.addr,
+            uptime: self.connected_at.elapsed(),
+            protocol_error: self.protocol_error
+        });
+    }
+}
+
+impl<T> Handler<Disconnect> for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        self.drain(&msg.0, ctx);
+    }
+}
+
+impl<T> Handler<HalfClose> for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    type Result = ();
+
+    fn handle(&mut self, _: HalfClose, ctx: &mut Context<Self>) {
+        self.half_close(ctx);
+    }
+}
+
+impl<T> Handler<Forward> for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Forward, ctx: &mut Context<Self>) {
+        if msg.0 == Kind::Hello && !self.config.is_client {
+            // The name the server registered for our client
+            self.nick = Some(String::from_utf8_lossy(&msg.1).into_owned());
+        } else if !self.subscribed_to(msg.0) {
+            return;
+        }
+        if !self.closing && !self.half_closed {
+            if msg.0 == Kind::Data && self.config.framing.has_header() && !self.remote_half_closed {
+                // Its response comes in the order of the wire
+                self.delays.push_back(Sent {
+                    at: Instant::now(),
+                    written_at: WrittenAt::default(),
+                    seq: None,
+                    len: msg.1.len(),
+                    forwarded: true
+                });
+            }
+            self.send(&msg.1, msg.0, ctx);
+        }
+    }
+}
+
+impl<T> Handler<UserInput> for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    type Result = ResponseFuture<(), ()>;
+
+    /// While the write buffer drains, the Peer doesn't process any
+    /// message: the sender of `msg` waits until then for the result.
+    /// While paused, the input is queued and the result waits for the
+    /// resume, so the sender stops sending more.
+    fn handle(&mut self, msg: UserInput, ctx: &mut Context<Self>) -> Self::Result {
+        if self.draining.is_some() || self.closing {
+            self.print(EVENT_COLOR, "Closing the connection, message not sent");
+            return Box::new(future::ok(()));
+        }
+        if !self.subscribed_to(Kind::Data) {
+            return Box::new(future::ok(()));
+        }
+        // The user as submitted data, write it on the socket
+        match self.config.coalesce {
+            Some(window) => self.coalesce(&msg.0, window, ctx),
+            None => self.send_input(msg.0, ctx)
+        }
+        if !self.paused {
+            return Box::new(future::ok(()));
+        }
+        // Also resolved when the peer stops
+        let (resumed, wait) = oneshot::channel();
+        self.resumed.push(resumed);
+        Box::new(wait.then(|_| Ok(())))
+    }
+}
+
+impl<T> Handler<Pause> for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
 {
-    /// Parent Actor
-    parent: Addr<T>,
-    /// An handle to a writable socket
-    writer: Writer<WriteHalf<TcpStream>, ::std::io::Error>,
-    /// List of [`Instant`] used to determine the roundtrip time
-    /// of a message
-    delays: VecDeque<Instant>,
-    /// Configuration
-    config: Config
-}
+    type Result = ();
 
-/// A Actix message to notify that the Peer as been stopped
-#[derive(Message)]
-pub struct PeerClose;
+    fn handle(&mut self, msg: Pause, ctx: &mut Context<Self>) {
+        let paused = msg == Pause::Toggle && !self.paused;
+        if paused == self.paused {
+            return;
+        }
+        self.paused = paused;
+        if paused {
+            self.print(EVENT_COLOR, "Sending paused");
+        } else {
+            self.print(EVENT_COLOR, &format!("Sending resumed, {} messages queued", self.waiting.len()));
+            self.send_waiting(ctx);
+            for resumed in self.resumed.drain(..) {
+                let _ = resumed.send(());
+            }
+        }
+    }
+}
 
-impl<T> Peer<T>
+impl<T> Handler<Inspect> for Peer<T>
 where
     T: Actor,
     T: Handler<PeerClose>,
-    T::Context: ToEnvelope<T, PeerClose>
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
 {
-    /// Create a Peer
-    /// It takes ownership of the socket and add the stream of the
-    /// socket to its Context Actor.
-    pub fn new(config: Config, parent: Addr<T>, socket: TcpStream) -> Addr<Peer<T>> {
-        let (read, write) = socket.split();
+    type Result = ();
 
-        Peer::create(move |ctx| {
-            ctx.add_stream(Reader::new(read));
-            let mut writer = actix::io::Writer::new(write, ctx);
-            writer.set_buffer_capacity(0, 0);
+    fn handle(&mut self, _: Inspect, _ctx: &mut Context<Self>) {
+        let pending = self.reader.get();
+        self.print(EVENT_COLOR, &format!("Reader: {}", pending));
+    }
+}
 
-            Peer { parent, writer, delays: VecDeque::new(), config }
-        })
+impl<T> Handler<SetDisplay> for Peer<T>
+where
+    T: Actor,
+    T: Handler<PeerClose>,
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    type Result = ();
+
+    fn handle(&mut self, SetDisplay(display): SetDisplay, _ctx: &mut Context<Self>) {
+        self.config.display = display;
     }
 }
 
-impl<T> Actor for Peer<T>
+impl<T> Handler<Reconfigure> for Peer<T>
 where
     T: Actor,
     T: Handler<PeerClose>,
-    T::Context: ToEnvelope<T, PeerClose>
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
 {
-    type Context = Context<Self>;
+    type Result = ();
 
-    fn stopped(&mut self, _: &mut Self::Context) {
-        // Socket as been closed, notify the parent
-        self.parent.do_send(PeerClose);
+    /// Without `--throttle` anymore, the queued data is written at once,
+    /// before the next messages skip the queues
+    fn handle(&mut self, msg: Reconfigure, ctx: &mut Context<Self>) {
+        self.config.reconfigure(&msg.0);
+        if self.config.throttle.is_none() && !self.throttled_empty() {
+            self.write_throttled(ctx);
+        }
     }
 }
 
-impl<T> Handler<UserInput> for Peer<T>
+impl<T> Handler<SendFile> for Peer<T>
 where
     T: Actor,
     T: Handler<PeerClose>,
-    T::Context: ToEnvelope<T, PeerClose>
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
 {
     type Result = ();
 
-    fn handle(&mut self, msg: UserInput, _: &mut Context<Self>) {
-        // The user as submitted data, write it on the socket
-        self.delays.push_back(Instant::now());
-        self.writer.write(&to_binary(msg.0.as_ref(), Kind::Data));
+    fn handle(&mut self, msg: SendFile, ctx: &mut Context<Self>) {
+        if let Err(e) = self.send_file(&msg.0, ctx) {
+            self.print(NO_COLOR, &format!("Can not send {}: {}", msg.0.display(), e));
+        }
     }
 }
 
@@ -93,8 +1379,105 @@ impl<T> WriteHandler<::std::io::Error> for Peer<T>
 where
     T: Actor,
     T: Handler<PeerClose>,
-    T::Context: ToEnvelope<T, PeerClose>
-{}
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
+{
+    /// Called when writing on the socket failed, the connection is
+    /// closed
+    fn error(&mut self, err: io::Error, _: &mut Self::Context) -> Running {
+        match self.write_retried.get() {
+            0 => self.print(EVENT_COLOR, &format!("Can not write to the connection: {}", err)),
+            n => self.print(EVENT_COLOR, &format!("Can not write to the connection after {} retries: {}", n, err))
+        }
+        self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: &err.to_string() });
+        Running::Stop
+    }
+
+    /// Called once the writer is closed and its data written
+    ///
+    /// After a [`HalfClose`], only the write half of the socket is shut
+    /// down and we keep reading.
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        if !self.half_closed || self.closing || self.remote_half_closed {
+            ctx.stop();
+            return;
+        }
+        if let Some(ref socket) = self.socket {
+            if let Err(e) = socket.shutdown(Shutdown::Write) {
+                self.print(NO_COLOR, &format!("Can not shut down the connection: {}", e));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/// Count the bytes written to `inner`
+///
+/// The actix [`Writer`] doesn't tell how much of its buffer is left,
+/// this is how we know it.
+///
+/// A write failing with a transient error is retried up to
+/// `max_retries` times, [`WRITE_RETRY_DELAY`] later: the [`Writer`] is
+/// told the socket isn't ready, and keeps the data in its buffer. So
+/// the message is written as it was.
+///
+/// The time the last byte of each message of `unwritten` is written is
+/// kept, its roundtrip time counts from then.
+struct Counted<W> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+    unwritten: Unwritten,
+    /// Number of times a failed write is retried, `--write-retries`
+    max_retries: u32,
+    /// Number of times the current failed write was retried
+    retried: Rc<Cell<u32>>
+}
+
+/// Return true for the write errors that may not happen again
+fn is_transient(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut)
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(n) => {
+                let count = self.count.get() + n as u64;
+                self.count.set(count);
+                self.retried.set(0);
+                let mut unwritten = self.unwritten.borrow_mut();
+                while unwritten.front().map_or(false, |&(end, _)| end <= count) {
+                    if let Some((_, written_at)) = unwritten.pop_front() {
+                        written_at.set(Some(Instant::now()));
+                    }
+                }
+                Ok(n)
+            }
+            Err(ref e) if is_transient(e) && self.retried.get() < self.max_retries => {
+                self.retried.set(self.retried.get() + 1);
+                // Wake up the writer once the delay is over
+                let writer = task::current();
+                Arbiter::spawn(Delay::new(Instant::now() + WRITE_RETRY_DELAY).then(move |_| {
+                    writer.notify();
+                    Ok(())
+                }));
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Counted<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
 
 /// Parsed message
 ///
@@ -104,55 +1487,795 @@ pub struct Msg {
     /// Bytes of the received datas, including header
     bytes: Bytes,
     /// [`Kind`] of the message
-    kind: Kind,
+    pub kind: Kind,
     /// Header len
-    header_len: usize
+    header_len: usize,
+    /// Sequence number
+    pub seq: u32,
+    /// Length flag of the header, `None` in raw mode
+    pub len_flag: Option<u8>,
+    /// When the message was sent, in microseconds since the Unix
+    /// epoch, with `--one-way-latency`
+    pub sent_at: Option<u64>,
+    /// When its last byte was read from the socket, set by the
+    /// [`Reader`]
+    pub received_at: Option<Instant>
 }
 
 impl Msg {
-    pub fn new(bytes: Bytes, kind: Kind, header_len: usize) -> Msg {
-        Msg { bytes, kind, header_len }
+    /// `kind` is never [`Kind::Wrong`]: the [`Reader`] fails on a wrong
+    /// kind flag before building a message.
+    pub fn new(bytes: Bytes, kind: Kind, header_len: usize, seq: u32) -> Msg {
+        debug_assert_ne!(kind, Kind::Wrong, "a message can't be of kind Wrong");
+        Msg { bytes, kind, header_len, seq, len_flag: None, sent_at: None, received_at: None }
     }
 
     /// Return the message without the header
     pub fn message(&self) -> Bytes {
         self.bytes.slice_from(self.header_len)
     }
+
+    /// Return the header of the message, empty in raw mode
+    pub fn header(&self) -> Bytes {
+        self.bytes.slice_to(self.header_len)
+    }
+}
+
+/// Format `duration` as hours, minutes and seconds, e.g. `01:02:03`
+pub fn format_uptime(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Return the beginning of `text`, at most `max` bytes
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// The first `max` bytes of `bytes` as utf8, with the invalid
+/// sequences replaced
+///
+/// A character cut at `max` is left out instead of replaced, like in
+/// [`truncate`].
+fn lossy_prefix(bytes: &[u8], max: usize) -> Cow<'_, str> {
+    let mut end = max.min(bytes.len());
+    if end < bytes.len() {
+        end -= incomplete_utf8(&bytes[..end]);
+    }
+    String::from_utf8_lossy(&bytes[..end])
+}
+
+/// Length of the utf8 character cut at the end of `bytes`, 0 when
+/// the last character is complete
+fn incomplete_utf8(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - len];
+        // Continuation byte
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let char_len = match byte {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1
+        };
+        return if char_len > len { len } else { 0 };
+    }
+    0
+}
+
+/// Return true if `seq` is `expected` or comes after it
+///
+/// The sequence numbers wrap: half of the u32 values after `expected`
+/// come after it, the other half before.
+fn seq_ahead(seq: u32, expected: u32) -> bool {
+    (seq.wrapping_sub(expected) as i32) >= 0
+}
+
+/// Add the bytes a `rate` per second allows during `elapsed` to
+/// `budget`, which holds at most a tenth of the rate
+///
+/// The budget isn't reset by each write: a message sent while nothing
+/// is queued only gets what the elapsed time allows, not a new tenth.
+fn refill(budget: f64, rate: usize, elapsed: Duration) -> f64 {
+    let burst = (rate / 10).max(1) as f64;
+    (budget + rate as f64 * elapsed.as_secs_f64()).min(burst)
+}
+
+/// Decode utf16 text
+///
+/// The endianness is given by the BOM, big endian without it.
+fn decode_utf16(bytes: &[u8]) -> Option<String> {
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+
+    let units: Vec<u16> = if bytes.starts_with(&[0xFF, 0xFE]) {
+        bytes[2..].chunks(2).map(LittleEndian::read_u16).collect()
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        bytes[2..].chunks(2).map(BigEndian::read_u16).collect()
+    } else {
+        bytes.chunks(2).map(BigEndian::read_u16).collect()
+    };
+
+    String::from_utf16(&units).ok()
+}
+
+/// Describe the one-way latency of a message sent at `sent_at` and
+/// received at `received_at`, in microseconds since the Unix epoch
+///
+/// A message received before it was sent means the clocks are not
+/// synchronized.
+fn one_way_latency(sent_at: u64, received_at: u64) -> String {
+    if received_at >= sent_at {
+        format!("One-way latency: {:?}", Duration::from_micros(received_at - sent_at))
+    } else {
+        format!("Received {:?} before it was sent, the clocks are not synchronized",
+                Duration::from_micros(sent_at - received_at))
+    }
+}
+
+/// Return the parts of `data` to send to a peer taking at most `max`
+/// bytes: several parts with [`Oversize::Split`], its beginning with
+/// [`Oversize::Truncate`] and none with [`Oversize::Reject`]
+fn fit_message(data: &[u8], max: usize, on_oversize: Oversize) -> Vec<&[u8]> {
+    match on_oversize {
+        _ if data.len() <= max => vec![data],
+        Oversize::Split => data.chunks(max.max(1)).collect(),
+        Oversize::Truncate => vec![&data[..max]],
+        Oversize::Reject => Vec::new()
+    }
+}
+
+/// Write `message` to the next free `msg-NNNNN.bin` file of `dir`,
+/// after the one numbered `last`
+///
+/// The directory is created if needed. Return the path of the file.
+fn dump(dir: &Path, last: &mut u32, message: &[u8]) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    loop {
+        *last += 1;
+        let path = dir.join(format!("msg-{:05}.bin", last));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(message)?;
+                return Ok(path);
+            }
+            // Written by another peer, or before a restart
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Format `bytes` in hexadecimal, separated by spaces
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+/// Format `bytes` as `hexdump -C`: the offset, 16 bytes in
+/// hexadecimal and the same bytes in ASCII, with `.` for the non
+/// printable ones
+fn hexdump(bytes: &[u8]) -> String {
+    let lines: Vec<String> = bytes.chunks(16).enumerate().map(|(i, line)| {
+        let mut hex = String::with_capacity(49);
+        for (j, byte) in line.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let ascii: String = line.iter().map(|&byte| match byte {
+            0x20..=0x7E => byte as char,
+            _ => '.'
+        }).collect();
+        format!("{:08x}  {:<49} |{}|", i * 16, hex, ascii)
+    }).collect();
+    lines.join("\n")
 }
 
 impl<T> StreamHandler<Msg, ReaderError> for Peer<T>
 where
     T: Actor,
     T: Handler<PeerClose>,
-    T::Context: ToEnvelope<T, PeerClose>
+    T: Handler<Relay>,
+    T::Context: ToEnvelope<T, PeerClose>,
+    T::Context: ToEnvelope<T, Relay>
 {
     /// This function is called once the message has been fully read
     /// and parsed to a [`Msg`].
-    fn handle(&mut self, msg: Msg, _ctx: &mut Self::Context) {
+    fn handle(&mut self, msg: Msg, ctx: &mut Self::Context) {
+        self.last_activity = Instant::now();
+        self.stats.messages_received += 1;
+        self.stats.bytes_received += msg.bytes.len() as u64;
+        Metrics::add(&METRICS.messages_received, 1);
+        Metrics::add(&METRICS.bytes_received, msg.bytes.len() as u64);
+
+        let ahead = seq_ahead(msg.seq, self.expected_seq);
+        if msg.seq != self.expected_seq {
+            if !ahead {
+                self.print(NO_COLOR, &format!("Duplicated or late message #{}, expected #{}",
+                                              msg.seq, self.expected_seq));
+            } else {
+                self.print(NO_COLOR, &format!("Out of order message #{}, expected #{}",
+                                              msg.seq, self.expected_seq));
+            }
+        }
+        if ahead {
+            self.expected_seq = msg.seq.wrapping_add(1);
+        }
+
+        if let (true, Some(flag)) = (self.config.show_framing, msg.len_flag) {
+            self.print(EVENT_COLOR, &format!(
+                "Received {} #{}: {} bytes frame using {:#04x} ({}) length",
+                msg.kind.name(), msg.seq, msg.bytes.len(), flag, length_flag_name(flag)
+            ));
+        }
+
+        // Not to a peer which half-closed, nor once we did and can't write
+        if self.config.ack_kinds.contains(&msg.kind) && !self.remote_half_closed && !self.half_closed {
+            if self.config.echo {
+                self.send(&msg.message(), Kind::Response, ctx);
+            } else {
+                self.send(b"message received", Kind::Response, ctx);
+            }
+        }
+
+        if let Some(ref kinds) = self.config.subscribe {
+            // Sent before the server received our subscription
+            if msg.kind.is_subscribable() && !kinds.contains(&msg.kind) {
+                return;
+            }
+        }
+
         match msg.kind {
             Kind::Data => {
-                let bin = to_binary(b"message received", Kind::Response);
-                self.writer.write(bin.as_ref());
-                let message = msg.message();
-                match self.config.display {
-                    Display::Binary => println!("Message: {:?}", message),
-                    Display::Utf8 => {
-                        match String::from_utf8(message.to_vec()) {
-                            Ok(utf8) => println!("Message[utf8]: {}", utf8),
-                            _ => println!("Message: {:?}", message)
+                let message = if self.config.display_stream {
+                    self.join_stream(msg.message())
+                } else {
+                    msg.message()
+                };
+                let display = match self.config.display {
+                    // Printed whatever the display with --tee
+                    Display::None if self.config.tee => &Display::Binary,
+                    ref display => display
+                };
+                let line = self.format_payload("Message", &message, display);
+                self.print(DATA_COLOR, &self.with_header(&msg, line));
+                if let Some(sent_at) = msg.sent_at {
+                    self.print(RESPONSE_COLOR, &one_way_latency(sent_at, now_micros()));
+                }
+                self.log_event(&Event::Message { id: self.id, addr: self.addr, len: msg.message().len() });
+                if let Some(ref dir) = self.config.dump_dir {
+                    if let Err(e) = dump(dir, &mut self.dumped, &msg.message()) {
+                        self.print(EVENT_COLOR, &format!("Can not write the message in {}: {}",
+                                                         dir.display(), e));
+                    }
+                }
+                self.notify_received(&msg);
+                if !self.config.echo {
+                    self.relay(&msg);
+                }
+            },
+            Kind::Response => {
+                if self.delays.front().map_or(true, |sent| sent.seq.is_none()) {
+                    // Nothing we sent is waiting for it, there is no
+                    // roundtrip to measure
+                    self.unsolicited_response(&msg, ctx);
+                    return;
+                }
+                let sent = self.delays.pop_front().unwrap();
+                if sent.forwarded {
+                    self.print(RESPONSE_COLOR, &format!("Response: {:?}", msg.message()));
+                    return;
+                }
+                let seq = sent.seq.unwrap_or_default();
+                let delay = sent.rtt(msg.received_at);
+                self.stats.responses += 1;
+                METRICS.roundtrip(delay);
+                if let Some(ref csv) = self.config.rtt_csv {
+                    csv.record(seq, delay, sent.len);
+                }
+                if let Some(ref hook) = self.config.hooks.roundtrip {
+                    let _ = hook.do_send(Roundtrip {
+                        id: self.id,
+                        seq,
+                        rtt: delay,
+                        len: sent.len,
+                        response: msg.message()
+                    });
+                }
+                let line = match self.config.display_response {
+                    Some(ref display) => {
+                        format!("{} in {:?}", self.format_payload("Response", &msg.message(), display), delay)
+                    },
+                    None => format!("Response: {:?} in {:?}", msg.message(), delay)
+                };
+                self.print(RESPONSE_COLOR, &line);
+                // A slot is free in the window
+                self.send_waiting(ctx);
+                if self.config.wait_response && self.unanswered() == 0 {
+                    // The message of --message got its response
+                    self.close("done", ctx);
+                }
+            },
+            Kind::Typing => {
+                // Relayed by the server with the nickname of who is typing
+                let line = match msg.message() {
+                    ref nick if nick.is_empty() => "Peer is typing...".to_owned(),
+                    nick => format!("{} is typing...", String::from_utf8_lossy(&nick))
+                };
+                self.show_typing(&line, ctx);
+                self.relay(&msg);
+            },
+            Kind::Hello if self.config.is_client => {
+                let nick = String::from_utf8_lossy(&msg.message()).into_owned();
+                match self.config.nick {
+                    Some(ref asked) if *asked != nick => {
+                        self.print(EVENT_COLOR, &format!("Nickname {} is taken, registered as {}",
+                                                         asked, nick));
+                    }
+                    _ => self.print(EVENT_COLOR, &format!("Registered as {}", nick))
+                }
+            },
+            Kind::Hello => {
+                if let Some(timer) = self.handshake_timer.take() {
+                    ctx.cancel_future(timer);
+                }
+                self.relay(&msg);
+            },
+            Kind::Banner => {
+                let banner = String::from_utf8_lossy(&msg.message()).into_owned();
+                self.print(EVENT_COLOR, &format!("Banner:\n{}", banner.trim_end()));
+            },
+            Kind::Subscribe if !self.config.is_client => {
+                let payload = msg.message();
+                if payload.len() == 2 {
+                    let mask = BigEndian::read_u16(&payload);
+                    let kinds: Vec<&str> = Kind::ALL.iter()
+                                                    .filter(|&&kind| mask & Kind::mask(&[kind]) != 0)
+                                                    .map(|kind| kind.name())
+                                                    .collect();
+                    let kinds = if kinds.is_empty() { "none".to_owned() } else { kinds.join(", ") };
+                    self.print(EVENT_COLOR, &format!("Peer subscribed to: {}", kinds));
+                    self.subscribed = Some(mask);
+                }
+            },
+            Kind::Subscribe => {},
+            Kind::MaxMessage => {
+                let payload = msg.message();
+                if payload.len() == 8 {
+                    let max = BigEndian::read_u64(&payload).min(usize::MAX as u64) as usize;
+                    if max < self.config.max_message {
+                        self.print(EVENT_COLOR, &format!("The peer takes messages of at most {} bytes", max));
+                    }
+                    self.peer_max_message = Some(max);
+                }
+            },
+            Kind::Nonce => {
+                let ours = self.config.session_nonce.map(|nonce| {
+                    let mut payload = [0; 8];
+                    BigEndian::write_u64(&mut payload, nonce);
+                    payload
+                });
+                if ours.as_ref().map(|nonce| &nonce[..]) == Some(&msg.message()[..]) {
+                    self.print(EVENT_COLOR, "Received our own session nonce, we are connected to ourselves. Closing");
+                    self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: "connected to itself" });
+                    self.protocol_error = true;
+                    self.close("connected to itself", ctx);
+                }
+            },
+            Kind::History => {
+                let line = self.format_payload("History", &msg.message(), &self.config.display);
+                self.print(DATA_COLOR, &self.with_header(&msg, line));
+                self.notify_received(&msg);
+            },
+            Kind::Ack => {
+                self.print(RESPONSE_COLOR, &format!("Delivered: {:?}", msg.message()));
+            },
+            Kind::FileMeta => {
+                match self.transfers.meta(&msg.message()) {
+                    Ok(started) => {
+                        self.print(NO_COLOR, &format!("Receiving transfer #{}: {} ({} bytes) in {}",
+                                                      started.id, started.name, started.total,
+                                                      started.path.display()));
+                    },
+                    Err(e) => self.print(NO_COLOR, &format!("Transfer failed: {}", e))
+                }
+            },
+            Kind::Chunk => {
+                match self.transfers.chunk(&msg.message()) {
+                    Ok(None) => (),
+                    Ok(Some(progress)) => {
+                        if let Some(path) = progress.started {
+                            self.print(NO_COLOR, &format!("Receiving transfer #{} in {}",
+                                                          progress.id, path.display()));
                         }
+                        self.print(NO_COLOR, &format!("Transfer #{}: {}%",
+                                                      progress.id, progress.percent));
                     },
-                    _ => println!("{} bytes received", message.len())
+                    Err(e) => self.print(NO_COLOR, &format!("Transfer failed: {}", e))
                 }
             },
-            Kind::Response => {
-                let delay = self.delays.pop_front()
-                                       .map(|s| s.elapsed())
-                                       .unwrap_or_default();
-                println!("Response: {:?} in {:?}", msg.message(), delay);
+            Kind::ChunkEnd => {
+                match self.transfers.end(&msg.message()) {
+                    Ok(None) => (),
+                    Ok(Some(Finished { id, path, hash })) => {
+                        self.print(NO_COLOR, &format!("Transfer #{} complete, SHA-256 {} verified: {}",
+                                                      id, transfer::hex(&hash), path.display()))
+                    },
+                    Err(e) => {
+                        self.print(NO_COLOR, &format!("Transfer failed: {}", e));
+                        if let TransferError::HashMismatch = e {
+                            self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: "file hash mismatch" });
+                        }
+                    }
+                }
+            },
+            Kind::Close => {
+                let reason = String::from_utf8_lossy(&msg.message()).into_owned();
+                self.print(EVENT_COLOR, &format!("Peer closed: {}", reason));
+                self.close_reason = Some(reason);
+                ctx.stop();
+            },
+            Kind::HalfClose => {
+                self.print(EVENT_COLOR, "Peer finished sending");
+                self.remote_half_closed = true;
+                // Its responses were written before, nothing we sent
+                // will get one now
+                self.delays.clear();
             },
             Kind::Wrong => {
+                // Not reachable as long as the Reader rejects wrong kinds,
+                // see Msg::new
+                self.print(EVENT_COLOR, &format!("Invalid message, closing: {:?}", msg.bytes));
+                self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: "invalid message kind" });
+                self.protocol_error = true;
+                self.close("invalid message", ctx);
+            }
+        }
+    }
+
+    /// Called when the data received can't be parsed, or the socket
+    /// can't be read
+    ///
+    /// We stop reading, the other side is told why before the
+    /// connection is closed.
+    fn error(&mut self, err: ReaderError, ctx: &mut Self::Context) -> Running {
+        let reason = err.to_string();
+        self.print(EVENT_COLOR, &format!("Closing the connection: {}", reason));
+        self.log_event(&Event::Error { id: self.id, addr: self.addr, reason: &reason });
+        match err {
+            ReaderError::IO(_) => ctx.stop(),
+            _ => {
+                self.protocol_error = true;
+                self.close(&reason, ctx);
+            }
+        }
+        Running::Stop
+    }
+
+    /// Called when the other side closed the connection
+    ///
+    /// After a [`Kind::HalfClose`] from the other side, we keep the
+    /// connection open to write to it.
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        if self.closing {
+            // The writer stops us once our Close message is written
+            return;
+        }
+        if !self.remote_half_closed || self.half_closed {
+            ctx.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use actix::prelude::*;
+    use futures::{future, Future};
+    use std::cell::Cell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tokio_reactor::Handle;
+    use tokio_tcp::TcpStream;
+    use super::{decode_utf16, dump, fit_message, format_uptime, hex_bytes, hexdump, lossy_prefix, one_way_latency, refill, seq_ahead, Msg, Counted, incomplete_utf8, truncate, Peer, PeerClose, Relay, Disconnect, Stats, Unwritten, WrittenAt};
+    use user::UserInput;
+    use Oversize;
+
+    #[test]
+    fn sequence_wraps() {
+        assert!(seq_ahead(5, 5));
+        assert!(seq_ahead(6, 5));
+        assert!(!seq_ahead(4, 5));
+        // Across the wrap
+        assert!(seq_ahead(0, 0xFFFF_FFFF));
+        assert!(seq_ahead(3, 0xFFFF_FFFE));
+        assert!(!seq_ahead(0xFFFF_FFFF, 0));
+        assert!(!seq_ahead(0xFFFF_FFF0, 2));
+    }
+
+    #[test]
+    fn throttle_refill() {
+        let ms = Duration::from_millis;
+        // 1000 bytes per second, at most 100 at once
+        assert_eq!(refill(f64::INFINITY, 1000, ms(0)), 100.0);
+        assert_eq!(refill(0.0, 1000, ms(5)), 5.0);
+        assert_eq!(refill(5.0, 1000, ms(5)), 10.0);
+        assert_eq!(refill(90.0, 1000, ms(500)), 100.0);
+        // Below 10 bytes per second, a byte at a time
+        assert_eq!(refill(0.0, 5, ms(1000)), 1.0);
+    }
+
+    #[test]
+    fn truncate_on_char_boundary() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello", 2), "he");
+        // 'é' is 2 bytes long
+        assert_eq!(truncate("héllo", 2), "h");
+    }
+
+    #[test]
+    fn lossy_prefix_on_char_boundary() {
+        assert_eq!(lossy_prefix("héllo".as_bytes(), 2), "h");
+        assert_eq!(lossy_prefix("héllo".as_bytes(), 3), "hé");
+        assert_eq!(lossy_prefix(b"h\xffllo", 3), "h\u{FFFD}l");
+        // Not cut, the missing byte is invalid
+        assert_eq!(lossy_prefix(b"h\xc3", 2), "h\u{FFFD}");
+    }
+
+    #[test]
+    fn uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(0)), "00:00:00");
+        assert_eq!(format_uptime(Duration::from_millis(59_999)), "00:00:59");
+        assert_eq!(format_uptime(Duration::from_secs(12 * 60 + 34)), "00:12:34");
+        assert_eq!(format_uptime(Duration::from_secs(100 * 3600 + 61)), "100:01:01");
+    }
+
+    #[test]
+    fn cut_utf8_character() {
+        assert_eq!(incomplete_utf8(b""), 0);
+        assert_eq!(incomplete_utf8("hé".as_bytes()), 0);
+        assert_eq!(incomplete_utf8(&"hé".as_bytes()[..2]), 1);
+        // '€' is 3 bytes long
+        assert_eq!(incomplete_utf8(&"h€".as_bytes()[..3]), 2);
+        assert_eq!(incomplete_utf8("h€".as_bytes()), 0);
+        assert_eq!(incomplete_utf8(&[0xFF]), 0);
+    }
+
+    #[test]
+    fn utf16_bom() {
+        assert_eq!(decode_utf16(&[0xFF, 0xFE, b'h', 0, b'i', 0]).unwrap(), "hi");
+        assert_eq!(decode_utf16(&[0xFE, 0xFF, 0, b'h', 0, b'i']).unwrap(), "hi");
+    }
+
+    #[test]
+    fn utf16_big_endian_without_bom() {
+        assert_eq!(decode_utf16(&[0, b'h', 0, b'i']).unwrap(), "hi");
+    }
+
+    #[test]
+    fn utf16_invalid() {
+        // Odd length
+        assert!(decode_utf16(&[0, b'h', 0]).is_none());
+        // Unpaired surrogate
+        assert!(decode_utf16(&[0xD8, 0x00]).is_none());
+    }
+
+    #[test]
+    fn message_header() {
+        let frame = ::reader::to_binary(b"hello", ::reader::Kind::Data, 5, &::reader::Framing::default()).unwrap();
+        let (msg, _) = ::reader::parse_frame(&frame, &::reader::Framing::default()).unwrap().unwrap();
+
+        assert_eq!(hex_bytes(&msg.header()), "10 05 00 00 00 05");
+        assert_eq!(msg.message().as_ref(), b"hello");
+
+        let raw = Msg::new(::bytes::Bytes::from(&b"hello"[..]), ::reader::Kind::Data, 0, 0);
+        assert!(raw.header().is_empty());
+    }
+
+    #[test]
+    fn fit_to_the_peer() {
+        let data = b"abcdefgh";
+        assert_eq!(fit_message(data, 8, Oversize::Reject), vec![&data[..]]);
+        assert_eq!(fit_message(data, 3, Oversize::Split), vec![&b"abc"[..], b"def", b"gh"]);
+        assert_eq!(fit_message(data, 3, Oversize::Truncate), vec![&b"abc"[..]]);
+        assert!(fit_message(data, 3, Oversize::Reject).is_empty());
+    }
+
+    #[test]
+    fn one_way_latency_text() {
+        assert_eq!(one_way_latency(1_000_000, 1_002_500), "One-way latency: 2.5ms");
+        assert_eq!(one_way_latency(1_002_500, 1_000_000),
+                   "Received 2.5ms before it was sent, the clocks are not synchronized");
+    }
+
+    #[test]
+    fn dump_numbering() {
+        let dir = ::std::env::temp_dir().join(format!("chat-test-{}-dump", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&dir);
+        let mut last = 0;
+
+        assert_eq!(dump(&dir, &mut last, b"one").unwrap(), dir.join("msg-00001.bin"));
+        // Another peer starts from the same number and skips the first file
+        let mut other = 0;
+        assert_eq!(dump(&dir, &mut other, b"two").unwrap(), dir.join("msg-00002.bin"));
+        assert_eq!(dump(&dir, &mut last, b"three").unwrap(), dir.join("msg-00003.bin"));
+        assert_eq!(::std::fs::read(dir.join("msg-00002.bin")).unwrap(), b"two");
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hexdump_layout() {
+        assert_eq!(hexdump(b"hello world\n"),
+                   "00000000  68 65 6c 6c 6f 20 77 6f  72 6c 64 0a              |hello world.|");
+
+        let bytes: Vec<u8> = (0..20).collect();
+        let dump = hexdump(&bytes);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|");
+        assert_eq!(lines[1], "00000010  10 11 12 13                                       |....|");
+        assert_eq!(hexdump(b""), "");
+    }
+
+    /// Writer failing `failures` times before writing
+    struct Flaky {
+        failures: u32,
+        written: Vec<u8>
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::ErrorKind::TimedOut.into());
             }
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
+
+    #[test]
+    fn write_retries() {
+        System::run(|| {
+            Arbiter::spawn(future::lazy(|| {
+                let retried = Rc::new(Cell::new(0));
+                let mut write = Counted {
+                    inner: Flaky { failures: 3, written: Vec::new() },
+                    count: Rc::new(Cell::new(0)),
+                    unwritten: Unwritten::default(),
+                    max_retries: 2,
+                    retried: retried.clone()
+                };
+
+                // Retried twice, the writer is told to wait
+                for n in 1..=2 {
+                    let error = write.write(b"hello").unwrap_err();
+                    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+                    assert_eq!(retried.get(), n);
+                }
+                assert_eq!(write.write(b"hello").unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+                assert_eq!(write.write(b"hello").unwrap(), 5);
+                assert_eq!(retried.get(), 0);
+                assert_eq!(write.inner.written, b"hello");
+
+                System::current().stop();
+                Ok(())
+            }));
+        });
+    }
+
+    #[test]
+    fn written_at() {
+        let unwritten = Unwritten::default();
+        let mut write = Counted {
+            inner: Vec::new(),
+            count: Rc::new(Cell::new(0)),
+            unwritten: unwritten.clone(),
+            max_retries: 0,
+            retried: Rc::new(Cell::new(0))
+        };
+        let first = WrittenAt::default();
+        let second = WrittenAt::default();
+        unwritten.borrow_mut().push_back((5, first.clone()));
+        unwritten.borrow_mut().push_back((10, second.clone()));
+
+        write.write_all(b"hel").unwrap();
+        assert!(first.get().is_none());
+        write.write_all(b"lowor").unwrap();
+        assert!(first.get().is_some() && second.get().is_none());
+        write.write_all(b"ld").unwrap();
+        assert!(second.get().is_some());
+        assert!(unwritten.borrow().is_empty());
+    }
+
+    /// Parent of the peer, keeps its stats and stops once it closed
+    struct Parent {
+        stats: Arc<Mutex<Option<Stats>>>
+    }
+
+    impl Actor for Parent {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PeerClose> for Parent {
+        type Result = ();
+
+        fn handle(&mut self, close: PeerClose, _: &mut Context<Self>) {
+            *self.stats.lock().unwrap() = Some(close.stats);
+            System::current().stop();
+        }
+    }
+
+    impl Handler<Relay> for Parent {
+        type Result = ();
+
+        fn handle(&mut self, _: Relay, _: &mut Context<Self>) {}
+    }
+
+    #[test]
+    fn slow_reader_loses_nothing() {
+        // Far more than the socket buffers can hold
+        const MESSAGES: usize = 256;
+        const LEN: usize = 64 * 1024;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The other side reads slowly, until the connection is closed
+        let reader = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buffer = [0; 16 * 1024];
+            let mut total = 0;
+            loop {
+                match socket.read(&mut buffer).unwrap() {
+                    0 => return total,
+                    n => total += n as u64
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let stats = Arc::new(Mutex::new(None));
+        let parent_stats = stats.clone();
+
+        System::run(move || {
+            let config = ::config_from(&::app().get_matches_from(vec!["chat"]));
+            let socket = StdTcpStream::connect(addr).unwrap();
+            let socket = TcpStream::from_std(socket, &Handle::default()).unwrap();
+            let parent = Parent { stats: parent_stats }.start();
+            let peer = Peer::new(config, parent, socket, 0);
+
+            // Send as fast as possible from another thread, like the User
+            thread::spawn(move || {
+                for i in 0..MESSAGES {
+                    peer.send(UserInput::new(vec![i as u8; LEN])).wait().unwrap().unwrap();
+                }
+                peer.do_send(Disconnect("done".to_owned()));
+            });
+        });
+
+        let received = reader.join().unwrap();
+        let stats = stats.lock().unwrap().unwrap();
+
+        // All the messages and the close message
+        assert_eq!(stats.messages_sent, MESSAGES as u64 + 1);
+        assert_eq!(received, stats.bytes_sent);
+        assert!(received > (MESSAGES * LEN) as u64);
+    }
 }
@@ -1,34 +1,206 @@
 //! Parse received data and format writable data
 
 use tokio_io::io::ReadHalf;
-use futures::{Async, Poll};
+use futures::{task, Async, Poll};
 use tokio_tcp::TcpStream;
 use tokio_io::AsyncRead;
 use futures::stream::Stream;
 use bytes::{BufMut, BytesMut, Bytes};
-use byteorder::{ByteOrder, NetworkEndian};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NetworkEndian};
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use crypto::{HmacKey, Psk, TAG_LEN};
 use peer::Msg;
 use MESSAGE_MAX_LEN;
 
+/// A transport read by a [`Reader`]
+///
+/// A read returning 0 bytes is the end of a TCP stream, but a transport
+/// can return 0 without being closed. The [`Reader`] asks it which one
+/// it is.
+pub trait Transport: AsyncRead {
+    /// Return true if the stream is closed, after a read returned 0
+    /// bytes. Otherwise the [`Reader`] reads again later
+    fn closed(&self) -> bool;
+}
+
+impl Transport for ReadHalf<TcpStream> {
+    /// TCP returns 0 bytes only once the other side closed
+    fn closed(&self) -> bool {
+        true
+    }
+}
+
 /// Kind of a message
+///
+/// The kind is stored in the low nibble of the first header byte, so
+/// up to 16 kinds can be defined. Values without a variant are
+/// parsed as [`Kind::Wrong`].
+///
+/// All of them are taken since [`Kind::Subscribe`]: another kind needs
+/// a change of the header, e.g. a kind whose payload starts with an
+/// extended kind, understood by both sides.
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum Kind {
     /// The data is a normal message
-    Data,
-    /// The data is a response to a message
-    Response,
-    /// Invalid data
-    Wrong
+    Data = 0,
+    /// The data is a response to a message, used to measure the
+    /// roundtrip time
+    Response = 1,
+    /// The peer is closing the connection, the data is the reason
+    Close = 2,
+    /// A chunk of a file transfer, see [`transfer`](::transfer)
+    Chunk = 3,
+    /// The end of a file transfer
+    ChunkEnd = 4,
+    /// The peer shut down its write half, it won't send anything else
+    /// but still reads
+    HalfClose = 5,
+    /// The data is an application level receipt, confirming that a
+    /// message was delivered. Unlike [`Kind::Response`], it is not
+    /// used to measure the roundtrip time
+    Ack = 6,
+    /// The peer is typing a message, without payload
+    Typing = 7,
+    /// A message sent before we connected, replayed by the server
+    History = 8,
+    /// The name and size of a file transfer, sent before its chunks
+    FileMeta = 9,
+    /// The nickname of a client, sent once connected. The server
+    /// answers with the name it registered
+    Hello = 10,
+    /// A random number identifying the session, sent once connected
+    /// with `--detect-loops`. Receiving our own means we are connected
+    /// to ourselves
+    Nonce = 11,
+    /// The `--banner` of the server, sent to the clients once
+    /// connected
+    Banner = 12,
+    /// The maximum length of the messages the peer takes, as u64,
+    /// sent once connected with `--advertise-max-message`
+    MaxMessage = 13,
+    /// The kinds a client wants the server to forward, as a u16 mask
+    /// of their values, sent once connected with `--subscribe`
+    Subscribe = 14,
+    /// Invalid data. Its value is never sent
+    Wrong = 0x0F
 }
 
-impl Into<u8> for Kind {
-    fn into(self) -> u8 {
+impl Kind {
+    /// All the kinds that can be sent, in the order of their value
+    pub const ALL: [Kind; 15] = [
+        Kind::Data, Kind::Response, Kind::Close, Kind::Chunk, Kind::ChunkEnd,
+        Kind::HalfClose, Kind::Ack, Kind::Typing, Kind::History, Kind::FileMeta,
+        Kind::Hello, Kind::Nonce, Kind::Banner, Kind::MaxMessage, Kind::Subscribe
+    ];
+
+    /// Name of the kind, as printed and given on the command line
+    pub fn name(self) -> &'static str {
         match self {
-            Kind::Data => 0,
-            Kind::Response => 1,
-            Kind::Wrong => 2,
+            Kind::Data => "data",
+            Kind::Response => "response",
+            Kind::Close => "close",
+            Kind::Chunk => "chunk",
+            Kind::ChunkEnd => "chunk-end",
+            Kind::HalfClose => "half-close",
+            Kind::Ack => "ack",
+            Kind::Typing => "typing",
+            Kind::History => "history",
+            Kind::FileMeta => "file-meta",
+            Kind::Hello => "hello",
+            Kind::Nonce => "nonce",
+            Kind::Banner => "banner",
+            Kind::MaxMessage => "max-message",
+            Kind::Subscribe => "subscribe",
+            Kind::Wrong => "wrong"
+        }
+    }
+
+    /// One line description of the kind, for `--list-kinds`
+    pub fn description(self) -> &'static str {
+        match self {
+            Kind::Data => "A message of the user",
+            Kind::Response => "Answer to a message, gives its roundtrip time",
+            Kind::Close => "The peer is closing the connection, with the reason",
+            Kind::Chunk => "Part of a file transfer",
+            Kind::ChunkEnd => "End of a file transfer, with the number of chunks and the SHA-256",
+            Kind::HalfClose => "The peer won't send anything else, but still reads",
+            Kind::Ack => "Receipt of a delivered message",
+            Kind::Typing => "The peer is typing, relayed with its nickname",
+            Kind::History => "A message sent before we connected, replayed by the server",
+            Kind::FileMeta => "Name and size of a file transfer",
+            Kind::Hello => "Nickname of a client, answered with the registered one",
+            Kind::Nonce => "Random number of the session, to detect a connection to ourselves",
+            Kind::Banner => "Greeting of the server, sent once connected",
+            Kind::MaxMessage => "Maximum length of the messages the peer takes",
+            Kind::Subscribe => "Kinds a client wants the server to forward",
+            Kind::Wrong => "Invalid kind, never sent"
+        }
+    }
+
+    /// Return true for the control messages, written before the data
+    /// with `--throttle`
+    ///
+    /// [`Kind::HalfClose`] isn't one, it must follow the data sent
+    /// before it.
+    pub fn is_control(self) -> bool {
+        matches!(self, Kind::Response | Kind::Close | Kind::Ack | Kind::Typing)
+    }
+
+    /// Parse a comma separated list of the kinds that can be answered
+    /// with a [`Kind::Response`], for `--ack-kinds`
+    ///
+    /// `none` is the empty list. The responses, receipts and closing
+    /// messages can't be answered.
+    pub fn parse_ackable(s: &str) -> Result<Vec<Kind>, String> {
+        Kind::parse_list(s, |kind| {
+            !matches!(kind, Kind::Response | Kind::Close | Kind::HalfClose | Kind::Ack)
+        })
+    }
+
+    /// Parse a comma separated list of the kinds a client can
+    /// subscribe to, for `--subscribe`
+    ///
+    /// These are the kinds the server forwards to its clients.
+    pub fn parse_subscribable(s: &str) -> Result<Vec<Kind>, String> {
+        Kind::parse_list(s, Kind::is_subscribable)
+    }
+
+    /// Return true for the kinds the server forwards to its clients,
+    /// see [`Kind::parse_subscribable`]
+    pub fn is_subscribable(self) -> bool {
+        matches!(self, Kind::Data | Kind::Typing | Kind::History | Kind::Banner)
+    }
+
+    /// Parse a comma separated list of the kinds accepted by `allowed`,
+    /// `none` is the empty list
+    fn parse_list<F: Fn(Kind) -> bool>(s: &str, allowed: F) -> Result<Vec<Kind>, String> {
+        if s.trim() == "none" {
+            return Ok(Vec::new());
         }
+        let kinds = Kind::ALL.iter().cloned().filter(|&kind| allowed(kind));
+        s.split(',').map(|name| {
+            kinds.clone().find(|kind| kind.name() == name.trim()).ok_or_else(|| {
+                let names: Vec<&str> = kinds.clone().map(|kind| kind.name()).collect();
+                format!("Unknown kind {}, should be {} or none", name, names.join(", "))
+            })
+        }).collect()
+    }
+
+    /// Return the mask of `kinds`, with the bit of the value of each
+    /// kind set
+    pub fn mask(kinds: &[Kind]) -> u16 {
+        kinds.iter().fold(0, |mask, &kind| mask | 1 << u8::from(kind))
+    }
+}
+
+impl From<Kind> for u8 {
+    fn from(kind: Kind) -> u8 {
+        kind as u8
     }
 }
 
@@ -37,11 +209,119 @@ impl From<u8> for Kind {
         match byte {
             0 => Kind::Data,
             1 => Kind::Response,
+            2 => Kind::Close,
+            3 => Kind::Chunk,
+            4 => Kind::ChunkEnd,
+            5 => Kind::HalfClose,
+            6 => Kind::Ack,
+            7 => Kind::Typing,
+            8 => Kind::History,
+            9 => Kind::FileMeta,
+            10 => Kind::Hello,
+            11 => Kind::Nonce,
+            12 => Kind::Banner,
+            13 => Kind::MaxMessage,
+            14 => Kind::Subscribe,
             _ => Kind::Wrong,
         }
     }
 }
 
+/// How the length of the sent messages is encoded in their header
+///
+/// Both encodings are always accepted when reading.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum LengthEncoding {
+    /// Length on 1, 2, 4 or 8 bytes, selected by the length flag
+    Fixed,
+    /// LEB128 varint length, with a length flag of 0
+    Varint
+}
+
+/// Byte order of the length in the header, with
+/// [`LengthEncoding::Fixed`]
+///
+/// It's not announced on the wire, both sides must use the same.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Endian {
+    /// Network order, the default
+    Big,
+    /// For peers written for little endian machines
+    Little
+}
+
+impl From<&str> for Endian {
+    fn from(s: &str) -> Endian {
+        match s {
+            "little" => Endian::Little,
+            _ => Endian::Big
+        }
+    }
+}
+
+impl Endian {
+    /// Read an unsigned integer of `nbytes` bytes
+    fn read_uint(self, bytes: &[u8], nbytes: usize) -> u64 {
+        match self {
+            Endian::Big => BigEndian::read_uint(bytes, nbytes),
+            Endian::Little => LittleEndian::read_uint(bytes, nbytes)
+        }
+    }
+
+    /// Write `value` on `nbytes` bytes
+    fn put_uint(self, buf: &mut BytesMut, value: u64, nbytes: usize) {
+        match self {
+            Endian::Big => buf.put_uint_be(value, nbytes),
+            Endian::Little => buf.put_uint_le(value, nbytes)
+        }
+    }
+}
+
+/// Options of the messages framing, shared by [`Reader`] and [`to_binary`]
+#[derive(Debug, Clone)]
+pub struct Framing {
+    /// Encoding of the length of sent messages
+    pub length: LengthEncoding,
+    /// Byte order of the length, sent and received
+    pub endian: Endian,
+    /// Key encrypting the payloads, see [`crypto`](::crypto)
+    pub psk: Option<Psk>,
+    /// Key signing the sent messages and verifying the received ones
+    pub hmac: Option<HmacKey>,
+    /// No framing at all: the payloads are written as they are, and
+    /// everything received is a [`Kind::Data`] payload
+    pub raw: bool,
+    /// The messages are delimited by a `\n` instead of a header, with
+    /// `--framing newline`. Like in raw mode, everything received is a
+    /// [`Kind::Data`] payload
+    pub newline: bool,
+    /// The header holds the time the message was sent, to measure the
+    /// one-way latency
+    pub timestamps: bool
+}
+
+impl Default for Framing {
+    fn default() -> Framing {
+        Framing {
+            length: LengthEncoding::Fixed,
+            endian: Endian::Big,
+            psk: None,
+            hmac: None,
+            raw: false,
+            newline: false,
+            timestamps: false
+        }
+    }
+}
+
+impl Framing {
+    /// Return false if the messages are sent without header, so only
+    /// [`Kind::Data`] payloads can be sent
+    pub fn has_header(&self) -> bool {
+        !self.raw && !self.newline
+    }
+}
+
 /// Errors when parsing data
 #[derive(Debug)]
 pub enum ReaderError {
@@ -51,205 +331,1027 @@ pub enum ReaderError {
     WrongLengthFlag,
     /// The length of the received data doesn't match header infomation
     IncorrectSize,
+    /// The HMAC tag is wrong, or missing with `--hmac-key`
+    BadMac,
     /// std input/output error
     IO(::std::io::Error),
 }
 
+impl fmt::Display for ReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReaderError::WrongKindFlag => {
+                write!(f, "received a message of unknown kind, the other side may run another version")
+            }
+            ReaderError::WrongLengthFlag => {
+                write!(f, "received a message with an invalid length in its header")
+            }
+            ReaderError::IncorrectSize => {
+                write!(f, "received a message with an invalid length, or larger than the maximum of {} bytes",
+                       MESSAGE_MAX_LEN)
+            }
+            ReaderError::BadMac => {
+                write!(f, "received a message with a wrong or missing signature, check --hmac-key")
+            }
+            ReaderError::IO(ref e) => write!(f, "can not read from the connection: {}", e)
+        }
+    }
+}
+
+impl Error for ReaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ReaderError::IO(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Error when framing a message to send
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    /// The payload is larger than [`MESSAGE_MAX_LEN`], the other side
+    /// would reject it
+    ///
+    /// [`MESSAGE_MAX_LEN`]: ../constant.MESSAGE_MAX_LEN.html
+    TooLarge(usize),
+    /// The payload contains a `\n`, it would end the message early with
+    /// `--framing newline`
+    Newline
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::TooLarge(len) => {
+                write!(f, "the message is {} bytes long, the maximum is {} bytes", len, MESSAGE_MAX_LEN)
+            }
+            FrameError::Newline => {
+                write!(f, "the message contains a newline, it can't be sent with --framing newline")
+            }
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+/// Check that a payload of `len` bytes can be sent
+fn check_len(len: usize) -> Result<(), FrameError> {
+    if len as u64 > MESSAGE_MAX_LEN {
+        Err(FrameError::TooLarge(len))
+    } else {
+        Ok(())
+    }
+}
+
 /// The Reader is responsible of parsing the received data
 /// and return a [`Msg`]
-pub struct Reader {
+///
+/// The same `pending` buffer is reused for every message: a parsed
+/// message is copied out of it and the buffer is cleared, keeping its
+/// allocation. A message of more than 31 bytes, its header included,
+/// is allocated by the copy, smaller ones are stored inline in their
+/// [`Bytes`]. Reading 10k messages of 5 bytes, one per read, makes no
+/// allocation once the reader is created, where a fresh buffer was
+/// previously allocated after each message (see the
+/// `allocations_small_messages` test).
+///
+/// When a read contains several messages, they are all parsed in place
+/// from `start`. The unparsed rest is moved to the front of `pending`
+/// only before reading more.
+///
+/// It reads the socket by default, any [`Transport`] works.
+pub struct Reader<R = ReadHalf<TcpStream>> {
     /// An handle to a readable socket
-    read: ReadHalf<TcpStream>,
+    read: R,
     /// Buffer where we read the incoming data
     pending: BytesMut,
+    /// Start of the data not parsed yet in `pending`
+    start: usize,
+    /// Capacity of `pending` we keep between messages
+    capacity: usize,
+    /// Framing options
+    framing: Framing,
+    /// Sequence number of the next message, without header
+    raw_seq: u32,
+    /// Longest line taken with `--framing newline`
+    max_line: usize,
+    /// Bytes after `start` known to have no `\n`, with `--framing
+    /// newline`
+    scanned: usize,
+    /// State of `pending` after the last poll, see [`Reader::probe`]
+    probe: Rc<Cell<Pending>>,
+    /// When data was last read, the messages parsed from it are
+    /// received then
+    last_read: Option<Instant>
+}
+
+/// State of the pending buffer of a [`Reader`], for diagnostics
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pending {
+    /// Number of bytes received and not parsed yet
+    pub len: usize,
+    /// Capacity of the buffer
+    pub capacity: usize,
+    /// Header of the frame at the start of the buffer, when it's
+    /// complete
+    pub header: Option<PayloadInfo>
+}
+
+impl fmt::Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes pending (capacity {})", self.len, self.capacity)?;
+        match self.header {
+            Some(ref info) => write!(f, ", waiting for {} #{}: {} of {} bytes received",
+                                     info.kind.name(), info.seq,
+                                     self.len.min(info.frame_len()), info.frame_len()),
+            None if self.len > 0 => write!(f, ", header incomplete"),
+            None => Ok(())
+        }
+    }
 }
 
 /// Information of the message from its header
-#[derive(Clone, Copy)]
-struct PayloadInfo {
+#[derive(Clone, Copy, Debug)]
+pub struct PayloadInfo {
     kind: Kind,
-    received_len: usize,
     bytes_capacity: usize,
     payload_len: usize,
-    header_len: usize
+    header_len: usize,
+    /// Length flag of the header, the encoding of the length
+    len_flag: u8,
+    seq: u32,
+    /// When the message was sent, in microseconds since the Unix
+    /// epoch, with `--one-way-latency`
+    sent_at: Option<u64>,
+    /// Length of the HMAC tag following the payload, 0 without tag
+    tag_len: usize
 }
 
-impl Reader {
-    pub fn new(read: ReadHalf<TcpStream>) -> Reader {
-        Reader { read, pending: BytesMut::new() }
+impl PayloadInfo {
+    /// Length of the whole frame, the tag included
+    fn frame_len(&self) -> usize {
+        self.header_len + self.payload_len + self.tag_len
     }
+}
 
-    /// Parse the header. It can takes differents size
-    ///
-    /// The header consists of:
-    ///
-    /// ## First byte:
-    ///
-    /// - HEADER\[0\] & 0x0F = [`Kind`] flag.
-    /// - HEADER\[0\] & 0xF0 = Length flag (0x10, 0x20, 0x40 or 0x80).
-    ///
-    /// ## Following bytes:
+impl<R: Transport> Reader<R> {
+    pub fn new(read: R, capacity: usize, framing: Framing) -> Reader<R> {
+        Reader {
+            read,
+            pending: BytesMut::with_capacity(capacity),
+            start: 0,
+            capacity,
+            framing,
+            raw_seq: 0,
+            max_line: MESSAGE_MAX_LEN.min(usize::MAX as u64) as usize,
+            scanned: 0,
+            probe: Rc::new(Cell::new(Pending::default())),
+            last_read: None
+        }
+    }
+
+    /// Take lines of at most `max` bytes with `--framing newline`,
+    /// [`MESSAGE_MAX_LEN`] by default
     ///
-    /// The following bytes correspond to the message length, depending of the length flag:
-    /// - flag = 0x10 =>  HEADER\[1\] as u8
-    /// - flag = 0x20 =>  HEADER[1, 2] as u16
-    /// - flag = 0x40 =>  HEADER[1, 2, 3, 4] as u32
-    /// - flag = 0x80 =>  HEADER[1, 2, 3, 4, 5, 6, 7, 8] as u64
+    /// A longer line is an [`ReaderError::IncorrectSize`], without
+    /// waiting for its end.
+    pub fn max_line(mut self, max: usize) -> Reader<R> {
+        self.max_line = max;
+        self
+    }
+
+    /// Return a handle on the state of the pending buffer, updated
+    /// each time the reader is polled
+    pub fn probe(&self) -> Rc<Cell<Pending>> {
+        self.probe.clone()
+    }
+
+    /// Update the [`Reader::probe`]
+    fn update_probe(&self) {
+        let bytes = &self.pending[self.start..];
+        let header = if !self.framing.has_header() {
+            None
+        } else {
+            parse_header(bytes, bytes.len(), &self.framing).ok().and_then(|info| info)
+        };
+        self.probe.set(Pending { len: bytes.len(), capacity: self.pending.capacity(), header });
+    }
+
+    /// Empty the pending buffer once its content has been consumed
     ///
-    fn parse_header(&self) -> Result<Option<PayloadInfo>, ReaderError> {
-        let bytes = self.pending.as_ref();
-        let received_len = bytes.len();
-        let bytes_capacity = self.pending.capacity();
+    /// The allocation is kept, unless a large message made it grow
+    /// past 4 times the configured capacity.
+    fn reset_pending(&mut self) {
+        self.start = 0;
+        self.scanned = 0;
+        if self.pending.capacity() > self.capacity.saturating_mul(4) {
+            self.pending = BytesMut::with_capacity(self.capacity);
+        } else {
+            self.pending.clear();
+        }
+    }
 
-        if received_len < 1 {
-            return Ok(None);
+    /// Move the data not parsed yet to the front of `pending`, to read
+    /// after it
+    fn compact(&mut self) {
+        if self.start > 0 {
+            let len = self.pending.len() - self.start;
+            self.pending.copy_within(self.start.., 0);
+            self.pending.truncate(len);
+            self.start = 0;
         }
+    }
 
-        let len_flag = bytes[0] & 0xF0;
-        let kind = match Kind::from(bytes[0] & 0x0F) {
-            Kind::Wrong => return Err(ReaderError::WrongKindFlag),
-            kind => kind
+    /// Parse the line at the start of the data not parsed yet, with
+    /// `--framing newline`
+    ///
+    /// The `\n` or `\r\n` ending the line is not part of the message.
+    /// The bytes already searched for the `\n` are skipped.
+    fn parse_line(&mut self) -> Poll<Option<Msg>, ReaderError> {
+        let start = self.start;
+        let scanned = start + self.scanned;
+        let end = match self.pending[scanned..].iter().position(|&b| b == b'\n') {
+            Some(end) => scanned + end,
+            None => {
+                self.scanned = self.pending.len() - start;
+                // Room for the \r
+                if self.scanned > self.max_line + 1 {
+                    return Err(ReaderError::IncorrectSize);
+                }
+                return Ok(Async::NotReady);
+            }
         };
+        self.scanned = 0;
+
+        let line = &self.pending[start..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.len() > self.max_line {
+            return Err(ReaderError::IncorrectSize);
+        }
+        let seq = self.raw_seq;
+        self.raw_seq = seq.wrapping_add(1);
+        let msg = Msg::new(Bytes::from(line), Kind::Data, 0, seq);
+
+        if self.pending.len() > end + 1 {
+            self.start = end + 1;
+        } else {
+            self.reset_pending();
+        }
+        Ok(Async::Ready(Some(msg)))
+    }
 
-        let (uint_len, header_len) = match len_flag {
+    /// Parse message and reallocate if necessary
+    fn parse(&mut self) -> Poll<Option<Msg>, ReaderError> {
+        if self.framing.raw {
+            // Everything received is the payload of a message
+            let seq = self.raw_seq;
+            self.raw_seq = seq.wrapping_add(1);
+            let bytes = self.pending.take().freeze();
+            return Ok(Async::Ready(Some(Msg::new(bytes, Kind::Data, 0, seq))));
+        }
+        if self.framing.newline {
+            return self.parse_line();
+        }
+
+        let start = self.start;
+        match parse_frame(&self.pending[start..], &self.framing)? {
+            Some((msg, frame_len)) => {
+                if self.pending.len() > start + frame_len {
+                    // The following messages, received in the same read,
+                    // are parsed next
+                    self.start += frame_len;
+                } else {
+                    self.reset_pending();
+                }
+                Ok(Async::Ready(Some(msg)))
+            }
+            None => {
+                // We didn't received the full message
+                let capacity = self.pending.capacity() - start;
+                if let Some(info) = parse_header(&self.pending[start..], capacity, &self.framing)? {
+                    let frame_len = info.frame_len();
+                    if info.bytes_capacity < frame_len {
+                        // The buffer is smaller than the message
+                        self.pending.reserve((frame_len + 1) - info.bytes_capacity);
+                    }
+                }
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Parse the frame at the start of `bytes`
+///
+/// Return the message and the length of its frame, or `None` if `bytes`
+/// doesn't contain the whole frame yet. The tag is verified and the
+/// payload decrypted according to `framing`.
+pub fn parse_frame(bytes: &[u8], framing: &Framing) -> Result<Option<(Msg, usize)>, ReaderError> {
+    let info = match parse_header(bytes, bytes.len(), framing)? {
+        Some(info) => info,
+        None => return Ok(None)
+    };
+    if info.payload_len > MESSAGE_MAX_LEN as usize {
+        return Err(ReaderError::IncorrectSize);
+    }
+
+    // The tag follows the payload, it's not part of the message
+    let frame_len = info.frame_len();
+    let data_len = info.header_len + info.payload_len;
+    let header_len = info.header_len;
+
+    if bytes.len() < frame_len {
+        return Ok(None);
+    }
+
+    let frame = &bytes[..frame_len];
+    if let Some(ref key) = framing.hmac {
+        if info.tag_len == 0 || !key.verify(&frame[..data_len], &frame[data_len..]) {
+            return Err(ReaderError::BadMac);
+        }
+    }
+    let msg = match framing.psk {
+        Some(ref psk) => {
+            let payload = psk.decrypt(&frame[header_len..data_len]);
+            let mut msg = BytesMut::with_capacity(header_len + payload.len());
+            msg.put_slice(&frame[..header_len]);
+            msg.put_slice(&payload);
+            msg.freeze()
+        },
+        None => Bytes::from(&frame[..data_len])
+    };
+
+    let mut msg = Msg::new(msg, info.kind, header_len, info.seq);
+    msg.len_flag = Some(info.len_flag);
+    msg.sent_at = info.sent_at;
+    Ok(Some((msg, frame_len)))
+}
+
+/// Parse the header of `bytes`. It can takes differents size
+///
+/// `bytes_capacity` is the capacity of the buffer holding `bytes`.
+///
+/// The header consists of:
+///
+/// ## First byte:
+///
+/// - HEADER\[0\] & 0x0F = [`Kind`] flag.
+/// - HEADER\[0\] & 0x70 = Length flag (0x00, 0x10, 0x20, 0x40 or 0x70).
+/// - HEADER\[0\] & 0x80 = Tag flag, set when an HMAC tag follows the
+///   payload.
+///
+/// ## Following bytes:
+///
+/// The following bytes correspond to the message length, depending of the length flag:
+/// - flag = 0x00 =>  HEADER[1..] as a LEB128 varint, 1 to 10 bytes
+/// - flag = 0x10 =>  HEADER\[1\] as u8
+/// - flag = 0x20 =>  HEADER[1, 2] as u16
+/// - flag = 0x40 =>  HEADER[1, 2, 3, 4] as u32
+/// - flag = 0x70 =>  HEADER[1, 2, 3, 4, 5, 6, 7, 8] as u64
+///
+/// The fixed size lengths are in network endian, or in the byte order
+/// given by `--endian`.
+///
+/// ## Sequence number:
+///
+/// The 4 bytes after the length are the sequence number of the message
+/// as u32. It starts at 0 and is incremented for each message sent on
+/// the connection.
+///
+/// ## Timestamp:
+///
+/// With `--one-way-latency`, the 8 bytes after the sequence number
+/// are the time the message was sent, in microseconds since the Unix
+/// epoch, as u64. Both sides must use it.
+///
+/// ## Tag:
+///
+/// With the tag flag, the payload is followed by a 32 bytes
+/// HMAC-SHA256 tag of the header and the payload. The length in the
+/// header doesn't include it.
+///
+fn parse_header(bytes: &[u8], bytes_capacity: usize, framing: &Framing) -> Result<Option<PayloadInfo>, ReaderError> {
+    let received_len = bytes.len();
+
+    if received_len < 1 {
+        return Ok(None);
+    }
+
+    let len_flag = bytes[0] & 0x70;
+    let tag_len = if bytes[0] & 0x80 != 0 { TAG_LEN } else { 0 };
+    let kind = match Kind::from(bytes[0] & 0x0F) {
+        Kind::Wrong => return Err(ReaderError::WrongKindFlag),
+        kind => kind
+    };
+
+    let (payload_len, len_end) = if len_flag == 0x00 {
+        match read_varint(&bytes[1..])? {
+            Some((payload_len, varint_len)) => (payload_len as usize, 1 + varint_len),
+            None => return Ok(None)
+        }
+    } else {
+        let (uint_len, len_end) = match len_flag {
             0x10 => (1, 2),
             0x20 => (2, 3),
             0x40 => (4, 5),
-            0x80 => (8, 9),
+            0x70 => (8, 9),
             _ => return Err(ReaderError::WrongLengthFlag)
         };
 
-        if received_len < header_len {
+        if received_len < len_end {
             return Ok(None);
         }
 
-        let payload_len = NetworkEndian::read_uint(&bytes[1..], uint_len) as usize;
+        (framing.endian.read_uint(&bytes[1..], uint_len) as usize, len_end)
+    };
 
-        Ok(Some(PayloadInfo {
-            kind, received_len, bytes_capacity, payload_len, header_len
-        }))
-    }
+    let header_len = if framing.timestamps { len_end + 12 } else { len_end + 4 };
 
-    /// Parse message and reallocate if necessary
-    fn parse(&mut self) -> Poll<Option<Msg>, ReaderError> {
-        let PayloadInfo {
-            kind,
-            received_len,
-            bytes_capacity,
-            payload_len,
-            header_len
-        } = match self.parse_header()? {
-            Some(info) => info,
-            None => return Ok(Async::NotReady),
-        };
+    if received_len < header_len {
+        return Ok(None);
+    }
 
-        let data_len = header_len + payload_len;
+    let seq = NetworkEndian::read_u32(&bytes[len_end..]);
+    let sent_at = if framing.timestamps {
+        Some(NetworkEndian::read_u64(&bytes[len_end + 4..]))
+    } else {
+        None
+    };
 
-        if received_len < data_len {
-            // We didn't received the full message
-            if bytes_capacity < data_len {
-                // The buffer is smaller than the message
-                self.pending.reserve((data_len + 1) - bytes_capacity);
-            }
-            Ok(Async::NotReady)
-        } else if received_len > data_len || payload_len > MESSAGE_MAX_LEN as usize {
-            Err(ReaderError::IncorrectSize)
-        } else {
-            let msg = self.pending.take().into();
-            self.pending.reserve(64);
-            Ok(Async::Ready(Some(Msg::new(
-                msg,
-                kind,
-                header_len
-            ))))
-        }
-    }
+    Ok(Some(PayloadInfo {
+        kind, bytes_capacity, payload_len, header_len, len_flag, seq, sent_at, tag_len
+    }))
 }
 
-impl Stream for Reader {
+impl<R: Transport> Stream for Reader<R> {
     type Item = Msg;
     type Error = ReaderError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = self.poll_frames();
+        self.update_probe();
+        polled
+    }
+}
+
+impl<R: Transport> Reader<R> {
+    /// Return the next message, reading the socket when `pending`
+    /// doesn't contain one
+    fn poll_frames(&mut self) -> Poll<Option<Msg>, ReaderError> {
         loop {
-            match self.read.read_buf(&mut self.pending)
-                           .map_err(ReaderError::IO)? {
-                Async::Ready(0) => return Ok(Async::Ready(None)),
-                Async::Ready(_) => match self.parse() {
+            if !self.pending.is_empty() {
+                // Parse what we already have before reading the socket,
+                // a single read can contain several messages
+                match self.parse() {
                     Ok(Async::NotReady) => (),
                     Err(e) => {
                         // Error while parsing, we drop the received data
-                        let _ = self.pending.take();
-                        self.pending.reserve(64);
+                        self.reset_pending();
                         return Err(e)
                     }
+                    Ok(Async::Ready(Some(mut msg))) => {
+                        msg.received_at = self.last_read;
+                        return Ok(Async::Ready(Some(msg)));
+                    }
                     x => return x
-                },
+                }
+            }
+            self.compact();
+            if !self.pending.has_remaining_mut() {
+                // Always leave room to read, a full buffer would look like EOF
+                let additional = self.capacity.max(64);
+                self.pending.reserve(additional);
+            }
+            match AsyncRead::read_buf(&mut self.read, &mut self.pending)
+                             .map_err(ReaderError::IO)? {
+                Async::Ready(0) if self.read.closed() => return Ok(Async::Ready(None)),
+                Async::Ready(0) => {
+                    // Nothing for now, read again once the others ran
+                    task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+                Async::Ready(_) => {
+                    self.last_read = Some(Instant::now());
+                }
                 Async::NotReady => return Ok(Async::NotReady),
             }
         }
     }
 }
 
+/// Decode a LEB128 varint at the start of `bytes`
+///
+/// Return the value and the number of bytes it takes, or `None` if
+/// `bytes` doesn't contain the whole varint yet.
+fn read_varint(bytes: &[u8]) -> Result<Option<(u64, usize)>, ReaderError> {
+    let mut value: u64 = 0;
+
+    for (index, byte) in bytes.iter().enumerate().take(10) {
+        if index == 9 && *byte > 1 {
+            // The 10th byte holds the last bit of a u64, more overflows
+            return Err(ReaderError::IncorrectSize);
+        }
+        value |= u64::from(byte & 0x7F) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, index + 1)));
+        }
+    }
+
+    if bytes.len() >= 10 {
+        // A u64 never takes more than 10 bytes
+        Err(ReaderError::IncorrectSize)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Encode `value` as a LEB128 varint
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
 /// Make a ready-to-send buffer, with the header.
-/// For the header format, see [`Reader::parse_header()`]
-pub fn to_binary(data: &[u8], kind: Kind) -> Bytes {
-    let kind_flag: u8 = kind.into();
+/// For the header format, see [`parse_header()`]
+///
+/// `seq` is the sequence number of the message. With a pre-shared
+/// key, the payload is encrypted first. With an HMAC key, the tag of
+/// the frame is appended.
+///
+/// A payload larger than [`MESSAGE_MAX_LEN`], once encrypted, is
+/// rejected here rather than by the receiver.
+///
+/// In raw mode, the payload is returned as it is.
+///
+/// [`MESSAGE_MAX_LEN`]: ../constant.MESSAGE_MAX_LEN.html
+pub fn to_binary(data: &[u8], kind: Kind, seq: u32, framing: &Framing) -> Result<Bytes, FrameError> {
+    if framing.raw {
+        return Ok(Bytes::from(data));
+    }
+    if framing.newline {
+        if data.contains(&b'\n') {
+            return Err(FrameError::Newline);
+        }
+        check_len(data.len())?;
+        let mut buf = BytesMut::with_capacity(data.len() + 1);
+        buf.put_slice(data);
+        buf.put_u8(b'\n');
+        return Ok(buf.freeze());
+    }
+
+    let mut kind_flag: u8 = kind.into();
+    if framing.hmac.is_some() {
+        kind_flag |= 0x80;
+    }
+
+    let encrypted;
+    let data = match framing.psk {
+        Some(ref psk) => {
+            encrypted = psk.encrypt(data);
+            &encrypted[..]
+        },
+        None => data
+    };
+
+    check_len(data.len())?;
 
     let mut buf = match data.len() {
+        len if framing.length == LengthEncoding::Varint => {
+            let mut buf = BytesMut::with_capacity(len + 15);
+            buf.put_u8(kind_flag);
+            put_varint(&mut buf, len as u64);
+            buf
+        },
         len if len <= 0xFF => {
-            let mut buf = BytesMut::with_capacity(len + 2);
+            let mut buf = BytesMut::with_capacity(len + 6);
             buf.put_slice(&[kind_flag | 0x10, len as u8]);
             buf
         },
         len if len <= 0xFFFF => {
-            let mut buf = BytesMut::with_capacity(len + 3);
+            let mut buf = BytesMut::with_capacity(len + 7);
             buf.put_u8(kind_flag | 0x20);
-            buf.put_u16_be(len as u16);
+            framing.endian.put_uint(&mut buf, len as u64, 2);
             buf
         },
         len if len <= 0xFFFF_FFFF => {
-            let mut buf = BytesMut::with_capacity(len + 5);
+            let mut buf = BytesMut::with_capacity(len + 9);
             buf.put_u8(kind_flag | 0x40);
-            buf.put_u32_be(len as u32);
+            framing.endian.put_uint(&mut buf, len as u64, 4);
             buf
         },
         len => {
-            let mut buf = BytesMut::with_capacity(len + 9);
-            buf.put_u8(kind_flag | 0x80);
-            buf.put_u64_be(len as u64);
+            let mut buf = BytesMut::with_capacity(len + 13);
+            buf.put_u8(kind_flag | 0x70);
+            framing.endian.put_uint(&mut buf, len as u64, 8);
             buf
         }
     };
 
+    buf.put_u32_be(seq);
+    if framing.timestamps {
+        buf.reserve(8);
+        buf.put_u64_be(now_micros());
+    }
     buf.put_slice(data);
-    buf.into()
+    if let Some(ref key) = framing.hmac {
+        let tag = key.tag(&buf);
+        buf.extend_from_slice(&tag);
+    }
+    Ok(buf.into())
+}
+
+/// Return the current time, in microseconds since the Unix epoch
+pub fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+                     .map(|now| now.as_secs() * 1_000_000 + u64::from(now.subsec_micros()))
+                     .unwrap_or(0)
+}
+
+/// Return the name of the length encoding of the length flag `flag`,
+/// as chosen by [`to_binary`]
+pub fn length_flag_name(flag: u8) -> &'static str {
+    match flag & 0x70 {
+        0x00 => "varint",
+        0x10 => "u8",
+        0x20 => "u16",
+        0x40 => "u32",
+        _ => "u64"
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::Kind;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::io::{self, Cursor, Read};
+    use futures::{Async, Stream};
+    use super::{Endian, FrameError, Framing, Kind, LengthEncoding, Reader, ReaderError, Transport};
+    use MESSAGE_MAX_LEN;
+    use crypto::{HmacKey, TAG_LEN};
     //use bytes::BytesMut;
     use bytes::{BufMut, BytesMut};
 
     #[test]
     fn to_binary() {
-        let res = super::to_binary(&[1, 2, 3], Kind::Data);
+        let res = super::to_binary(&[1, 2, 3], Kind::Data, 0, &Framing::default()).unwrap();
 
         let kind_flag: u8 = Kind::Data.into();
-        assert_eq!(res.as_ref(), &[kind_flag | 0x10, 3, 1, 2, 3]);
+        assert_eq!(res.as_ref(), &[kind_flag | 0x10, 3, 0, 0, 0, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn length_flag_name() {
+        let varint = Framing { length: LengthEncoding::Varint, ..Framing::default() };
+        let name = |len: usize, framing: &Framing| {
+            let frame = super::to_binary(&vec![0; len], Kind::Data, 0, framing).unwrap();
+            super::length_flag_name(frame[0])
+        };
+
+        assert_eq!(name(3, &Framing::default()), "u8");
+        assert_eq!(name(256, &Framing::default()), "u16");
+        assert_eq!(name(0x10000, &Framing::default()), "u32");
+        assert_eq!(name(3, &varint), "varint");
+    }
+
+    /// Reader giving its data one byte at a time
+    struct OneByte(Cursor<Vec<u8>>);
+
+    impl Read for OneByte {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    impl ::tokio_io::AsyncRead for OneByte {}
+
+    impl Transport for OneByte {
+        fn closed(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for Cursor<Vec<u8>> {
+        fn closed(&self) -> bool {
+            true
+        }
+    }
+
+    /// Return the messages read from `read`
+    fn read_all<R: Transport>(read: R, framing: Framing) -> Vec<(Kind, u32, Vec<u8>)> {
+        Reader::new(read, 64, framing)
+            .wait()
+            .map(|msg| {
+                let msg = msg.unwrap();
+                (msg.kind, msg.seq, msg.message().to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zero_length_frames_in_one_read() {
+        let mut data = Vec::new();
+        for seq in 0..3 {
+            data.extend_from_slice(&super::to_binary(&[], Kind::Data, seq, &Framing::default()).unwrap());
+        }
+
+        let expected = vec![
+            (Kind::Data, 0, Vec::new()),
+            (Kind::Data, 1, Vec::new()),
+            (Kind::Data, 2, Vec::new())
+        ];
+        assert_eq!(read_all(Cursor::new(data.clone()), Framing::default()), expected);
+        // The headers are split over several reads
+        assert_eq!(read_all(OneByte(Cursor::new(data)), Framing::default()), expected);
+    }
+
+    /// Socket with some data received, and nothing more for now
+    struct Pending(Cursor<Vec<u8>>);
+
+    impl Read for Pending {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n)
+            }
+        }
+    }
+
+    impl ::tokio_io::AsyncRead for Pending {}
+
+    impl Transport for Pending {
+        fn closed(&self) -> bool {
+            true
+        }
+    }
+
+    /// Transport returning each chunk in a read, an empty chunk is a
+    /// read returning 0 without being closed
+    struct Spurious(VecDeque<Vec<u8>>);
+
+    impl Read for Spurious {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                None => Ok(0)
+            }
+        }
+    }
+
+    impl ::tokio_io::AsyncRead for Spurious {}
+
+    impl Transport for Spurious {
+        fn closed(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    #[test]
+    fn spurious_zero_reads() {
+        let frame = super::to_binary(b"hello", Kind::Data, 1, &Framing::default()).unwrap().to_vec();
+        let chunks = vec![Vec::new(), frame[..3].to_vec(), Vec::new(), Vec::new(), frame[3..].to_vec(),
+                          Vec::new(), Vec::new(), frame.clone()];
+
+        assert_eq!(read_all(Spurious(chunks.into_iter().collect()), Framing::default()), vec![
+            (Kind::Data, 1, b"hello".to_vec()),
+            (Kind::Data, 1, b"hello".to_vec())
+        ]);
+    }
+
+    #[test]
+    fn newline_framing() {
+        let framing = Framing { newline: true, ..Framing::default() };
+        assert_eq!(super::to_binary(b"hello", Kind::Data, 7, &framing).unwrap().as_ref(), b"hello\n");
+        assert_eq!(super::to_binary(b"two\nlines", Kind::Data, 7, &framing), Err(FrameError::Newline));
+
+        let expected = vec![
+            (Kind::Data, 0, b"hello".to_vec()),
+            (Kind::Data, 1, Vec::new()),
+            (Kind::Data, 2, b"world".to_vec())
+        ];
+        let data = b"hello\n\r\nworld\r\nincomplete".to_vec();
+        assert_eq!(read_all(Cursor::new(data.clone()), framing.clone()), expected);
+        assert_eq!(read_all(OneByte(Cursor::new(data)), framing), expected);
+    }
+
+    #[test]
+    fn newline_framing_max_line() {
+        let framing = Framing { newline: true, ..Framing::default() };
+        let read = |data: &[u8]| {
+            Reader::new(OneByte(Cursor::new(data.to_vec())), 64, framing.clone())
+                .max_line(5)
+                .wait()
+                .map(|msg| msg.map(|msg| msg.message().to_vec()))
+                .collect::<Vec<_>>()
+        };
+
+        let lines = read(b"hello\r\nworld\n");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].as_ref().unwrap(), b"world");
+
+        // The error comes without waiting for the end of the line
+        let lines = read(b"hello world, and more");
+        match lines[0] {
+            Err(ReaderError::IncorrectSize) => (),
+            _ => panic!("Expected IncorrectSize, got {:?}", lines)
+        }
+    }
+
+    #[test]
+    fn pipelined_frames() {
+        let mut data = Vec::new();
+        for seq in 0..1000 {
+            data.extend_from_slice(&super::to_binary(&[seq as u8; 3], Kind::Data, seq, &Framing::default()).unwrap());
+        }
+
+        // All the messages come out before waiting for the socket again
+        let mut reader = Reader::new(Pending(Cursor::new(data)), 4096, Framing::default());
+        let mut seq = 0;
+        while let Async::Ready(Some(msg)) = reader.poll().unwrap() {
+            assert_eq!(msg.seq, seq);
+            assert_eq!(msg.message().as_ref(), &[seq as u8; 3]);
+            assert!(msg.received_at.is_some());
+            seq += 1;
+        }
+        assert_eq!(seq, 1000);
+    }
+
+    /// Allocator counting the allocations of the threads asking for it
+    struct Counting;
+
+    thread_local!(static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) });
+
+    fn count_allocation() {
+        let _ = ALLOCATIONS.try_with(|count| {
+            if let Some(n) = count.get() {
+                count.set(Some(n + 1));
+            }
+        });
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_allocation();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count_allocation();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    #[test]
+    fn allocations_small_messages() {
+        // Received one at a time
+        let frames = (0..10_000).map(|seq| {
+            super::to_binary(b"hello", Kind::Data, seq, &Framing::default()).unwrap().to_vec()
+        }).collect();
+        let mut reader = Reader::new(Spurious(frames), 4096, Framing::default());
+
+        ALLOCATIONS.with(|count| count.set(Some(0)));
+        let mut received = 0;
+        while let Async::Ready(Some(_)) = reader.poll().unwrap() {
+            received += 1;
+        }
+        let allocations = ALLOCATIONS.with(|count| count.replace(None)).unwrap();
+
+        assert_eq!(received, 10_000);
+        // The messages are inline in their Bytes, the buffer is reused
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn probe_partial_frame() {
+        let frame = super::to_binary(&[7; 100], Kind::Data, 3, &Framing::default()).unwrap();
+        let mut reader = Reader::new(Pending(Cursor::new(frame[..50].to_vec())), 4096, Framing::default());
+        let probe = reader.probe();
+
+        assert!(reader.poll().unwrap().is_not_ready());
+        let pending = probe.get();
+        assert_eq!(pending.len, 50);
+        assert!(pending.capacity >= 4096);
+        assert_eq!(pending.to_string(), "50 bytes pending (capacity 4096), waiting for data #3: 50 of 106 bytes received");
+
+        // Only the first byte of the header
+        let mut reader = Reader::new(Pending(Cursor::new(frame[..1].to_vec())), 64, Framing::default());
+        let probe = reader.probe();
+        assert!(reader.poll().unwrap().is_not_ready());
+        assert!(probe.get().header.is_none());
+        assert_eq!(probe.get().to_string(), "1 bytes pending (capacity 64), header incomplete");
+    }
+
+    #[test]
+    fn parse_frame_boundaries() {
+        let framing = Framing::default();
+
+        for &(len, flag) in &[(0xFF, 0x10), (0x100, 0x20), (0xFFFF, 0x20), (0x1_0000, 0x40)] {
+            let mut frame = super::to_binary(&vec![7; len], Kind::Data, 3, &framing).unwrap().to_vec();
+            assert_eq!(frame[0] & 0x70, flag, "length {}", len);
+
+            // Incomplete until the last byte
+            assert!(super::parse_frame(&frame[..frame.len() - 1], &framing).unwrap().is_none());
+
+            // The next frame is left alone
+            let frame_len = frame.len();
+            frame.extend_from_slice(&[0x10, 0]);
+            let (msg, parsed_len) = super::parse_frame(&frame, &framing).unwrap().unwrap();
+            assert_eq!(parsed_len, frame_len);
+            assert_eq!(msg.seq, 3);
+            assert_eq!(msg.len_flag, Some(flag));
+            assert_eq!(msg.message().len(), len);
+        }
+
+        // Too large to build a frame, only the header is checked
+        for &(len, flag, uint_len) in &[(0xFFFF_FFFF, 0x40, 4), (0x1_0000_0000, 0x70, 8)] {
+            let mut header = BytesMut::with_capacity(13);
+            header.put_u8(flag);
+            header.put_uint_be(len, uint_len);
+            header.put_u32_be(0);
+
+            let info = super::parse_header(&header, header.len(), &Framing::default()).unwrap().unwrap();
+            assert_eq!(info.payload_len as u64, len);
+            assert_eq!(info.len_flag, flag);
+            assert!(super::parse_frame(&header, &framing).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn endian_roundtrip() {
+        for &endian in &[Endian::Big, Endian::Little] {
+            let framing = Framing { endian, ..Framing::default() };
+            for &len in &[3, 0x100, 0x1_0000] {
+                let frame = super::to_binary(&vec![7; len], Kind::Data, 5, &framing).unwrap();
+                let (msg, frame_len) = super::parse_frame(&frame, &framing).unwrap().unwrap();
+                assert_eq!(frame_len, frame.len());
+                assert_eq!((msg.seq, msg.message().len()), (5, len), "{:?}", endian);
+            }
+        }
+
+        let little = Framing { endian: Endian::Little, ..Framing::default() };
+        let frame = super::to_binary(&[1; 0x100], Kind::Data, 0, &little).unwrap();
+        assert_eq!(&frame[..3], &[0x20, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn cross_endian() {
+        let big = Framing::default();
+        let little = Framing { endian: Endian::Little, ..Framing::default() };
+
+        // 0x100 is read as 1, what follows the first payload byte is
+        // taken for the next header
+        let frame = super::to_binary(&[0x31; 0x100], Kind::Data, 0, &big).unwrap();
+        let (msg, frame_len) = super::parse_frame(&frame, &little).unwrap().unwrap();
+        assert_eq!(msg.message().len(), 1);
+        match super::parse_frame(&frame[frame_len..], &little) {
+            Err(ReaderError::WrongLengthFlag) => (),
+            _ => panic!("Expected WrongLengthFlag")
+        }
+
+        let mut header = BytesMut::with_capacity(13);
+        header.put_u8(0x71);
+        header.put_u64_be(1);
+        header.put_u32_be(0);
+        match super::parse_frame(&header, &little) {
+            Err(ReaderError::IncorrectSize) => (),
+            _ => panic!("Expected IncorrectSize")
+        }
+    }
+
+    #[test]
+    fn to_binary_raw() {
+        let framing = Framing { raw: true, ..Framing::default() };
+        let res = super::to_binary(&[1, 2, 3], Kind::Data, 7, &framing).unwrap();
+
+        assert_eq!(res.as_ref(), &[1, 2, 3]);
     }
 
     #[test]
     fn to_binary_u16() {
         let bytes: Vec<u8> = vec![1; 256];
-        let res = super::to_binary(&bytes, Kind::Data);
+        let res = super::to_binary(&bytes, Kind::Data, 0, &Framing::default()).unwrap();
 
         let kind_flag: u8 = Kind::Data.into();
-        let mut buf = BytesMut::with_capacity(256 + 3);
+        let mut buf = BytesMut::with_capacity(256 + 7);
         buf.put_u8(kind_flag | 0x20);
         buf.put_u16_be(bytes.len() as u16);
+        buf.put_u32_be(0);
         buf.put_slice(&bytes);
 
         assert_eq!(res.as_ref(), buf.as_ref());
@@ -258,14 +1360,190 @@ mod tests {
     #[test]
     fn to_binary_u32() {
         let bytes: Vec<u8> = vec![2; 65_536];
-        let res = super::to_binary(&bytes, Kind::Data);
+        let res = super::to_binary(&bytes, Kind::Data, 0, &Framing::default()).unwrap();
 
         let kind_flag: u8 = Kind::Data.into();
-        let mut buf = BytesMut::with_capacity(65_536 + 5);
+        let mut buf = BytesMut::with_capacity(65_536 + 9);
         buf.put_u8(kind_flag | 0x40);
         buf.put_u32_be(bytes.len() as u32);
+        buf.put_u32_be(0);
         buf.put_slice(&bytes);
 
         assert_eq!(res.as_ref(), buf.as_ref());
     }
+
+    #[test]
+    fn kinds_roundtrip() {
+        for kind in Kind::ALL.iter() {
+            let res = super::to_binary(&[1, 2, 3], *kind, 0, &Framing::default()).unwrap();
+            let info = super::parse_header(&res, res.len(), &Framing::default()).unwrap().unwrap();
+
+            assert_eq!(info.kind, *kind);
+            assert_eq!(Kind::from(u8::from(*kind)), *kind);
+            assert!(u8::from(*kind) <= 0x0F);
+        }
+    }
+
+    #[test]
+    fn kinds_metadata() {
+        for (value, &kind) in Kind::ALL.iter().enumerate() {
+            assert_eq!(u8::from(kind) as usize, value);
+            assert!(!kind.description().is_empty());
+            // The names are parsed back
+            let same_name = Kind::ALL.iter().filter(|other| other.name() == kind.name()).count();
+            assert_eq!(same_name, 1, "{}", kind.name());
+        }
+    }
+
+    #[test]
+    fn ackable_kinds() {
+        assert_eq!(Kind::parse_ackable("data").unwrap(), vec![Kind::Data]);
+        assert_eq!(Kind::parse_ackable("data, history").unwrap(), vec![Kind::Data, Kind::History]);
+        assert!(Kind::parse_ackable("none").unwrap().is_empty());
+        // Answering a response would never end
+        assert!(Kind::parse_ackable("data,response").is_err());
+    }
+
+    #[test]
+    fn subscribable_kinds() {
+        let kinds = Kind::parse_subscribable("history, typing").unwrap();
+        assert_eq!(kinds, vec![Kind::History, Kind::Typing]);
+        assert_eq!(Kind::mask(&kinds), 1 << 8 | 1 << 7);
+        assert_eq!(Kind::mask(&Kind::parse_subscribable("none").unwrap()), 0);
+        // Not forwarded by the server
+        assert!(Kind::parse_subscribable("response").is_err());
+    }
+
+    #[test]
+    fn unused_kinds_are_wrong() {
+        for byte in 0..=0xFF {
+            let kind = Kind::from(byte);
+            if !Kind::ALL.contains(&kind) {
+                assert_eq!(kind, Kind::Wrong);
+            }
+        }
+        assert_eq!(Kind::from(u8::from(Kind::Wrong)), Kind::Wrong);
+    }
+
+    #[test]
+    fn seq_roundtrip() {
+        for seq in [0, 1, 0x0102_0304, u32::MAX].iter() {
+            let res = super::to_binary(&[1, 2, 3], Kind::Data, *seq, &Framing::default()).unwrap();
+            let info = super::parse_header(&res, res.len(), &Framing::default()).unwrap().unwrap();
+
+            assert_eq!(info.seq, *seq);
+            assert_eq!(info.header_len + info.payload_len, res.len());
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        let framing = Framing { timestamps: true, ..Framing::default() };
+        let before = super::now_micros();
+        let frame = super::to_binary(b"hello", Kind::Data, 7, &framing).unwrap();
+        assert_eq!(frame.len(), 2 + 4 + 8 + 5);
+
+        let (msg, len) = super::parse_frame(&frame, &framing).unwrap().unwrap();
+        assert_eq!(len, frame.len());
+        assert_eq!(msg.seq, 7);
+        assert_eq!(msg.message().as_ref(), b"hello");
+        let sent_at = msg.sent_at.unwrap();
+        assert!(sent_at >= before && sent_at <= super::now_micros());
+
+        // The timestamp is part of the header
+        assert!(super::parse_frame(&frame[..13], &framing).unwrap().is_none());
+        let (msg, _) = super::parse_frame(&super::to_binary(b"hello", Kind::Data, 7, &Framing::default()).unwrap(),
+                                          &Framing::default()).unwrap().unwrap();
+        assert!(msg.sent_at.is_none());
+    }
+
+    #[test]
+    fn seq_incomplete() {
+        let res = super::to_binary(&[1, 2, 3], Kind::Data, 7, &Framing::default()).unwrap();
+        // Length received, but not the whole sequence number
+        assert!(super::parse_header(&res[..4], 4, &Framing::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn too_large() {
+        assert_eq!(super::check_len(MESSAGE_MAX_LEN as usize), Ok(()));
+        assert_eq!(super::check_len(MESSAGE_MAX_LEN as usize + 1),
+                   Err(FrameError::TooLarge(MESSAGE_MAX_LEN as usize + 1)));
+    }
+
+    #[test]
+    fn hmac_tag() {
+        let key = HmacKey::new(b"secret");
+        let framing = Framing { hmac: Some(key.clone()), ..Framing::default() };
+        let res = super::to_binary(&[1, 2, 3], Kind::Data, 0, &framing).unwrap();
+        let info = super::parse_header(&res, res.len(), &Framing::default()).unwrap().unwrap();
+
+        assert_eq!(res[0] & 0x80, 0x80);
+        assert_eq!(info.kind, Kind::Data);
+        assert_eq!(info.payload_len, 3);
+        assert_eq!(info.tag_len, TAG_LEN);
+        assert_eq!(res.len(), info.header_len + 3 + TAG_LEN);
+
+        let (frame, tag) = res.split_at(info.header_len + 3);
+        assert!(key.verify(frame, tag));
+    }
+
+    #[test]
+    fn parse_header_wrong_kind() {
+        let res = super::parse_header(&[0x1F, 0], 2, &Framing::default());
+        match res {
+            Err(ReaderError::WrongKindFlag) => (),
+            _ => panic!("Expected WrongKindFlag")
+        }
+    }
+
+    #[test]
+    fn to_binary_varint() {
+        let framing = Framing { length: LengthEncoding::Varint, ..Framing::default() };
+        let res = super::to_binary(&[1, 2, 3, 4, 5], Kind::Data, 0, &framing).unwrap();
+
+        let kind_flag: u8 = Kind::Data.into();
+        assert_eq!(res.as_ref(), &[kind_flag, 5, 0, 0, 0, 0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        let framing = Framing { length: LengthEncoding::Varint, ..Framing::default() };
+        for len in [0, 1, 127, 128, 300, 16_383, 16_384, 70_000].iter() {
+            let bytes: Vec<u8> = vec![3; *len];
+            let res = super::to_binary(&bytes, Kind::Response, 0, &framing).unwrap();
+            let info = super::parse_header(&res, res.len(), &Framing::default()).unwrap().unwrap();
+
+            assert_eq!(info.kind, Kind::Response);
+            assert_eq!(info.payload_len, *len);
+            assert_eq!(info.header_len + info.payload_len, res.len());
+        }
+    }
+
+    #[test]
+    fn varint_incomplete() {
+        // The varint continues after the last byte
+        let res = super::parse_header(&[0x00, 0x80, 0x80], 3, &Framing::default()).unwrap();
+        assert!(res.is_none());
+
+        match super::parse_header(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                                    0xFF, 0xFF, 0xFF, 0xFF, 0xFF], 11, &Framing::default()) {
+            Err(ReaderError::IncorrectSize) => (),
+            _ => panic!("Expected IncorrectSize")
+        }
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let mut max = [0xFF; 10];
+        max[9] = 0x01;
+        assert_eq!(super::read_varint(&max).unwrap(), Some((u64::MAX, 10)));
+
+        // The 10th byte can't have more than the last bit of a u64
+        max[9] = 0x02;
+        match super::read_varint(&max) {
+            Err(ReaderError::IncorrectSize) => (),
+            _ => panic!("Expected IncorrectSize")
+        }
+    }
 }
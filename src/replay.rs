@@ -0,0 +1,165 @@
+//! Transcripts sent again to the server with `--replay`
+//!
+//! A transcript is read in one of two formats, with `--replay-format`:
+//!
+//! - lines: One message per line, without its end of line. A line can
+//!   start with the time it was sent, in seconds, followed by a tab:
+//!   `1700000000.250\thello`. Empty lines are skipped.
+//! - framed: Messages framed like on the wire, as captured from a
+//!   connection. Only the data messages are sent. With
+//!   `--one-way-latency`, their headers hold the time they were sent.
+//!
+//! The delays between the recorded times are kept, divided by
+//! `--replay-speed`. A message without a time is sent right after the
+//! previous one.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use reader::{parse_frame, Framing, Kind};
+use {ReplayFormat, MESSAGE_MAX_LEN};
+
+/// A message of a transcript
+#[derive(Debug, PartialEq)]
+pub struct Replayed {
+    /// Time to wait after the previous message
+    pub delay: Duration,
+    /// Message sent to the server
+    pub data: Vec<u8>
+}
+
+/// Turn the recorded times, in microseconds, into delays between the
+/// messages
+#[derive(Default)]
+struct Clock {
+    /// Time of the last message with one
+    last: Option<u64>
+}
+
+impl Clock {
+    fn delay(&mut self, at: Option<u64>) -> Duration {
+        let at = match at {
+            Some(at) => at,
+            None => return Duration::from_secs(0)
+        };
+        let delay = self.last.map(|last| at.saturating_sub(last)).unwrap_or(0);
+        self.last = Some(at);
+        Duration::from_micros(delay)
+    }
+}
+
+/// Read the transcript at `path`
+///
+/// The framed messages are parsed with `framing`. The error explains
+/// which message is invalid.
+pub fn load(path: &Path, format: ReplayFormat, framing: &Framing) -> Result<Vec<Replayed>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Can not read {}: {}", path.display(), e))?;
+    let messages = match format {
+        ReplayFormat::Lines => parse_lines(&bytes),
+        ReplayFormat::Framed => parse_frames(&bytes, framing)?
+    };
+    if let Some(index) = messages.iter().position(|m| m.data.len() as u64 > MESSAGE_MAX_LEN) {
+        return Err(format!("Message #{} of {} is longer than {} bytes",
+                           index + 1, path.display(), MESSAGE_MAX_LEN));
+    }
+    Ok(messages)
+}
+
+/// Read one message per line, with an optional time
+fn parse_lines(bytes: &[u8]) -> Vec<Replayed> {
+    let mut clock = Clock::default();
+    bytes.split(|&b| b == b'\n')
+         .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+         .filter(|line| !line.is_empty())
+         .map(|line| {
+             let (at, data) = split_time(line);
+             Replayed { delay: clock.delay(at), data: data.to_vec() }
+         })
+         .collect()
+}
+
+/// Split the time, in microseconds, at the start of `line`
+///
+/// The line is the message when it doesn't start with a number of
+/// seconds followed by a tab.
+fn split_time(line: &[u8]) -> (Option<u64>, &[u8]) {
+    let tab = match line.iter().position(|&b| b == b'\t') {
+        Some(tab) => tab,
+        None => return (None, line)
+    };
+    let secs = ::std::str::from_utf8(&line[..tab]).ok()
+                                                  .and_then(|s| f64::from_str(s).ok())
+                                                  .filter(|secs| secs.is_finite() && *secs >= 0.0);
+    match secs {
+        Some(secs) => (Some((secs * 1_000_000.0).round() as u64), &line[tab + 1..]),
+        None => (None, line)
+    }
+}
+
+/// Read the messages framed like on the wire
+fn parse_frames(mut bytes: &[u8], framing: &Framing) -> Result<Vec<Replayed>, String> {
+    let mut clock = Clock::default();
+    let mut messages = Vec::new();
+    let mut index = 1;
+    while !bytes.is_empty() {
+        let (msg, len) = match parse_frame(bytes, framing) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Err(format!("Message #{} is incomplete", index)),
+            Err(e) => return Err(format!("Message #{} is invalid: {:?}", index, e))
+        };
+        if msg.kind == Kind::Data {
+            messages.push(Replayed { delay: clock.delay(msg.sent_at), data: msg.message().to_vec() });
+        }
+        bytes = &bytes[len..];
+        index += 1;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+    use byteorder::{BigEndian, ByteOrder};
+    use reader::{to_binary, Framing, Kind};
+    use super::{parse_frames, parse_lines, Replayed};
+
+    fn replayed(delay_ms: u64, data: &[u8]) -> Replayed {
+        Replayed { delay: Duration::from_millis(delay_ms), data: data.to_vec() }
+    }
+
+    #[test]
+    fn lines_with_times() {
+        let transcript = b"10.5\thello\r\n\nno time\n11\tworld\n12.25\t\n9\tearlier\nnot\ta time\n";
+        assert_eq!(parse_lines(transcript), vec![
+            replayed(0, b"hello"),
+            replayed(0, b"no time"),
+            replayed(500, b"world"),
+            replayed(1250, b""),
+            replayed(0, b"earlier"),
+            replayed(0, b"not\ta time"),
+        ]);
+    }
+
+    #[test]
+    fn frames_with_times() {
+        let framing = Framing { timestamps: true, ..Framing::default() };
+        let mut transcript = Vec::new();
+        for &(data, kind, sent_at) in &[(&b"hello"[..], Kind::Data, 1_000_000),
+                                        (&b""[..], Kind::Response, 1_100_000),
+                                        (&b"world"[..], Kind::Data, 1_300_000)] {
+            let mut frame = to_binary(data, kind, 1, &framing).unwrap().to_vec();
+            // The time follows the length and the sequence number
+            BigEndian::write_u64(&mut frame[6..14], sent_at);
+            transcript.extend(frame);
+        }
+
+        assert_eq!(parse_frames(&transcript, &framing).unwrap(), vec![
+            replayed(0, b"hello"),
+            replayed(300, b"world"),
+        ]);
+        assert!(parse_frames(&transcript[..transcript.len() - 1], &framing).is_err());
+    }
+}
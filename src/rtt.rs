@@ -0,0 +1,122 @@
+//! Roundtrip measurements written to a CSV file with `--rtt-csv`
+//!
+//! Each line is `timestamp,message_id,rtt_micros,payload_len`:
+//! - timestamp: When the response was received, in seconds since the
+//!   Unix epoch
+//! - message_id: Sequence number of the message
+//! - rtt_micros: Roundtrip time in microseconds
+//! - payload_len: Length of the message, without header
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// First line of a new file
+const HEADER: &str = "timestamp,message_id,rtt_micros,payload_len";
+
+/// Minimum time between two flushes of the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// CSV file of roundtrips, shared by all the peers
+#[derive(Clone)]
+pub struct RttCsv {
+    inner: Arc<Mutex<Inner>>
+}
+
+struct Inner {
+    file: BufWriter<File>,
+    last_flush: Instant
+}
+
+impl RttCsv {
+    /// Open `path` to append to it
+    ///
+    /// The header is written when the file is empty.
+    pub fn open(path: &Path) -> io::Result<RttCsv> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut file = BufWriter::new(file);
+
+        if empty {
+            writeln!(file, "{}", HEADER)?;
+            file.flush()?;
+        }
+
+        Ok(RttCsv {
+            inner: Arc::new(Mutex::new(Inner { file, last_flush: Instant::now() }))
+        })
+    }
+
+    /// Write a roundtrip
+    ///
+    /// The file is flushed at most once per second, and by
+    /// [`RttCsv::flush`].
+    pub fn record(&self, message_id: u32, rtt: Duration, payload_len: usize) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return
+        };
+
+        let result = writeln!(inner.file, "{}.{:06},{},{},{}",
+                              timestamp.as_secs(), timestamp.subsec_micros(),
+                              message_id, rtt.as_micros(), payload_len)
+            .and_then(|_| {
+                if inner.last_flush.elapsed() >= FLUSH_INTERVAL {
+                    inner.last_flush = Instant::now();
+                    inner.file.flush()
+                } else {
+                    Ok(())
+                }
+            });
+
+        if let Err(e) = result {
+            println!("Can not write the roundtrip: {}", e);
+        }
+    }
+
+    /// Write what is buffered to the file
+    pub fn flush(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.last_flush = Instant::now();
+            let _ = inner.file.flush();
+        }
+    }
+}
+
+impl fmt::Debug for RttCsv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RttCsv")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{read_to_string, remove_file};
+    use std::time::Duration;
+    use super::{RttCsv, HEADER};
+
+    #[test]
+    fn header_written_once() {
+        let path = ::std::env::temp_dir().join(format!("chat-rtt-{}.csv", ::std::process::id()));
+        let _ = remove_file(&path);
+
+        RttCsv::open(&path).unwrap().record(1, Duration::from_micros(250), 12);
+        let csv = RttCsv::open(&path).unwrap();
+        csv.record(2, Duration::from_millis(3), 4);
+        csv.flush();
+
+        let content = read_to_string(&path).unwrap();
+        let _ = remove_file(&path);
+        let lines: Vec<&str> = content.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], HEADER);
+        assert!(lines[1].ends_with(",1,250,12"));
+        assert!(lines[2].ends_with(",2,3000,4"));
+    }
+}
@@ -1,13 +1,33 @@
 //! Server Actor
 
 use actix::prelude::*;
+use actix::actors::signal;
 use tokio_tcp::{TcpListener, TcpStream};
+use futures::{future, Future};
 use futures::stream::Stream;
-use std::net::{SocketAddrV4, Ipv4Addr};
+use tokio_reactor::Handle;
+use socket2::{Socket, Domain, Type, Protocol};
+use std::env;
+use std::fs;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
 
-use peer::{Peer, PeerClose};
-use user::{User, UserInput};
-use Config;
+use cidr;
+use events::Event;
+use metrics::{self, Metrics, METRICS};
+use admin::{self, AdminCommand};
+use peer::{format_uptime, Peer, PeerClose, Disconnect, Stats, Relay, Forward, Reconfigure, Pause, pause_signals, Inspect, inspect_signals, SetDisplay};
+use reader::Kind;
+use user::{Command, Input, User, UserInput, InputClosed, Typing, TypingDebounce};
+use {reload_config, Config, ExitCode, IpVersion, RelayFull};
 
 /// Address of a [`User`]
 type AUser = Addr<User<Server>>;
@@ -20,61 +40,551 @@ type APeer = Addr<Peer<Server>>;
 /// [`User`] input and managing connected [`Peer`]
 ///
 pub struct Server {
-    /// List of connected [`Peer`]s
-    peers: Vec<APeer>,
+    /// Sockets bound by [`Server::new`], we accept connections on them
+    /// once started
+    listeners: Vec<TcpListener>,
+    /// Addresses of `listeners`, with the port given by the OS for
+    /// `--port 0`
+    addrs: Vec<SocketAddr>,
+    /// Addresses in use when [`Server::new`] tried to bind them, retried
+    /// once started with `--bind-retry`
+    retrying: Vec<SocketAddr>,
+    /// When we stop retrying to bind `retrying`
+    retry_deadline: Instant,
+    /// List of connected [`Peer`]s, with their id and address
+    peers: Vec<(usize, APeer, Option<SocketAddr>)>,
+    /// Nicknames of the connected clients, by connection id
+    nicks: HashMap<usize, String>,
+    /// When the connected peers connected, by connection id
+    connected_at: HashMap<usize, Instant>,
+    /// Id of the next connection
+    next_id: usize,
+    /// Limit the typing notifications
+    typing: TypingDebounce,
+    /// Last messages, replayed to new clients with `--history`
+    history: VecDeque<Bytes>,
+    /// Total length of the messages in `history`
+    history_bytes: usize,
     /// A [`User`] actor
     user: Option<AUser>,
+    /// Counters of all the closed connections
+    stats: Stats,
+    /// We are shutting down, waiting for the peers to close
+    closing: bool,
+    /// The admin socket we listen on, removed on shutdown
+    admin_socket: Option<PathBuf>,
+    /// Sending the user input is paused with [`Pause`], the peers
+    /// connecting meanwhile are paused too
+    paused: bool,
+    /// Messages sent and received at the last status line, with
+    /// `--status-interval`
+    status_messages: u64,
+    /// Bytes sent and received at the last status line
+    status_bytes: u64,
     /// Configuration
     config: Config
 }
 
 impl Server {
-    /// Return a new [`Server`] with some configuration
-    pub fn new(config: Config) -> Server {
-        Server {
+    /// Return a new [`Server`] with some configuration, bound to its
+    /// ports
+    ///
+    /// A port that can't be bound is skipped, unless
+    /// `--require-all-ports` is set. With `--bind-retry`, a port in use
+    /// is retried once the server is started.
+    ///
+    /// With systemd socket activation, the sockets passed by systemd
+    /// are used instead and the ports are ignored. The variables
+    /// passing them are removed from the environment once taken.
+    pub fn new(config: Config) -> Result<Server, ServerError> {
+        let mut listeners = Vec::new();
+        let mut retrying = Vec::new();
+
+        let activated = env::var("LISTEN_PID").ok().and_then(|pid| {
+            parse_listen_fds(&pid, &env::var("LISTEN_FDS").unwrap_or_default(), process::id())
+        });
+        match activated {
+            Some(count) => {
+                // Taken: a process we start must not think they are for it
+                for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+                    env::remove_var(var);
+                }
+                listeners = inherited_listeners(count).map_err(ServerError::Systemd)?;
+            }
+            None if config.systemd => {
+                return Err(ServerError::Systemd(io::Error::new(
+                    io::ErrorKind::NotFound, "no socket passed in LISTEN_FDS"
+                )));
+            }
+            None => ()
+        }
+
+        for &port in config.ports.iter().filter(|_| activated.is_none()) {
+            let addr: SocketAddr = match config.ip_version {
+                IpVersion::V6 => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into(),
+                IpVersion::Any | IpVersion::V4 => SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port).into()
+            };
+
+            match bind(addr, config.interface.as_ref(), config.backlog) {
+                Ok(listener) => listeners.push(listener),
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && config.bind_retry.is_some() => {
+                    println!("Can not bind to port {}: {}, retrying", port, e);
+                    retrying.push(addr);
+                }
+                Err(error) if config.require_all_ports => {
+                    return Err(ServerError::Bind { port, error });
+                }
+                Err(e) => println!("Can not bind to port {}: {}", port, e)
+            }
+        }
+
+        if listeners.is_empty() && retrying.is_empty() {
+            return Err(ServerError::NoPort);
+        }
+
+        let addrs = listeners.iter().filter_map(|l| l.local_addr().ok()).collect();
+
+        Ok(Server {
+            listeners,
+            addrs,
+            retrying,
+            retry_deadline: Instant::now() + config.bind_retry.unwrap_or_default(),
             peers: vec![],
+            nicks: HashMap::new(),
+            connected_at: HashMap::new(),
+            next_id: 0,
+            typing: TypingDebounce::default(),
+            history: VecDeque::new(),
+            history_bytes: 0,
             user: None,
+            stats: Stats::default(),
+            closing: false,
+            admin_socket: None,
+            paused: false,
+            status_messages: 0,
+            status_bytes: 0,
             config
+        })
+    }
+
+    /// Addresses the server is bound to, one per port
+    pub fn bound_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Return the addresses in use, which `--bind-retry` retries once
+    /// started
+    pub fn retrying_addrs(&self) -> &[SocketAddr] {
+        &self.retrying
+    }
+
+    /// Try to bind `addr` again, after `BIND_RETRY_INTERVAL`
+    ///
+    /// Without the actor thread blocked in the meantime. Once the
+    /// `--bind-retry` delay is over, the port is given up like any port
+    /// that can't be bound.
+    fn retry_bind(&mut self, addr: SocketAddr, ctx: &mut Context<Self>) {
+        ctx.run_later(BIND_RETRY_INTERVAL, move |server, ctx| {
+            let error = match bind(addr, server.config.interface.as_ref(), server.config.backlog) {
+                Ok(listener) => {
+                    if let Ok(bound) = listener.local_addr() {
+                        println!("Listening on {}", bound);
+                        server.addrs.push(bound);
+                    }
+                    ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(TcpConnect));
+                    None
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && Instant::now() < server.retry_deadline => {
+                    println!("Can not bind to port {}: {}, retrying", addr.port(), e);
+                    server.retry_bind(addr, ctx);
+                    return;
+                }
+                Err(error) if server.config.require_all_ports => Some(ServerError::Bind { port: addr.port(), error }),
+                Err(e) => {
+                    println!("Can not bind to port {}: {}", addr.port(), e);
+                    None
+                }
+            };
+            server.retrying.retain(|&a| a != addr);
+            let error = match error {
+                None if server.addrs.is_empty() && server.retrying.is_empty() => Some(ServerError::NoPort),
+                error => error
+            };
+            if let Some(e) = error {
+                println!("{}", e);
+                e.exit_code().exit();
+            }
+        });
+    }
+
+    /// Print a status line, `interval` after the previous one
+    ///
+    /// The rates are of all the peers together, read from the counters
+    /// they update in [`METRICS`].
+    fn print_status(&mut self, interval: Duration) {
+        let messages = Metrics::get(&METRICS.messages_sent) + Metrics::get(&METRICS.messages_received);
+        let rate = (messages - self.status_messages) as f64 / interval.as_secs_f64();
+        self.status_messages = messages;
+
+        let bytes_sent = Metrics::get(&METRICS.bytes_sent);
+        let bytes_received = Metrics::get(&METRICS.bytes_received);
+        let bytes = bytes_sent + bytes_received;
+        let bytes_rate = (bytes - self.status_bytes) as f64 / interval.as_secs_f64();
+        self.status_bytes = bytes;
+
+        println!("{}", status_line(self.peers.len(), rate, bytes_sent, bytes_received, bytes_rate));
+    }
+
+    /// Write an event with `--event-log`
+    fn log_event(&self, event: &Event) {
+        if let Some(ref log) = self.config.event_log {
+            log.record(event);
         }
     }
+
+    /// Nickname of the connection `id`, a default one until the
+    /// client sends a [`Kind::Hello`]
+    fn nick(&self, id: usize) -> String {
+        self.nicks.get(&id).cloned().unwrap_or_else(|| format!("guest{}", id))
+    }
+
+    /// Register `asked` as the nickname of the connection `id`
+    ///
+    /// A name used by another client gets a number appended. Return the
+    /// registered name.
+    fn register_nick(&mut self, id: usize, asked: &str) -> String {
+        let asked = match asked.trim() {
+            "" => self.nick(id),
+            asked => asked.to_owned()
+        };
+        let taken = |nick: &str| {
+            self.peers.iter().any(|&(other, _, _)| other != id && self.nick(other) == nick)
+        };
+
+        let mut nick = asked.clone();
+        let mut n = 2;
+        while taken(&nick) {
+            nick = format!("{}{}", asked, n);
+            n += 1;
+        }
+
+        self.nicks.insert(id, nick.clone());
+        nick
+    }
+
+    /// Close the connections and stop once they are closed, the admin
+    /// socket is removed at once
+    fn shutdown(&mut self, reason: &str) {
+        self.closing = true;
+        if let Some(path) = self.admin_socket.take() {
+            let _ = fs::remove_file(path);
+        }
+        if self.peers.is_empty() {
+            System::current().stop();
+        }
+        for (_, peer, _) in &self.peers {
+            peer.do_send(Disconnect(reason.to_owned()));
+        }
+    }
+
+    /// Read the config file again, and give the peers the options
+    /// that can change while running
+    fn reload(&mut self) {
+        if self.config.config_file.is_none() {
+            println!("SIGHUP received, but there is no --config file to reload");
+            return;
+        }
+        let config = match reload_config(&self.config) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Can not reload the configuration: {}", e);
+                return;
+            }
+        };
+
+        for option in self.config.reconfigure(&config) {
+            println!("The {} can not change while running, restart to apply it", option);
+        }
+        for (_, peer, _) in &self.peers {
+            peer.do_send(Reconfigure(config.clone()));
+        }
+        println!("Configuration reloaded");
+    }
+
+    /// Run a command typed instead of a message
+    fn run_command(&mut self, command: Result<Command, String>) {
+        match command {
+            Ok(Command::SetDisplay(display)) => {
+                println!("Display set to {}", display.name());
+                self.config.display = display.clone();
+                for (_, peer, _) in &self.peers {
+                    peer.do_send(SetDisplay(display.clone()));
+                }
+            }
+            Err(e) => println!("{}", e)
+        }
+    }
+
+    /// Relay a message to `peer` without growing its mailbox, return
+    /// true if it took it
+    ///
+    /// When the mailbox is full, the message is dropped for this peer
+    /// or the connection is closed, according to `--relay-full`.
+    fn relay_to(&self, &(id, ref peer, addr): &(usize, APeer, Option<SocketAddr>),
+                kind: Kind, payload: Bytes) -> bool
+    {
+        match peer.try_send(Forward(kind, payload)) {
+            Ok(()) => return true,
+            Err(SendError::Full(_)) => match self.config.relay_full {
+                RelayFull::Drop => {
+                    println!("{} is too busy, {:?} message not relayed", self.nick(id), kind);
+                }
+                RelayFull::Disconnect => {
+                    println!("{} is too busy, closing its connection", self.nick(id));
+                    self.log_event(&Event::Error { id, addr, reason: "mailbox full" });
+                    peer.do_send(Disconnect("too busy".to_owned()));
+                }
+            },
+            // The peer is stopping, its PeerClose is on the way
+            Err(SendError::Closed(_)) => {}
+        }
+        false
+    }
+
+    /// The message matches `--filter`, it's not relayed. Messages not
+    /// in utf8 never match
+    fn filtered(&self, payload: &[u8]) -> bool {
+        match (&self.config.filter, ::std::str::from_utf8(payload)) {
+            (Some(filter), Ok(text)) => filter.is_match(text),
+            _ => false
+        }
+    }
+
+    /// Keep a message in the history, dropping the oldest ones past
+    /// `--history` messages or `--history-bytes`
+    fn remember(&mut self, message: Bytes) {
+        if self.config.history == 0 {
+            return;
+        }
+
+        self.history_bytes += message.len();
+        self.history.push_back(message);
+
+        while self.history.len() > self.config.history
+            || self.history_bytes > self.config.history_bytes
+        {
+            match self.history.pop_front() {
+                Some(old) => self.history_bytes -= old.len(),
+                None => break
+            }
+        }
+    }
+}
+
+/// Errors preventing the server from starting
+#[derive(Debug)]
+pub enum ServerError {
+    /// A port can't be bound, with `--require-all-ports`
+    Bind { port: u16, error: io::Error },
+    /// None of the ports can be bound
+    NoPort,
+    /// The sockets passed by systemd can't be used, or there are none
+    /// with `--systemd`
+    Systemd(io::Error)
+}
+
+impl ServerError {
+    /// Exit code of the process
+    pub fn exit_code(&self) -> ExitCode {
+        match *self {
+            ServerError::Bind { .. } | ServerError::NoPort | ServerError::Systemd(_) => ExitCode::Bind
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::Bind { port, ref error } => write!(f, "Can not bind to port {}: {}", port, error),
+            ServerError::NoPort => write!(f, "Can not listen on any port"),
+            ServerError::Systemd(ref error) => write!(f, "Can not use the sockets of systemd: {}", error)
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ServerError::Bind { ref error, .. } | ServerError::Systemd(ref error) => Some(error),
+            ServerError::NoPort => None
+        }
+    }
+}
+
+/// Format a status line printed with `--status-interval`
+///
+/// The fields keep their names and order, to be parsed by scripts. New
+/// ones are added at the end.
+fn status_line(connections: usize, messages_per_sec: f64, bytes_sent: u64, bytes_received: u64,
+               bytes_per_sec: f64) -> String {
+    format!("status connections={} messages_per_sec={:.2} bytes_sent={} bytes_received={} bytes_per_sec={:.2}",
+            connections, messages_per_sec, bytes_sent, bytes_received, bytes_per_sec)
+}
+
+/// Return true when `target` is the IP address of `addr`, or the
+/// address with its port
+fn addr_matches(addr: SocketAddr, target: &str) -> bool {
+    addr.to_string() == target || addr.ip().to_string() == target
+}
+
+/// First file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Return the number of sockets passed by systemd, from the values of
+/// `LISTEN_PID` and `LISTEN_FDS`
+///
+/// The sockets are for us only if `LISTEN_PID` is our process id.
+fn parse_listen_fds(pid: &str, fds: &str, our_pid: u32) -> Option<usize> {
+    if u32::from_str(pid).ok()? != our_pid {
+        return None;
+    }
+    usize::from_str(fds).ok().filter(|&count| count > 0)
+}
+
+/// Take the `count` listening sockets passed by systemd
+#[cfg(unix)]
+fn inherited_listeners(count: usize) -> io::Result<Vec<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    (0..count as i32).map(|i| {
+        // systemd gives us these file descriptors, nothing else in the
+        // process owns them
+        let listener = unsafe { ::std::net::TcpListener::from_raw_fd(LISTEN_FDS_START + i) };
+        TcpListener::from_std(listener, &Handle::default())
+    }).collect()
+}
+
+/// Socket activation is only supported on Unix
+#[cfg(not(unix))]
+fn inherited_listeners(_: usize) -> io::Result<Vec<TcpListener>> {
+    Err(io::Error::new(io::ErrorKind::Other, "socket activation is only supported on Unix"))
+}
+
+/// Delay between two attempts to bind a port with `--bind-retry`
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bind a listening socket to `addr`, on `interface` when set, with
+/// `backlog` connections waiting to be accepted at most
+///
+/// `SO_REUSEADDR` lets us bind while the connections of a previous
+/// server are in TIME_WAIT. An IPv6 socket doesn't accept IPv4
+/// connections.
+fn bind(addr: SocketAddr, interface: Option<&String>, backlog: i32) -> io::Result<TcpListener> {
+    // The options have to be set before binding the socket
+    let domain = if addr.is_ipv6() { Domain::ipv6() } else { Domain::ipv4() };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+
+    TcpListener::from_std(socket.into_tcp_listener(), &Handle::default())
+}
+
+/// Set `SO_BINDTODEVICE` on `socket`
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    let name = ::std::ffi::CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    socket.bind_device(Some(&name))
+}
+
+/// Binding to an interface is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_: &Socket, interface: &str) -> io::Result<()> {
+    println!("--interface is only supported on Linux, not listening only on {}", interface);
+    Ok(())
 }
 
 /// Wrap a [`TcpStream`] to handle the stream as an Actix message
 #[derive(Message)]
 struct TcpConnect(pub TcpStream);
 
+/// Ask the [`Server`] the addresses it listens on, one per port
+///
+/// With `--port 0`, this is how to know the port given by the OS.
+#[allow(dead_code)]
+pub struct GetBoundAddr;
+
+impl Message for GetBoundAddr {
+    type Result = Vec<SocketAddr>;
+}
+
 impl Actor for Server {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // We start to bind the socket
-        let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), self.config.port);
-
-        let listener = match TcpListener::bind(&addr.into()) {
-            Ok(listener) => listener,
-            Err(e) => {
-                println!("Can not bind to the address: {}", e);
-                System::current().stop();
-                return;
-            }
-        };
-
-        let addr = listener.local_addr();
+        for listener in self.listeners.drain(..) {
+            // Add the socket as a stream to our actor's context
+            ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(|st| {
+                TcpConnect(st)
+            }));
+        }
 
-        // Add the socket as a stream to our actor's context
-        ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(|st| {
-            TcpConnect(st)
-        }));
+        // Close the connections properly on CTRL+C
+        let signals = signal::ProcessSignals::from_registry();
+        signals.do_send(signal::Subscribe(ctx.address().recipient()));
+        ctx.add_message_stream(pause_signals());
+        ctx.add_message_stream(inspect_signals());
 
         // Start the User actor
-        let server = ctx.address();
-        let user = Arbiter::start(|_| User::new(server));
-        self.user = Some(user);
+        if !self.config.no_stdin {
+            let server = ctx.address();
+            let config = self.config.clone();
+            let user = Arbiter::start(move |_| User::new(server, Input::Stdin, &config));
+            self.user = Some(user);
+        }
 
         println!("Running as server");
-        if let Ok(addr) = addr {
+        for addr in &self.addrs {
             println!("Listening on {}", addr);
-        };
+        }
+        for addr in self.retrying.clone() {
+            self.retry_bind(addr, ctx);
+        }
+
+        if let Some(interval) = self.config.status_interval {
+            ctx.run_interval(interval, move |server, _| server.print_status(interval));
+        }
+
+        if let Some(duration) = self.config.duration {
+            ctx.run_later(duration, move |server, _| {
+                println!("Session of {}s over", duration.as_secs());
+                server.shutdown("session over");
+            });
+        }
+
+        if let Some(port) = self.config.metrics_port {
+            match metrics::serve(port) {
+                Ok(addr) => println!("Metrics on http://{}/metrics", addr),
+                Err(e) => println!("Can not serve the metrics: {}", e)
+            }
+        }
+
+        if let Some(ref path) = self.config.admin_socket {
+            match admin::serve(path, ctx.address().recipient()) {
+                Ok(()) => {
+                    println!("Admin commands on {}", path.display());
+                    self.admin_socket = Some(path.clone());
+                }
+                Err(e) => println!("Can not listen on {}: {}", path.display(), e)
+            }
+        }
     }
 }
 
@@ -85,27 +595,484 @@ impl Handler<TcpConnect> for Server {
         // A new connection is established.
         // Create a Peer from it and add it to self.peers
         let socket: TcpStream = tcp.0;
+
+        if let Ok(addr) = socket.peer_addr() {
+            if !cidr::is_allowed(addr.ip(), &self.config.allow, &self.config.deny) {
+                // Dropping the socket closes the connection
+                println!("Rejected connection from {}", addr);
+                self.log_event(&Event::Rejected { addr });
+                return;
+            }
+        }
         socket.set_nodelay(true).ok();
-        self.peers.push(Peer::new(self.config.clone(), ctx.address(), socket));
+        if let Some(idle) = self.config.tcp_keepalive {
+            if let Err(e) = socket.set_keepalive(Some(idle)) {
+                println!("Can not set TCP keepalive: {}", e);
+            }
+        }
+        Metrics::add(&METRICS.connections, 1);
+        let id = self.next_id;
+        self.next_id += 1;
+        let addr = socket.peer_addr().ok();
+
+        // Address of the client, if it's already connected
+        let duplicate = match (self.config.one_per_ip, addr) {
+            (Some(duplicate), Some(addr)) => {
+                let connected = self.peers.iter().any(|&(_, _, other)| {
+                    other.map(|other| duplicate.matches(addr, other)).unwrap_or(false)
+                });
+                if connected { Some(addr) } else { None }
+            }
+            _ => None
+        };
+
+        let peer = Peer::new(self.config.clone(), ctx.address(), socket, id);
+        if self.paused {
+            peer.do_send(Pause::Toggle);
+        }
+
+        let newcomer = (id, peer, addr);
+        if let Some(addr) = duplicate {
+            // The client is told why before the connection is closed
+            println!("Rejected another connection from {}", addr.ip());
+            self.log_event(&Event::Rejected { addr });
+            newcomer.1.do_send(Disconnect("already connected from this address".to_owned()));
+        } else {
+            self.log_event(&Event::Connect { id, addr });
+
+            if let Some(ref banner) = self.config.banner {
+                self.relay_to(&newcomer, Kind::Banner, Bytes::from(&banner[..]));
+            }
+            // Give the newcomer some context
+            for message in &self.history {
+                self.relay_to(&newcomer, Kind::History, message.clone());
+            }
+        }
+
+        self.peers.push(newcomer);
+        self.connected_at.insert(id, Instant::now());
+    }
+}
+
+impl Handler<AdminCommand> for Server {
+    type Result = MessageResult<AdminCommand>;
+
+    fn handle(&mut self, command: AdminCommand, _ctx: &mut Context<Self>) -> Self::Result {
+        println!("Admin command: {:?}", command);
+        MessageResult(match command {
+            AdminCommand::Shutdown => {
+                self.shutdown("server shutdown");
+                "Shutting down".to_owned()
+            }
+            AdminCommand::Status => {
+                let mut status = format!("{} peers connected", self.peers.len());
+                for &(id, _, addr) in &self.peers {
+                    let addr = addr.map(|addr| addr.to_string()).unwrap_or_else(|| "unknown".to_owned());
+                    let uptime = self.connected_at.get(&id).map(|at| format_uptime(at.elapsed())).unwrap_or_default();
+                    status.push_str(&format!("\n{} {} up {}", self.nick(id), addr, uptime));
+                }
+                status
+            }
+            AdminCommand::Kick(target) => {
+                let kicked: Vec<_> = self.peers.iter().filter(|&&(_, _, addr)| {
+                    addr.map_or(false, |addr| addr_matches(addr, &target))
+                }).collect();
+                for (_, peer, _) in &kicked {
+                    peer.do_send(Disconnect("kicked by the administrator".to_owned()));
+                }
+                match kicked.len() {
+                    0 => format!("No peer connected from {}", target),
+                    n => format!("Kicked {} peers", n)
+                }
+            }
+        })
+    }
+}
+
+impl Handler<GetBoundAddr> for Server {
+    type Result = MessageResult<GetBoundAddr>;
+
+    fn handle(&mut self, _: GetBoundAddr, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.addrs.clone())
     }
 }
 
 impl Handler<UserInput> for Server {
-    type Result = ();
+    type Result = ResponseFuture<(), ()>;
+
+    /// Resolves once all the peers queued the input
+    ///
+    /// The peers share the input. A peer whose mailbox is full is waited
+    /// for, so the [`User`] stops reading until it catches up. So are
+    /// the peers while paused, until the resume.
+    fn handle(&mut self, input: UserInput, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(command) = input.command() {
+            self.run_command(command);
+            return Box::new(future::ok(()));
+        }
+        self.remember(Bytes::from(&input.0[..]));
 
-    fn handle(&mut self, input: UserInput, _ctx: &mut Context<Self>) {
         // Send the user input to all connected peers
+        let full: Vec<_> = self.peers.iter().filter_map(|(_, peer, _)| {
+            let input = if self.paused {
+                input.clone()
+            } else {
+                match peer.try_send(input.clone()) {
+                    Ok(()) => return None,
+                    Err(SendError::Full(input)) => input,
+                    // A closed peer doesn't hold back the others
+                    Err(SendError::Closed(_)) => return None
+                }
+            };
+            Some(peer.send(input).then(|_| Ok(())))
+        }).collect();
+
+        Box::new(future::join_all(full).map(|_| ()))
+    }
+}
+
+impl Handler<InputClosed> for Server {
+    type Result = ();
+
+    fn handle(&mut self, _: InputClosed, _ctx: &mut Context<Self>) {
+        // Keep accepting connections and receiving messages
+        self.user = None;
+        println!("Input closed, the session remains open (CTRL+C to quit)\n");
+    }
+}
+
+impl Handler<Typing> for Server {
+    type Result = ();
+
+    fn handle(&mut self, _: Typing, _ctx: &mut Context<Self>) {
+        if !self.config.typing_indicators || !self.typing.ready() {
+            return;
+        }
         for peer in &self.peers {
-            peer.do_send(input.clone());
-        };
+            self.relay_to(peer, Kind::Typing, Bytes::new());
+        }
+    }
+}
+
+impl Handler<Relay> for Server {
+    type Result = ();
+
+    fn handle(&mut self, relay: Relay, _ctx: &mut Context<Self>) {
+        match relay.kind {
+            Kind::Data if self.filtered(&relay.payload) => {
+                println!("Message from {} dropped by --filter", self.nick(relay.id));
+            }
+            Kind::Data => {
+                // Relayed and replayed to the newcomers with who sent it
+                let mut message = format!("{}: ", self.nick(relay.id)).into_bytes();
+                message.extend_from_slice(&relay.payload);
+                let message = Bytes::from(message);
+                let mut delivered = false;
+                for peer in self.peers.iter().filter(|(id, _, _)| *id != relay.id) {
+                    delivered |= self.relay_to(peer, Kind::Data, message.clone());
+                }
+                // The sender learns its message reached someone
+                if let (true, Some(sender)) = (delivered, self.peers.iter().find(|(id, _, _)| *id == relay.id)) {
+                    self.relay_to(sender, Kind::Ack, relay.payload.clone());
+                }
+                self.remember(message);
+            }
+            Kind::Typing if self.config.typing_indicators => {
+                // Send to every peer except the one it comes from
+                let nick = Bytes::from(self.nick(relay.id));
+                for peer in self.peers.iter().filter(|(id, _, _)| *id != relay.id) {
+                    self.relay_to(peer, relay.kind, nick.clone());
+                }
+            }
+            Kind::Hello => {
+                let asked = String::from_utf8_lossy(&relay.payload).into_owned();
+                let nick = self.register_nick(relay.id, &asked);
+                let addr = match self.peers.iter().find(|&&(id, _, _)| id == relay.id) {
+                    Some(peer) => {
+                        // The client learns the name it got
+                        self.relay_to(peer, Kind::Hello, Bytes::from(nick.as_str()));
+                        peer.2
+                    }
+                    None => None
+                };
+                println!("{} joined", nick);
+                self.log_event(&Event::Nick { id: relay.id, addr, nick: &nick });
+            }
+            _ => ()
+        }
     }
 }
 
 impl Handler<PeerClose> for Server {
     type Result = ();
 
-    fn handle(&mut self, _: PeerClose, _ctx: &mut Context<Self>) {
+    fn handle(&mut self, close: PeerClose, _ctx: &mut Context<Self>) {
         // A connection has been close, clean self.peers
-        self.peers.retain(Addr::connected);
+        self.peers.retain(|(_, peer, _)| peer.connected());
+        self.log_event(&Event::Disconnect {
+            id: close.id,
+            addr: close.addr,
+            reason: close.reason.as_deref()
+        });
+        let nick = self.nick(close.id);
+        self.nicks.remove(&close.id);
+        self.connected_at.remove(&close.id);
+        let uptime = format_uptime(close.uptime);
+        match close.reason {
+            Some(reason) => println!("{} left after {}: {}", nick, uptime, reason),
+            None => println!("{} left after {}", nick, uptime)
+        }
+        self.stats.add(&close.stats);
+        println!("Total stats: {}", self.stats);
+
+        if self.closing && self.peers.is_empty() {
+            System::current().stop();
+        }
+    }
+}
+
+impl Handler<Pause> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Pause, _ctx: &mut Context<Self>) {
+        self.paused = msg == Pause::Toggle && !self.paused;
+        for (_, peer, _) in &self.peers {
+            peer.do_send(msg);
+        }
+    }
+}
+
+impl Handler<Inspect> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Inspect, _ctx: &mut Context<Self>) {
+        for (_, peer, _) in &self.peers {
+            peer.do_send(msg);
+        }
+    }
+}
+
+impl Handler<signal::Signal> for Server {
+    type Result = ();
+
+    fn handle(&mut self, sig: signal::Signal, _ctx: &mut Context<Self>) {
+        match sig.0 {
+            signal::SignalType::Int
+            | signal::SignalType::Term
+            | signal::SignalType::Quit => self.shutdown("server shutdown"),
+            signal::SignalType::Hup => self.reload(),
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+    use actix::prelude::*;
+    use bytes::Bytes;
+    use futures::Future;
+    use peer::Relay;
+    use reader::Kind;
+    use super::{addr_matches, parse_listen_fds, status_line, GetBoundAddr, Server};
+
+    #[test]
+    fn ephemeral_port() {
+        System::run(|| {
+            let config = ::config_from(&::app().get_matches_from(vec!["chat", "--port", "0"]));
+            let server = Server::new(config).unwrap().start();
+
+            Arbiter::spawn(server.send(GetBoundAddr).then(|addrs| {
+                let addrs = addrs.unwrap();
+                assert_eq!(addrs.len(), 1);
+                assert_ne!(addrs[0].port(), 0);
+                System::current().stop();
+                Ok(())
+            }));
+        });
+    }
+
+    #[test]
+    fn filtered_messages_not_kept() {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        System::run(move || {
+            let args = vec!["chat", "--port", "0", "--no-stdin", "--history", "10", "--filter", "(?i)spam"];
+            let server = Server::new(::config_from(&::app().get_matches_from(args))).unwrap();
+            let port = server.bound_addrs()[0].port();
+            let server = server.start();
+            for &payload in &["buy SPAM", "hello"] {
+                server.do_send(Relay { id: 0, kind: Kind::Data, payload: Bytes::from(payload) });
+            }
+
+            // A newcomer gets the history
+            let system = System::current();
+            thread::spawn(move || {
+                let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                let mut received = Vec::new();
+                let mut buffer = [0; 1024];
+                while !String::from_utf8_lossy(&received).contains("hello") {
+                    match socket.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => received.extend_from_slice(&buffer[..n])
+                    }
+                }
+                tx.send(received).unwrap();
+                system.stop();
+            });
+        });
+
+        let history = rx.recv().unwrap();
+        let history = String::from_utf8_lossy(&history);
+        assert!(history.contains("guest0: hello"), "{:?}", history);
+        assert!(!history.contains("SPAM"), "{:?}", history);
+    }
+
+    #[test]
+    fn long_history_replayed() {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        System::run(move || {
+            let args = vec!["chat", "--port", "0", "--no-stdin", "--history", "40"];
+            let server = Server::new(::config_from(&::app().get_matches_from(args))).unwrap();
+            let port = server.bound_addrs()[0].port();
+            let server = server.start();
+            for n in 0..40 {
+                server.do_send(Relay { id: 0, kind: Kind::Data, payload: Bytes::from(format!("message {}.", n)) });
+            }
+
+            // More than the default mailbox of the newcomer
+            let system = System::current();
+            thread::spawn(move || {
+                let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                let mut received = Vec::new();
+                let mut buffer = [0; 1024];
+                while !String::from_utf8_lossy(&received).contains("message 39.") {
+                    match socket.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => received.extend_from_slice(&buffer[..n])
+                    }
+                }
+                tx.send(received).unwrap();
+                system.stop();
+            });
+        });
+
+        let history = rx.recv().unwrap();
+        let history = String::from_utf8_lossy(&history);
+        for n in 0..40 {
+            assert!(history.contains(&format!("message {}.", n)), "{:?}", history);
+        }
+    }
+
+    #[test]
+    fn relayed_message_acked() {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        System::run(move || {
+            let args = vec!["chat", "--port", "0", "--no-stdin"];
+            let server = Server::new(::config_from(&::app().get_matches_from(args))).unwrap();
+            let port = server.bound_addrs()[0].port();
+            let server = server.start();
+
+            let system = System::current();
+            thread::spawn(move || {
+                let read = |socket: &mut TcpStream, until: &str| {
+                    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                    let mut received = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !String::from_utf8_lossy(&received).contains(until) {
+                        match socket.read(&mut buffer) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => received.extend_from_slice(&buffer[..n])
+                        }
+                    }
+                    String::from_utf8_lossy(&received).into_owned()
+                };
+                let mut sender = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                thread::sleep(Duration::from_millis(100));
+                let mut other = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                thread::sleep(Duration::from_millis(100));
+
+                server.do_send(Relay { id: 0, kind: Kind::Data, payload: Bytes::from("hello") });
+                let relayed = read(&mut other, "hello");
+                let acked = read(&mut sender, "hello");
+                tx.send((relayed, acked)).unwrap();
+                system.stop();
+            });
+        });
+
+        // The sender gets its own message back, without its name
+        let (relayed, acked) = rx.recv().unwrap();
+        assert!(relayed.contains("guest0: hello"), "{:?}", relayed);
+        assert!(acked.contains("hello") && !acked.contains("guest0"), "{:?}", acked);
+    }
+
+    #[test]
+    fn filtered_messages_not_relayed() {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        System::run(move || {
+            let args = vec!["chat", "--port", "0", "--no-stdin", "--filter", "(?i)spam"];
+            let server = Server::new(::config_from(&::app().get_matches_from(args))).unwrap();
+            let port = server.bound_addrs()[0].port();
+            let server = server.start();
+
+            let system = System::current();
+            thread::spawn(move || {
+                let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                // Once the server has the connection, from another peer
+                thread::sleep(Duration::from_millis(100));
+                for &payload in &["buy SPAM", "hello"] {
+                    server.do_send(Relay { id: 42, kind: Kind::Data, payload: Bytes::from(payload) });
+                }
+                let mut received = Vec::new();
+                let mut buffer = [0; 1024];
+                while !String::from_utf8_lossy(&received).contains("hello") {
+                    match socket.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => received.extend_from_slice(&buffer[..n])
+                    }
+                }
+                tx.send(received).unwrap();
+                system.stop();
+            });
+        });
+
+        let relayed = rx.recv().unwrap();
+        let relayed = String::from_utf8_lossy(&relayed);
+        assert!(relayed.contains("guest42: hello"), "{:?}", relayed);
+        assert!(!relayed.contains("SPAM"), "{:?}", relayed);
+    }
+
+    #[test]
+    fn listen_fds() {
+        assert_eq!(parse_listen_fds("42", "2", 42), Some(2));
+        // For another process
+        assert_eq!(parse_listen_fds("41", "2", 42), None);
+        assert_eq!(parse_listen_fds("42", "0", 42), None);
+        assert_eq!(parse_listen_fds("42", "", 42), None);
+    }
+
+    #[test]
+    fn kick_targets() {
+        let addr = "10.0.0.1:4000".parse().unwrap();
+        assert!(addr_matches(addr, "10.0.0.1"));
+        assert!(addr_matches(addr, "10.0.0.1:4000"));
+        assert!(!addr_matches(addr, "10.0.0.1:4001"));
+        assert!(!addr_matches(addr, "10.0.0.2"));
+
+        let addr = "[::1]:4000".parse().unwrap();
+        assert!(addr_matches(addr, "::1"));
+        assert!(addr_matches(addr, "[::1]:4000"));
+    }
+
+    #[test]
+    fn status_line_format() {
+        assert_eq!(status_line(3, 12.5, 1024, 2048, 307.25),
+                   "status connections=3 messages_per_sec=12.50 bytes_sent=1024 bytes_received=2048 bytes_per_sec=307.25");
     }
 }
@@ -0,0 +1,328 @@
+//! SOCKS5 client, to connect to the server through a proxy with
+//! `--proxy`
+//!
+//! Only the CONNECT command is supported, without authentication or
+//! with a username and a password (RFC 1928 and RFC 1929).
+//!
+//! The handshake is done on the connected socket, before it's given
+//! to the [`Peer`](::peer::Peer).
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use futures::{future, Future};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, write_all};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const USER_PASSWORD_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A SOCKS5 proxy
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    /// Host of the proxy
+    pub host: String,
+    /// Port of the proxy
+    pub port: u16,
+    /// Username and password, with `--proxy-auth`
+    pub auth: Option<(String, String)>
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    /// Parse `host:port`
+    fn from_str(s: &str) -> Result<Proxy, String> {
+        let mut parts = s.rsplitn(2, ':');
+        let port = parts.next().and_then(|port| u16::from_str(port).ok());
+        match (parts.next(), port) {
+            (Some(host), Some(port)) if !host.is_empty() => Ok(Proxy {
+                host: host.trim_matches(&['[', ']'][..]).to_owned(),
+                port,
+                auth: None
+            }),
+            _ => Err("Should be host:port".to_owned())
+        }
+    }
+}
+
+/// Parse `user:password`
+pub fn parse_auth(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(user), Some(password)) if user.len() <= 255 && password.len() <= 255 => {
+            Ok((user.to_owned(), password.to_owned()))
+        }
+        _ => Err("Should be user:password, each at most 255 bytes".to_owned())
+    }
+}
+
+/// Errors of the SOCKS5 handshake
+#[derive(Debug)]
+pub enum SocksError {
+    /// The connection with the proxy failed
+    IO(io::Error),
+    /// The proxy doesn't speak SOCKS5
+    Version(u8),
+    /// The proxy accepts none of our authentication methods
+    NoMethod,
+    /// The username or password was refused
+    AuthFailed,
+    /// The host name is longer than 255 bytes
+    HostTooLong,
+    /// The proxy couldn't connect to the server, with the reply code
+    Refused(u8)
+}
+
+impl From<io::Error> for SocksError {
+    fn from(e: io::Error) -> SocksError {
+        SocksError::IO(e)
+    }
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SocksError::IO(ref e) => write!(f, "{}", e),
+            SocksError::Version(version) => write!(f, "not a SOCKS5 proxy (version {})", version),
+            SocksError::NoMethod => write!(f, "the proxy requires another authentication"),
+            SocksError::AuthFailed => write!(f, "authentication refused by the proxy"),
+            SocksError::HostTooLong => write!(f, "host name too long"),
+            SocksError::Refused(code) => write!(f, "the proxy can not connect: {}", reply_message(code))
+        }
+    }
+}
+
+impl Error for SocksError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SocksError::IO(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Message of a reply code of the proxy
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error"
+    }
+}
+
+/// Future of the handshake, giving the stream back once connected
+pub type Handshake<S> = Box<dyn Future<Item = S, Error = SocksError>>;
+
+/// Ask the proxy at the other end of `stream` to connect to
+/// `host:port`
+///
+/// `host` is resolved by the proxy unless it's an IP address. Once the
+/// future resolves, `stream` is connected to the server.
+pub fn handshake<S>(stream: S, host: &str, port: u16, auth: Option<&(String, String)>) -> Handshake<S>
+where
+    S: AsyncRead + AsyncWrite + 'static
+{
+    let request = match connect_request(host, port) {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::err(e))
+    };
+
+    // Methods we accept
+    let method = if auth.is_some() { USER_PASSWORD } else { NO_AUTH };
+    let auth = auth.cloned();
+    let authenticated = write_all(stream, [VERSION, 1, method])
+        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+        .map_err(SocksError::from)
+        .and_then(move |(stream, reply)| -> Handshake<S> {
+            if reply[0] != VERSION {
+                return Box::new(future::err(SocksError::Version(reply[0])));
+            }
+            match (reply[1], auth) {
+                (NO_ACCEPTABLE_METHOD, _) => Box::new(future::err(SocksError::NoMethod)),
+                (USER_PASSWORD, Some((user, password))) => {
+                    let mut request = vec![USER_PASSWORD_VERSION, user.len() as u8];
+                    request.extend_from_slice(user.as_bytes());
+                    request.push(password.len() as u8);
+                    request.extend_from_slice(password.as_bytes());
+                    Box::new(write_all(stream, request)
+                        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+                        .map_err(SocksError::from)
+                        .and_then(|(stream, reply)| match reply[1] {
+                            0 => Ok(stream),
+                            _ => Err(SocksError::AuthFailed)
+                        }))
+                }
+                (NO_AUTH, _) => Box::new(future::ok(stream)),
+                _ => Box::new(future::err(SocksError::NoMethod))
+            }
+        });
+
+    Box::new(authenticated
+        .and_then(|stream| {
+            write_all(stream, request)
+                .and_then(|(stream, _)| read_exact(stream, [0; 4]))
+                .map_err(SocksError::from)
+        })
+        .and_then(|(stream, header)| -> Box<dyn Future<Item = (S, usize), Error = SocksError>> {
+            if header[0] != VERSION {
+                return Box::new(future::err(SocksError::Version(header[0])));
+            }
+            if header[1] != 0 {
+                return Box::new(future::err(SocksError::Refused(header[1])));
+            }
+            // Skip the address the proxy bound, and its port
+            match header[3] {
+                IPV4 => Box::new(future::ok((stream, 4))),
+                IPV6 => Box::new(future::ok((stream, 16))),
+                _ => Box::new(read_exact(stream, [0; 1])
+                              .map(|(stream, len)| (stream, len[0] as usize))
+                              .map_err(SocksError::from))
+            }
+        })
+        .and_then(|(stream, len)| {
+            read_exact(stream, vec![0; len + 2])
+                .map(|(stream, _)| stream)
+                .map_err(SocksError::from)
+        }))
+}
+
+/// Return the CONNECT request to `host:port`
+fn connect_request(host: &str, port: u16) -> Result<Vec<u8>, SocksError> {
+    let mut request = vec![VERSION, CONNECT, 0];
+    match IpAddr::from_str(host) {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() > 255 => return Err(SocksError::HostTooLong),
+        Err(_) => {
+            request.push(DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&[(port >> 8) as u8, port as u8]);
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{self, Cursor, Read, Write};
+    use futures::{Async, Future, Poll};
+    use super::{handshake, parse_auth, Proxy, SocksError};
+
+    /// A proxy replying with `replies`, keeping what we wrote
+    #[derive(Debug)]
+    struct Scripted {
+        replies: Cursor<Vec<u8>>,
+        written: Vec<u8>
+    }
+
+    impl Scripted {
+        fn new(replies: &[u8]) -> Scripted {
+            Scripted { replies: Cursor::new(replies.to_vec()), written: Vec::new() }
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ::tokio_io::AsyncRead for Scripted {}
+
+    impl ::tokio_io::AsyncWrite for Scripted {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn parse_proxy() {
+        let proxy: Proxy = "localhost:1080".parse().unwrap();
+        assert_eq!((proxy.host.as_str(), proxy.port), ("localhost", 1080));
+        let proxy: Proxy = "[::1]:1080".parse().unwrap();
+        assert_eq!(proxy.host, "::1");
+        assert!("localhost".parse::<Proxy>().is_err());
+        assert!(":1080".parse::<Proxy>().is_err());
+
+        assert_eq!(parse_auth("user:pass:word").unwrap(), ("user".to_owned(), "pass:word".to_owned()));
+        assert!(parse_auth("user").is_err());
+    }
+
+    #[test]
+    fn connect_to_a_domain() {
+        let proxy = Scripted::new(&[5, 0, 5, 0, 0, 1, 127, 0, 0, 1, 0x04, 0x38]);
+
+        let proxy = handshake(proxy, "example.com", 12345, None).wait().unwrap();
+
+        let mut expected = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[0x30, 0x39]);
+        assert_eq!(proxy.written, expected);
+    }
+
+    #[test]
+    fn user_password() {
+        let auth = ("me".to_owned(), "pw".to_owned());
+        let proxy = Scripted::new(&[5, 2, 1, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let proxy = handshake(proxy, "10.0.0.1", 80, Some(&auth)).wait().unwrap();
+
+        assert_eq!(proxy.written, vec![5, 1, 2, 1, 2, b'm', b'e', 2, b'p', b'w',
+                                       5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+
+        let proxy = Scripted::new(&[5, 2, 1, 1]);
+        match handshake(proxy, "10.0.0.1", 80, Some(&auth)).wait() {
+            Err(SocksError::AuthFailed) => {}
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn refused() {
+        let proxy = Scripted::new(&[5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        match handshake(proxy, "10.0.0.1", 80, None).wait() {
+            Err(SocksError::Refused(5)) => {}
+            other => panic!("{:?}", other)
+        }
+
+        let proxy = Scripted::new(&[5, 0xFF]);
+        match handshake(proxy, "10.0.0.1", 80, None).wait() {
+            Err(SocksError::NoMethod) => {}
+            other => panic!("{:?}", other)
+        }
+    }
+}
@@ -0,0 +1,426 @@
+//! End-to-end tests, with a server and a client in the same process
+//!
+//! The input is sent to the actors with [`UserInput`] instead of being
+//! read from stdin, and what was received is read back from the
+//! `--rtt-csv` and `--event-log` files. The clients are disconnected
+//! once the [`Hooks`] or the event log show what the test waits for,
+//! not after a fixed delay.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use actix::prelude::*;
+use actix::actors::signal;
+use futures::Future;
+
+use admin::AdminCommand;
+use client::Client;
+use config::ConfigBuilder;
+use peer::{Hooks, Received, Roundtrip};
+use reader::Kind;
+use server::{GetBoundAddr, Server};
+use user::UserInput;
+use {app, check, config_from, ExitCode};
+
+/// Time after which a test gives up waiting, its assertions tell what
+/// is missing
+const GUARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Path of a new temporary file, removed if it exists
+fn temp_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("chat-test-{}-{}", process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// What to wait for before disconnecting the clients
+#[derive(Clone)]
+enum Until {
+    /// Messages received by the server, from all the clients
+    Received(usize),
+    /// Roundtrips measured by the clients
+    Roundtrips(usize),
+    /// Lines of the event log containing a text
+    Logged(PathBuf, &'static str, usize)
+}
+
+/// What the hooks were notified of
+#[derive(Default)]
+struct Notified {
+    /// Payloads received by the server
+    received: Vec<(Kind, Vec<u8>)>,
+    /// Lengths of the messages of the roundtrips measured by the
+    /// clients, with the payloads of their responses
+    roundtrips: Vec<(usize, Vec<u8>)>
+}
+
+/// Actor given as hooks to the server and the clients
+///
+/// Once `until` is reached, or after [`GUARD_TIMEOUT`], the clients are
+/// disconnected. Without clients, the system is stopped.
+struct Observer {
+    notified: Arc<Mutex<Notified>>,
+    until: Until,
+    /// Given with [`Watch`], `None` once disconnected
+    clients: Option<Vec<Addr<Client>>>,
+    done: bool
+}
+
+impl Observer {
+    fn new(notified: Arc<Mutex<Notified>>, until: Until) -> Observer {
+        Observer { notified, until, clients: None, done: false }
+    }
+}
+
+/// Clients the [`Observer`] disconnects, it waits for them before
+/// checking `until`
+#[derive(Message)]
+struct Watch(Vec<Addr<Client>>);
+
+impl Observer {
+    fn reached(&self) -> bool {
+        let notified = self.notified.lock().unwrap();
+        match self.until {
+            Until::Received(n) => notified.received.len() >= n,
+            Until::Roundtrips(n) => notified.roundtrips.len() >= n,
+            Until::Logged(ref path, text, n) => {
+                let content = fs::read_to_string(path).unwrap_or_default();
+                content.lines().filter(|line| line.contains(text)).count() >= n
+            }
+        }
+    }
+
+    /// Disconnect the clients once `until` is reached
+    fn check(&mut self) {
+        if !self.done && self.clients.is_some() && self.reached() {
+            self.finish();
+        }
+    }
+
+    /// Disconnect the clients, they stop the system once closed. Stop
+    /// it now without clients, or when called again
+    fn finish(&mut self) {
+        self.done = true;
+        match self.clients.take() {
+            Some(ref clients) if !clients.is_empty() => {
+                for client in clients {
+                    client.do_send(signal::Signal(signal::SignalType::Int));
+                }
+            }
+            _ => System::current().stop()
+        }
+    }
+}
+
+impl Actor for Observer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_later(GUARD_TIMEOUT, |observer, ctx| {
+            observer.finish();
+            ctx.run_later(Duration::from_secs(1), |_, _| System::current().stop());
+        });
+        if let Until::Logged(..) = self.until {
+            ctx.run_interval(Duration::from_millis(10), |observer, _| observer.check());
+        }
+    }
+}
+
+impl Handler<Watch> for Observer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, _ctx: &mut Context<Self>) {
+        self.clients = Some(msg.0);
+        self.check();
+    }
+}
+
+impl Handler<Received> for Observer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Received, _ctx: &mut Context<Self>) {
+        self.notified.lock().unwrap().received.push((msg.kind, msg.payload.to_vec()));
+        self.check();
+    }
+}
+
+impl Handler<Roundtrip> for Observer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Roundtrip, _ctx: &mut Context<Self>) {
+        self.notified.lock().unwrap().roundtrips.push((msg.len, msg.response.to_vec()));
+        self.check();
+    }
+}
+
+/// Start a server with `server_args` and a client with `client_args`,
+/// send `inputs` from the client and disconnect it once `until` is
+/// reached
+///
+/// Return what the hooks were notified of, once the connection is
+/// closed.
+fn run(server_args: &[&str], client_args: &[&str], inputs: Vec<Vec<u8>>, until: Until) -> Notified {
+    run_clients(server_args, client_args, 1, inputs, until)
+}
+
+/// Same as [`run`], with `clients` clients sending the same `inputs`
+///
+/// The server runs in its own system, on another thread: it sees the
+/// clients disconnect after they stopped theirs. It's stopped once it
+/// has no peers left.
+fn run_clients(server_args: &[&str], client_args: &[&str], clients: usize, inputs: Vec<Vec<u8>>,
+               until: Until) -> Notified {
+    let mut args = vec!["chat", "--port", "0", "--no-stdin"];
+    args.extend_from_slice(server_args);
+    let mut server_config = config_from(&app().get_matches_from(args));
+    let client_args: Vec<String> = client_args.iter().map(|&arg| arg.to_owned()).collect();
+    let notified = Arc::new(Mutex::new(Notified::default()));
+    let observer_notified = notified.clone();
+    let (server_tx, server_rx) = mpsc::channel();
+    let started = Arc::new(Mutex::new(None));
+    let server_started = started.clone();
+
+    System::run(move || {
+        let observer = Observer::new(observer_notified, until).start();
+        server_config.hooks = Hooks { received: Some(observer.clone().recipient()), ..Hooks::default() };
+
+        thread::spawn(move || {
+            System::run(move || {
+                let server = Server::new(server_config).unwrap();
+                let port = server.bound_addrs()[0].port();
+                server_tx.send((System::current(), server.start(), port)).unwrap();
+            });
+        });
+        let (system, server, port) = server_rx.recv().unwrap();
+
+        let port = port.to_string();
+        let mut args = vec!["chat", "--client", "--port", &port, "--no-stdin"];
+        args.extend(client_args.iter().map(String::as_str));
+        let mut config = config_from(&app().get_matches_from(args));
+        config.hooks = Hooks { roundtrip: Some(observer.clone().recipient()), ..Hooks::default() };
+
+        let clients: Vec<_> = (0..clients).map(|_| {
+            let client = Client::new(config.clone()).start();
+            // Sent once connected
+            for input in &inputs {
+                client.do_send(UserInput::new(input.clone()));
+            }
+            client
+        }).collect();
+        observer.do_send(Watch(clients));
+        *server_started.lock().unwrap() = Some((system, server));
+    });
+
+    // Stop the server once the clients are gone, or after the guard
+    let (system, server) = started.lock().unwrap().take().unwrap();
+    let deadline = Instant::now() + GUARD_TIMEOUT;
+    while Instant::now() < deadline {
+        match server.send(AdminCommand::Status).wait() {
+            Ok(ref status) if !status.starts_with("0 peers") => thread::sleep(Duration::from_millis(10)),
+            _ => break
+        }
+    }
+    system.stop();
+
+    let mut notified = notified.lock().unwrap();
+    ::std::mem::take(&mut *notified)
+}
+
+#[test]
+fn echo_roundtrip() {
+    let csv = temp_path("echo.csv");
+
+    let notified = run(&["--echo"], &["--rtt-csv", csv.to_str().unwrap()], vec![b"hello".to_vec()],
+                       Until::Roundtrips(1));
+    // The message comes back as its response
+    assert_eq!(notified.roundtrips, vec![(5, b"hello".to_vec())]);
+
+    let content = fs::read_to_string(&csv).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    let _ = fs::remove_file(&csv);
+
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert_eq!(lines[0], "timestamp,message_id,rtt_micros,payload_len");
+    // Message #0, 5 bytes
+    let fields: Vec<&str> = lines[1].split(',').collect();
+    assert_eq!(fields[1], "0");
+    assert_eq!(fields[3], "5");
+}
+
+#[test]
+fn server_events() {
+    let log = temp_path("events.log");
+
+    run(&["--event-log", log.to_str().unwrap()], &[],
+        vec![b"first".to_vec(), b"second message".to_vec()], Until::Received(2));
+
+    let content = fs::read_to_string(&log).unwrap();
+    let events: Vec<&str> = content.lines().collect();
+    let _ = fs::remove_file(&log);
+
+    assert_eq!(events.len(), 4, "{:?}", events);
+    assert!(events[0].contains(r#""event":"connect""#));
+    assert!(events[1].contains(r#""event":"message""#) && events[1].ends_with(r#""len":5}"#));
+    assert!(events[2].contains(r#""event":"message""#) && events[2].ends_with(r#""len":14}"#));
+    assert!(events[3].contains(r#""event":"disconnect""#));
+}
+
+#[test]
+fn one_shot_message() {
+    let csv = temp_path("one-shot.csv");
+
+    run(&["--echo"], &["--message", "hello", "--wait-response", "--rtt-csv", csv.to_str().unwrap()],
+        Vec::new(), Until::Roundtrips(1));
+
+    let content = fs::read_to_string(&csv).unwrap();
+    let _ = fs::remove_file(&csv);
+
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert!(lines[1].ends_with(",5"));
+}
+
+#[test]
+fn pending_messages_are_sent_before_closing() {
+    let log = temp_path("drain.log");
+
+    // About 1.5s to write everything, the client disconnects as soon
+    // as the first one is received
+    run(&["--event-log", log.to_str().unwrap()], &["--throttle", "2000"], vec![vec![0; 1000]; 3],
+        Until::Received(1));
+
+    let content = fs::read_to_string(&log).unwrap();
+    let _ = fs::remove_file(&log);
+
+    let messages = content.lines().filter(|event| event.contains(r#""event":"message""#)).count();
+    assert_eq!(messages, 3, "{}", content);
+}
+
+#[test]
+fn coalesced_inputs() {
+    let log = temp_path("coalesce.log");
+
+    // Sent together, within the window
+    let notified = run(&["--event-log", log.to_str().unwrap()], &["--coalesce", "100"],
+                       vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()], Until::Received(1));
+    assert_eq!(notified.received, vec![(Kind::Data, b"one\ntwo\nthree".to_vec())]);
+
+    let content = fs::read_to_string(&log).unwrap();
+    let _ = fs::remove_file(&log);
+
+    let messages: Vec<&str> = content.lines().filter(|event| event.contains(r#""event":"message""#)).collect();
+    assert_eq!(messages.len(), 1, "{}", content);
+    // "one\ntwo\nthree"
+    assert!(messages[0].ends_with(r#""len":13}"#), "{}", content);
+}
+
+#[test]
+fn send_limit() {
+    let log = temp_path("send-limit.log");
+
+    // The client disconnects by itself after the second one
+    run(&["--event-log", log.to_str().unwrap()], &["--send-limit", "2"],
+        vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()], Until::Received(3));
+
+    let content = fs::read_to_string(&log).unwrap();
+    let _ = fs::remove_file(&log);
+
+    let messages = content.lines().filter(|event| event.contains(r#""event":"message""#)).count();
+    assert_eq!(messages, 2, "{}", content);
+}
+
+#[test]
+fn raw_mode() {
+    let log = temp_path("raw.log");
+
+    run(&["--raw", "--event-log", log.to_str().unwrap()], &["--raw"], vec![b"hello".to_vec()],
+        Until::Received(1));
+
+    let content = fs::read_to_string(&log).unwrap();
+    let events: Vec<&str> = content.lines().collect();
+    let _ = fs::remove_file(&log);
+
+    assert_eq!(events.len(), 3, "{:?}", events);
+    assert!(events[1].contains(r#""event":"message""#) && events[1].ends_with(r#""len":5}"#));
+}
+
+#[test]
+fn hooks() {
+    let notified = Arc::new(Mutex::new(Notified::default()));
+    let observer_notified = notified.clone();
+
+    System::run(move || {
+        // Stops the system once the roundtrip is measured
+        let observer = Observer::new(observer_notified, Until::Roundtrips(1)).start();
+        observer.do_send(Watch(Vec::new()));
+
+        let hooks = Hooks { received: Some(observer.clone().recipient()), ..Hooks::default() };
+        let config = ConfigBuilder::new().port(0).no_stdin(true).hooks(hooks).build();
+        let server = Server::new(config).unwrap().start();
+
+        Arbiter::spawn(server.send(GetBoundAddr).then(move |addrs| {
+            let hooks = Hooks { roundtrip: Some(observer.recipient()), ..Hooks::default() };
+            let config = ConfigBuilder::new()
+                .client(true)
+                .port(addrs.unwrap()[0].port())
+                .no_stdin(true)
+                .hooks(hooks)
+                .build();
+
+            let client = Client::new(config).start();
+            client.do_send(UserInput::new(b"hello".to_vec()));
+            Ok(())
+        }));
+    });
+
+    let notified = notified.lock().unwrap();
+    assert_eq!(notified.received, vec![(Kind::Data, b"hello".to_vec())]);
+    assert_eq!(notified.roundtrips.iter().map(|r| r.0).collect::<Vec<_>>(), vec![5]);
+}
+
+#[test]
+fn one_per_ip() {
+    let log = temp_path("one-per-ip.log");
+
+    // Without --reconnect, the rejected client would exit the process
+    run_clients(&["--one-per-ip", "ip", "--event-log", log.to_str().unwrap()], &["--reconnect", "5"],
+                2, Vec::new(), Until::Logged(log.clone(), r#""event":"rejected""#, 1));
+
+    let content = fs::read_to_string(&log).unwrap();
+    let _ = fs::remove_file(&log);
+
+    let count = |event: &str| content.lines().filter(|line| line.contains(event)).count();
+    assert_eq!(count(r#""event":"connect""#), 1, "{}", content);
+    assert_eq!(count(r#""event":"rejected""#), 1, "{}", content);
+}
+
+#[test]
+fn nickname_collision() {
+    let log = temp_path("nick.log");
+
+    run_clients(&["--event-log", log.to_str().unwrap()], &["--nick", "alice"], 2, Vec::new(),
+                Until::Logged(log.clone(), r#""event":"nick""#, 2));
+
+    let content = fs::read_to_string(&log).unwrap();
+    let _ = fs::remove_file(&log);
+
+    let mut nicks: Vec<&str> = content.lines()
+        .filter(|line| line.contains(r#""event":"nick""#))
+        .map(|line| line.rsplit(r#""nick":"#).next().unwrap())
+        .collect();
+    nicks.sort();
+    assert_eq!(nicks, vec![r#""alice"}"#, r#""alice2"}"#], "{}", content);
+}
+
+#[test]
+fn check_exit_codes() {
+    assert_eq!(check(ConfigBuilder::new().port(0).build()), ExitCode::Success);
+
+    let used = ::std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let port = used.local_addr().unwrap().port();
+    assert_eq!(check(ConfigBuilder::new().port(port).build()), ExitCode::Bind);
+}
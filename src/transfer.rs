@@ -0,0 +1,656 @@
+//! Chunked file transfer
+//!
+//! A file is sent as a [`Kind::FileMeta`] message, a sequence of
+//! [`Kind::Chunk`] messages, then a [`Kind::ChunkEnd`] message.
+//!
+//! The metadata payload is made of:
+//! - Version of the layout, [`META_VERSION`]: u8
+//! - Transfer id: u32
+//! - Total size of the file: u64
+//! - Name of the file, in utf8
+//!
+//! A chunk payload is made of:
+//! - Transfer id: u32
+//! - Sequence number of the chunk, starting at 0: u32
+//! - Total size of the file: u64
+//! - Data of the chunk
+//!
+//! The end payload is made of:
+//! - Transfer id: u32
+//! - Number of chunks sent: u32
+//! - SHA-256 of the file: 32 bytes
+//!
+//! All integers are in network endian.
+//!
+//! The sender reads the file a chunk at a time, see [`Outgoing`], and
+//! computes its SHA-256 on the way.
+//!
+//! The receiver only accepts files with `--receive-files`, up to a
+//! number of bytes per connection. It writes them in `--download-dir`,
+//! under their name without the directories. A transfer without
+//! metadata is written in `transfer-<id>`.
+//!
+//! The receiver computes the SHA-256 of the chunks as they arrive and
+//! compares it with the one of the end. On mismatch, the file is
+//! removed.
+//!
+//! [`Kind::FileMeta`]: ../reader/enum.Kind.html#variant.FileMeta
+//! [`Kind::Chunk`]: ../reader/enum.Kind.html#variant.Chunk
+//! [`Kind::ChunkEnd`]: ../reader/enum.Kind.html#variant.ChunkEnd
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use bytes::{BufMut, BytesMut};
+use byteorder::{ByteOrder, NetworkEndian};
+use sha2::{Digest, Sha256};
+
+use reader::Kind;
+
+/// Size of the data sent in each chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length of the header of a chunk payload
+const CHUNK_HEADER_LEN: usize = 16;
+
+/// Length of a SHA-256
+pub const HASH_LEN: usize = 32;
+
+/// Length of the end payload
+const END_LEN: usize = 8 + HASH_LEN;
+
+/// Version of the metadata layout, a receiver rejects the others
+///
+/// Version 1 added the version and the SHA-256, version 2 moved the
+/// SHA-256 to the end payload.
+const META_VERSION: u8 = 2;
+
+/// Length of the header of a metadata payload
+const META_HEADER_LEN: usize = 13;
+
+/// Errors of a transfer being received
+#[derive(Debug)]
+pub enum TransferError {
+    /// The payload is too short to contain its header
+    Malformed,
+    /// The metadata has a layout we don't know
+    UnknownVersion(u8),
+    /// We don't take files, without `--receive-files`
+    Refused,
+    /// The file is larger than what `--receive-files` still allows
+    TooLarge { size: u64, left: u64 },
+    /// We received a chunk for a transfer we don't know
+    UnknownTransfer(u32),
+    /// The chunk doesn't have the expected sequence number
+    OutOfOrder { expected: u32, received: u32 },
+    /// The transfer ended but we didn't receive everything
+    Incomplete,
+    /// The size of the file changed during the transfer
+    WrongSize { expected: u64, received: u64 },
+    /// The SHA-256 of the received file isn't the one of the metadata
+    HashMismatch,
+    /// Error writing the file
+    IO(io::Error),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransferError::Malformed => write!(f, "malformed message"),
+            TransferError::UnknownVersion(version) => write!(f, "unknown metadata version {}", version),
+            TransferError::Refused => write!(f, "files are refused without --receive-files"),
+            TransferError::TooLarge { size, left } => {
+                write!(f, "the file is {} bytes, --receive-files allows {} more bytes", size, left)
+            }
+            TransferError::UnknownTransfer(id) => write!(f, "unknown transfer #{}", id),
+            TransferError::OutOfOrder { expected, received } => {
+                write!(f, "expected chunk {} but received chunk {}", expected, received)
+            }
+            TransferError::Incomplete => write!(f, "some chunks are missing"),
+            TransferError::WrongSize { expected, received } => {
+                write!(f, "expected a file of {} bytes but it is {} bytes", expected, received)
+            }
+            TransferError::HashMismatch => write!(f, "the SHA-256 of the file doesn't match"),
+            TransferError::IO(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Make the payload of a [`Kind::FileMeta`](../reader/enum.Kind.html) message
+pub fn meta(id: u32, total: u64, name: &str) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(META_HEADER_LEN + name.len());
+    buf.put_u8(META_VERSION);
+    buf.put_u32_be(id);
+    buf.put_u64_be(total);
+    buf.put_slice(name.as_bytes());
+    buf
+}
+
+/// Make the payload of a [`Kind::Chunk`](../reader/enum.Kind.html) message
+pub fn chunk(id: u32, seq: u32, total: u64, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(CHUNK_HEADER_LEN + data.len());
+    buf.put_u32_be(id);
+    buf.put_u32_be(seq);
+    buf.put_u64_be(total);
+    buf.put_slice(data);
+    buf
+}
+
+/// Make the payload of a [`Kind::ChunkEnd`](../reader/enum.Kind.html) message
+pub fn end(id: u32, chunks: u32, hash: &[u8; HASH_LEN]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(END_LEN);
+    buf.put_u32_be(id);
+    buf.put_u32_be(chunks);
+    buf.put_slice(hash);
+    buf
+}
+
+/// A file being sent, read a chunk at a time
+pub struct Outgoing {
+    /// Transfer id
+    id: u32,
+    /// The file, read up to where we are
+    file: File,
+    /// Size of the file
+    total: u64,
+    /// Sequence number of the next chunk
+    seq: u32,
+    /// SHA-256 of the chunks read so far
+    hasher: Sha256,
+    /// The end was returned, there is nothing left to send
+    done: bool
+}
+
+impl Outgoing {
+    /// Open the file at `path` for the transfer `id`, and return the
+    /// payload of its metadata
+    pub fn open(path: &Path, id: u32) -> io::Result<(Outgoing, BytesMut)> {
+        let file = File::open(path)?;
+        let total = file.metadata()?.len();
+        let name = path.file_name()
+                       .map(|name| name.to_string_lossy().into_owned())
+                       .unwrap_or_default();
+        let outgoing = Outgoing { id, file, total, seq: 0, hasher: Sha256::new(), done: false };
+        Ok((outgoing, meta(id, total, &name)))
+    }
+
+    /// Size of the file
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Read the next chunk of the file and return its message, the end
+    /// once the file is read, then `None`
+    pub fn next_message(&mut self) -> io::Result<Option<(Kind, BytesMut)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let n = self.file.read(&mut buffer)?;
+        if n == 0 {
+            self.done = true;
+            let hash: [u8; HASH_LEN] = self.hasher.clone().finalize().into();
+            return Ok(Some((Kind::ChunkEnd, end(self.id, self.seq, &hash))));
+        }
+        self.hasher.update(&buffer[..n]);
+        let chunk = chunk(self.id, self.seq, self.total, &buffer[..n]);
+        self.seq += 1;
+        Ok(Some((Kind::Chunk, chunk)))
+    }
+}
+
+/// Format a SHA-256 in hexadecimal
+pub fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A file being received
+struct Incoming {
+    /// Where the file is written
+    file: File,
+    /// Path of `file`
+    path: PathBuf,
+    /// Sequence number of the next chunk
+    next_seq: u32,
+    /// Number of bytes received so far
+    received: u64,
+    /// Size of the complete file
+    total: u64,
+    /// SHA-256 of the chunks received so far
+    hasher: Sha256,
+}
+
+/// Progress of a transfer after receiving a chunk
+pub struct Progress {
+    /// Transfer id
+    pub id: u32,
+    /// Percentage of the file received
+    pub percent: u64,
+    /// The transfer just started, and is written to `path`
+    pub started: Option<PathBuf>,
+}
+
+/// A transfer announced by its metadata
+pub struct Started {
+    /// Transfer id
+    pub id: u32,
+    /// Name of the file, as sent by the peer
+    pub name: String,
+    /// Size of the file
+    pub total: u64,
+    /// Where the file is written
+    pub path: PathBuf,
+}
+
+/// A transfer received completely
+pub struct Finished {
+    /// Transfer id
+    pub id: u32,
+    /// Where the file is written
+    pub path: PathBuf,
+    /// SHA-256 of the file, verified
+    pub hash: [u8; HASH_LEN],
+}
+
+/// Transfers being received from a peer, by transfer id
+pub struct Transfers {
+    /// Directory where the files are written
+    dir: PathBuf,
+    incoming: HashMap<u32, Incoming>,
+    /// Bytes the peer can send us in files, `None` refuses them
+    max_bytes: Option<u64>,
+    /// Bytes of the files accepted so far, the cancelled ones aside
+    accepted: u64,
+    /// Transfers refused, their chunks and end are ignored
+    refused: HashSet<u32>,
+}
+
+impl Transfers {
+    /// Receive files in `dir`, `max_bytes` at most in total
+    pub fn new(dir: PathBuf, max_bytes: Option<u64>) -> Transfers {
+        Transfers { dir, incoming: HashMap::new(), max_bytes, accepted: 0, refused: HashSet::new() }
+    }
+
+    /// Accept a transfer of `total` bytes, or refuse it
+    fn accept(&mut self, id: u32, total: u64) -> Result<(), TransferError> {
+        let error = match self.max_bytes.map(|max| max.saturating_sub(self.accepted)) {
+            None => TransferError::Refused,
+            Some(left) if total > left => TransferError::TooLarge { size: total, left },
+            Some(_) => {
+                self.accepted += total;
+                return Ok(());
+            }
+        };
+        self.refused.insert(id);
+        Err(error)
+    }
+
+    /// Create the file of a transfer from its metadata
+    pub fn meta(&mut self, payload: &[u8]) -> Result<Started, TransferError> {
+        if payload.len() < META_HEADER_LEN {
+            return Err(TransferError::Malformed);
+        }
+        if payload[0] != META_VERSION {
+            return Err(TransferError::UnknownVersion(payload[0]));
+        }
+
+        let id = NetworkEndian::read_u32(&payload[1..]);
+        let total = NetworkEndian::read_u64(&payload[5..]);
+        let name = String::from_utf8_lossy(&payload[META_HEADER_LEN..]).into_owned();
+
+        // A transfer reusing the id of an unfinished one replaces it
+        self.cancel(id);
+        self.refused.remove(&id);
+        self.accept(id, total)?;
+
+        let file_name = sanitize(&name).unwrap_or_else(|| format!("transfer-{}", id));
+        let (file, path) = create_file(&self.dir, &file_name).map_err(|e| {
+            self.accepted -= total;
+            TransferError::IO(e)
+        })?;
+
+        self.incoming.insert(id, Incoming {
+            file, path: path.clone(), next_seq: 0, received: 0, total, hasher: Sha256::new()
+        });
+        Ok(Started { id, name, total, path })
+    }
+
+    /// Write a received chunk in its file
+    ///
+    /// The first chunk of a transfer without metadata creates its file.
+    /// On error, the transfer is cancelled and its file removed. The
+    /// chunks of a refused transfer are ignored, `None` is returned.
+    pub fn chunk(&mut self, payload: &[u8]) -> Result<Option<Progress>, TransferError> {
+        if payload.len() < CHUNK_HEADER_LEN {
+            return Err(TransferError::Malformed);
+        }
+
+        let id = NetworkEndian::read_u32(&payload[0..]);
+        let seq = NetworkEndian::read_u32(&payload[4..]);
+        let total = NetworkEndian::read_u64(&payload[8..]);
+        let data = &payload[CHUNK_HEADER_LEN..];
+
+        let mut started = None;
+
+        if self.refused.contains(&id) {
+            return Ok(None);
+        }
+        if seq == 0 && !self.incoming.contains_key(&id) {
+            self.accept(id, total)?;
+            let name = format!("transfer-{}", id);
+            let (file, path) = create_file(&self.dir, &name).map_err(|e| {
+                self.accepted -= total;
+                TransferError::IO(e)
+            })?;
+            started = Some(path.clone());
+            self.incoming.insert(id, Incoming {
+                file, path, next_seq: 0, received: 0, total, hasher: Sha256::new()
+            });
+        }
+
+        let result = match self.incoming.get_mut(&id) {
+            None => return Err(TransferError::UnknownTransfer(id)),
+            Some(ref incoming) if incoming.next_seq != seq => {
+                Err(TransferError::OutOfOrder {
+                    expected: incoming.next_seq,
+                    received: seq
+                })
+            }
+            Some(ref incoming) if incoming.total != total => {
+                Err(TransferError::WrongSize {
+                    expected: incoming.total,
+                    received: total
+                })
+            }
+            Some(ref incoming) if incoming.received + data.len() as u64 > incoming.total => {
+                // Not more than announced, it was accepted for that size
+                Err(TransferError::WrongSize {
+                    expected: incoming.total,
+                    received: incoming.received + data.len() as u64
+                })
+            }
+            Some(incoming) => {
+                incoming.file.write_all(data).map(|_| {
+                    incoming.hasher.update(data);
+                    incoming.next_seq += 1;
+                    incoming.received += data.len() as u64;
+                    incoming.received * 100 / incoming.total.max(1)
+                }).map_err(TransferError::IO)
+            }
+        };
+
+        match result {
+            Ok(percent) => Ok(Some(Progress { id, percent: percent.min(100), started })),
+            Err(e) => {
+                self.cancel(id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Finish a transfer and verify its SHA-256
+    ///
+    /// On mismatch, the file is removed. The end of a refused transfer
+    /// is ignored, `None` is returned.
+    pub fn end(&mut self, payload: &[u8]) -> Result<Option<Finished>, TransferError> {
+        if payload.len() < END_LEN {
+            return Err(TransferError::Malformed);
+        }
+
+        let id = NetworkEndian::read_u32(&payload[0..]);
+        let chunks = NetworkEndian::read_u32(&payload[4..]);
+        let mut expected = [0; HASH_LEN];
+        expected.copy_from_slice(&payload[8..END_LEN]);
+
+        if self.refused.remove(&id) {
+            return Ok(None);
+        }
+        let complete = match self.incoming.get(&id) {
+            None if chunks == 0 => {
+                // Empty file, we never received a chunk
+                self.accept(id, 0)?;
+                let hash: [u8; HASH_LEN] = Sha256::digest(b"").into();
+                if hash != expected {
+                    return Err(TransferError::HashMismatch);
+                }
+                let name = format!("transfer-{}", id);
+                let (_, path) = create_file(&self.dir, &name).map_err(TransferError::IO)?;
+                return Ok(Some(Finished { id, path, hash }));
+            }
+            None => return Err(TransferError::UnknownTransfer(id)),
+            Some(incoming) => {
+                incoming.next_seq == chunks && incoming.received == incoming.total
+            }
+        };
+
+        if !complete {
+            self.cancel(id);
+            return Err(TransferError::Incomplete);
+        }
+
+        let mut incoming = self.incoming.remove(&id).unwrap();
+        let hash: [u8; HASH_LEN] = incoming.hasher.finalize().into();
+        if hash != expected {
+            drop(incoming.file);
+            let _ = remove_file(&incoming.path);
+            self.accepted -= incoming.total;
+            return Err(TransferError::HashMismatch);
+        }
+        incoming.file.flush().map_err(TransferError::IO)?;
+        Ok(Some(Finished { id, path: incoming.path, hash }))
+    }
+
+    /// Forget a transfer and remove its partial file
+    fn cancel(&mut self, id: u32) {
+        if let Some(incoming) = self.incoming.remove(&id) {
+            let _ = remove_file(&incoming.path);
+            self.accepted -= incoming.total;
+        }
+    }
+}
+
+/// Keep only the last component of `name`, so the file can't be
+/// written outside the download directory
+///
+/// Return `None` when nothing usable is left.
+fn sanitize(name: &str) -> Option<String> {
+    let name: String = name.rsplit(&['/', '\\'][..])
+                           .next()
+                           .unwrap_or("")
+                           .chars()
+                           .filter(|c| !c.is_control())
+                           .collect();
+    match name.trim() {
+        "" | "." | ".." => None,
+        name => Some(name.to_owned())
+    }
+}
+
+/// Create a new file `name` for a received transfer, in `dir`
+///
+/// Existing files are never overwritten, a number is appended to the
+/// name instead.
+fn create_file(dir: &Path, name: &str) -> io::Result<(File, PathBuf)> {
+    let mut n = 0;
+    loop {
+        let path = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{}.{}", name, n)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use std::fs;
+    use std::process;
+    use sha2::{Digest, Sha256};
+    use reader::Kind;
+    use super::{chunk, end, meta, sanitize, Outgoing, TransferError, Transfers, HASH_LEN, CHUNK_SIZE};
+
+    fn hash(data: &[u8]) -> [u8; HASH_LEN] {
+        Sha256::digest(data).into()
+    }
+
+    fn temp_dir(name: &str) -> ::std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("chat-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sanitize_name() {
+        assert_eq!(sanitize("report.pdf").unwrap(), "report.pdf");
+        assert_eq!(sanitize("../../etc/passwd").unwrap(), "passwd");
+        assert_eq!(sanitize("C:\\Users\\me\\notes.txt").unwrap(), "notes.txt");
+        assert_eq!(sanitize("bad\nname").unwrap(), "badname");
+        assert!(sanitize("..").is_none());
+        assert!(sanitize("dir/").is_none());
+        assert!(sanitize("").is_none());
+    }
+
+    #[test]
+    fn named_transfer() {
+        let dir = temp_dir("transfer");
+        let mut transfers = Transfers::new(dir.clone(), Some(1000));
+
+        let hello = hash(b"hello world");
+        let started = transfers.meta(&meta(7, 11, "../hello.txt")).unwrap();
+        assert_eq!(started.name, "../hello.txt");
+        assert_eq!(started.path, dir.join("hello.txt"));
+
+        transfers.chunk(&chunk(7, 0, 11, b"hello ")).unwrap();
+        // The size doesn't match the metadata
+        match transfers.chunk(&chunk(7, 1, 12, b"world")) {
+            Err(TransferError::WrongSize { expected: 11, received: 12 }) => (),
+            _ => panic!("the size should be checked")
+        }
+        assert!(!started.path.exists());
+
+        // The name is taken, a number is appended
+        fs::write(dir.join("hello.txt"), b"").unwrap();
+        let started = transfers.meta(&meta(8, 11, "hello.txt")).unwrap();
+        assert_eq!(started.path, dir.join("hello.txt.1"));
+        transfers.chunk(&chunk(8, 0, 11, b"hello ")).unwrap();
+        transfers.chunk(&chunk(8, 1, 11, b"world")).unwrap();
+        let finished = transfers.end(&end(8, 2, &hello)).unwrap().unwrap();
+        assert_eq!(finished.hash, hello);
+        assert_eq!(fs::read(&finished.path).unwrap(), b"hello world");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let dir = temp_dir("hash");
+        let mut transfers = Transfers::new(dir.clone(), Some(1000));
+
+        let started = transfers.meta(&meta(1, 11, "hello.txt")).unwrap();
+        transfers.chunk(&chunk(1, 0, 11, b"hello w0rld")).unwrap();
+        match transfers.end(&end(1, 1, &hash(b"hello world"))) {
+            Err(TransferError::HashMismatch) => (),
+            _ => panic!("the hash should be checked")
+        }
+        assert!(!started.path.exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn meta_version() {
+        let mut transfers = Transfers::new(env::temp_dir(), Some(1000));
+
+        // Version 1 had the SHA-256 in the metadata
+        let mut payload = meta(1, 11, "hello.txt");
+        payload[0] = 1;
+        match transfers.meta(&payload) {
+            Err(TransferError::UnknownVersion(1)) => (),
+            _ => panic!("the version should be checked")
+        }
+        match transfers.meta(&payload[..12]) {
+            Err(TransferError::Malformed) => (),
+            _ => panic!("the length should be checked")
+        }
+    }
+
+    #[test]
+    fn refused_transfers() {
+        let dir = temp_dir("refused");
+
+        // Without --receive-files, nothing is written
+        let mut transfers = Transfers::new(dir.clone(), None);
+        match transfers.meta(&meta(1, 5, "hello.txt")) {
+            Err(TransferError::Refused) => (),
+            _ => panic!("the file should be refused")
+        }
+        assert!(transfers.chunk(&chunk(1, 0, 5, b"hello")).unwrap().is_none());
+        assert!(transfers.end(&end(1, 1, &hash(b"hello"))).unwrap().is_none());
+        match transfers.chunk(&chunk(2, 0, 5, b"hello")) {
+            Err(TransferError::Refused) => (),
+            _ => panic!("the file should be refused")
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // The cap is for all the files of the connection
+        let mut transfers = Transfers::new(dir.clone(), Some(10));
+        transfers.meta(&meta(1, 6, "a")).unwrap();
+        match transfers.meta(&meta(2, 6, "b")) {
+            Err(TransferError::TooLarge { size: 6, left: 4 }) => (),
+            _ => panic!("the cap should be checked")
+        }
+        assert!(transfers.chunk(&chunk(2, 0, 6, b"hello!")).unwrap().is_none());
+        // Not more than what the metadata announced
+        transfers.chunk(&chunk(1, 0, 6, b"hello!")).unwrap();
+        match transfers.chunk(&chunk(1, 1, 6, b"more")) {
+            Err(TransferError::WrongSize { expected: 6, received: 10 }) => (),
+            _ => panic!("the size should be checked")
+        }
+        // The cancelled transfer gives its bytes back
+        transfers.meta(&meta(3, 10, "c")).unwrap();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn outgoing_chunks() {
+        let dir = temp_dir("outgoing");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|n| n as u8).collect();
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        let (mut outgoing, payload) = Outgoing::open(&dir.join("data.bin"), 3).unwrap();
+        assert_eq!(outgoing.total(), data.len() as u64);
+
+        let mut transfers = Transfers::new(dir.join("received"), Some(data.len() as u64));
+        fs::create_dir(dir.join("received")).unwrap();
+        let started = transfers.meta(&payload).unwrap();
+        assert_eq!(started.name, "data.bin");
+
+        let mut chunks = 0;
+        let finished = loop {
+            match outgoing.next_message().unwrap() {
+                Some((Kind::Chunk, payload)) => {
+                    chunks += 1;
+                    transfers.chunk(&payload).unwrap();
+                }
+                Some((Kind::ChunkEnd, payload)) => break transfers.end(&payload).unwrap().unwrap(),
+                _ => panic!("a chunk or the end should be next")
+            }
+        };
+        assert_eq!(chunks, 3);
+        assert!(outgoing.next_message().unwrap().is_none());
+        assert_eq!(finished.hash, hash(&data));
+        assert_eq!(fs::read(&finished.path).unwrap(), data);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
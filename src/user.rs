@@ -2,19 +2,158 @@
 
 use actix::prelude::*;
 use actix::dev::ToEnvelope;
-use std::io::Read;
+use std::fs::File;
+use std::sync::Arc;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::Future;
 use atty;
 
-use MESSAGE_MAX_LEN;
+use replay::Replayed;
+use {Config, Display, ExitCode, Oversize};
 
 /// Input datas
 ///
 /// Wrap the input data to be sent between differents actors
 /// (User -> Client/Server) as an Actix message
+///
+/// It resolves once the peers took the data. A [`Peer`] waiting for its
+/// write buffer to drain doesn't take it, so the [`User`] stops reading
+/// stdin until the socket catches up.
+///
+/// The data is shared: sending it to many peers doesn't copy it. The
+/// second field is true when it was typed in a terminal, it can then be
+/// a [`Command`].
+///
+/// [`Peer`]: ../peer/struct.Peer.html
 #[derive(Message, Clone)]
-pub struct UserInput(pub Vec<u8>);
+#[rtype(result = "Result<(), ()>")]
+pub struct UserInput(pub Arc<[u8]>, pub bool);
+
+impl UserInput {
+    /// Take `data`, to be shared by the peers
+    pub fn new(data: Vec<u8>) -> UserInput {
+        UserInput(data.into(), false)
+    }
+
+    /// Take `data` typed in a terminal
+    pub fn typed(data: Vec<u8>) -> UserInput {
+        UserInput(data.into(), true)
+    }
+
+    /// Return the command typed instead of a message, if any
+    ///
+    /// Piped or replayed input is never a command.
+    pub fn command(&self) -> Option<Result<Command, String>> {
+        if self.1 { Command::parse(&self.0) } else { None }
+    }
+}
+
+/// Notify the parent that no more input will be read
+///
+/// Only the [`User`] actor stops, the parent keeps running and can
+/// still receive messages.
+#[derive(Message)]
+pub struct InputClosed;
 
-/// User Actor, reads data on stdin
+/// Notify the parent that the user is typing a message
+///
+/// Sent for each line typed in a terminal, before the message is
+/// complete.
+#[derive(Message)]
+pub struct Typing;
+
+/// Minimum time between two typing notifications sent to the peers
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Limit the typing notifications sent to the peers, at most one
+/// every 3 seconds
+#[derive(Default)]
+pub struct TypingDebounce {
+    /// Last notification sent
+    last: Option<Instant>
+}
+
+impl TypingDebounce {
+    /// Return true if a notification can be sent now
+    pub fn ready(&mut self) -> bool {
+        match self.last {
+            Some(last) if last.elapsed() < TYPING_INTERVAL => false,
+            _ => {
+                self.last = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+/// A command typed instead of a message
+///
+/// The input is a command when it's only `/display <mode>`, with an
+/// optional end of line. It's not sent to the peers.
+#[derive(Debug)]
+pub enum Command {
+    /// `/display <mode>`: change how the received messages are displayed
+    SetDisplay(Display)
+}
+
+impl Command {
+    /// Parse `input`, `None` when it's not a command
+    ///
+    /// The error explains why the command is invalid.
+    pub fn parse(input: &[u8]) -> Option<Result<Command, String>> {
+        let input = ::std::str::from_utf8(input).ok()?;
+        let input = input.trim_end_matches(&['\r', '\n'][..]);
+        let mode = input.strip_prefix("/display ")?.trim();
+        if Display::NAMES.contains(&mode) {
+            Some(Ok(Command::SetDisplay(Display::from(mode))))
+        } else {
+            Some(Err(format!("Unknown display mode '{}', expected one of: {}",
+                             mode, Display::NAMES.join(", "))))
+        }
+    }
+}
+
+/// Where the [`User`] reads the messages
+pub enum Input {
+    /// Standard input. In a terminal, each CTRL+D sends a message
+    Stdin,
+    /// Any reader, sent as a single message once read until the end
+    #[allow(dead_code)]
+    Reader(Box<dyn Read + Send>),
+    /// The terminal, opened after the end of a piped stdin with
+    /// `--follow-input`
+    Tty(File),
+    /// The messages of a transcript, sent with their delays with
+    /// `--replay`
+    Replay(Vec<Replayed>)
+}
+
+impl Input {
+    /// Return true if the input is typed by someone in a terminal
+    fn is_terminal(&self) -> bool {
+        match *self {
+            Input::Stdin => atty::is(atty::Stream::Stdin),
+            Input::Reader(_) | Input::Replay(_) => false,
+            Input::Tty(_) => true
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Input::Stdin => io::stdin().read(buffer),
+            Input::Reader(ref mut reader) => reader.read(buffer),
+            Input::Tty(ref mut tty) => tty.read(buffer),
+            Input::Replay(_) => Ok(0)
+        }
+    }
+}
+
+/// User Actor, reads data on its [`Input`]
 ///
 /// When the data is read, it is sent as an Actix message to its parent
 /// (Client/Server)
@@ -22,61 +161,503 @@ pub struct User<T>
 where
     T: Actor,
     T: Handler<UserInput>,
-    T::Context: ToEnvelope<T, UserInput>
+    T: Handler<InputClosed>,
+    T: Handler<Typing>,
+    T::Context: ToEnvelope<T, UserInput>,
+    T::Context: ToEnvelope<T, InputClosed>,
+    T::Context: ToEnvelope<T, Typing>
 {
     /// Address of the Actor that created `User`
-    parent: Addr<T>
+    parent: Addr<T>,
+    /// Where the messages are read
+    input: Input,
+    /// Send an empty message when the input is empty, with `--allow-empty`
+    allow_empty: bool,
+    /// Maximum length of a message, with `--max-message`
+    max_message: usize,
+    /// What to do with longer messages
+    on_oversize: Oversize,
+    /// Read the terminal once the piped input ends, with `--follow-input`
+    follow_input: bool,
+    /// Send a piped input as it comes, with `--follow`
+    follow: bool,
+    /// Send each line as a message, with `--lines`
+    lines: bool,
+    /// Keep the end of line of the messages, with `--keep-newline`
+    keep_newline: bool,
+    /// Divide the delays of a transcript, with `--replay-speed`
+    replay_speed: f64
 }
 
 impl<T> User<T>
 where
     T: Actor,
     T: Handler<UserInput>,
-    T::Context: ToEnvelope<T, UserInput>
+    T: Handler<InputClosed>,
+    T: Handler<Typing>,
+    T::Context: ToEnvelope<T, UserInput>,
+    T::Context: ToEnvelope<T, InputClosed>,
+    T::Context: ToEnvelope<T, Typing>
 {
-    pub fn new(parent: Addr<T>) -> Self {
-        User { parent }
+    pub fn new(parent: Addr<T>, input: Input, config: &Config) -> Self {
+        User {
+            parent,
+            input,
+            allow_empty: config.allow_empty,
+            max_message: config.max_message,
+            on_oversize: config.on_oversize,
+            follow_input: config.follow_input,
+            follow: config.follow,
+            lines: config.lines,
+            keep_newline: config.keep_newline,
+            replay_speed: config.replay_speed
+        }
+    }
+
+    /// Loop reading the input, sending each line as a message
+    ///
+    /// The `\n` or `\r\n` at the end of the lines is removed, unless
+    /// `--keep-newline` is set. `--max-message` applies to each line. An
+    /// empty line, with or without its end, is only sent with
+    /// `--allow-empty`.
+    ///
+    /// Return false if the input failed or the parent is gone, true at
+    /// the end of the input.
+    fn read_lines(&mut self) -> bool {
+        let isatty = self.input.is_terminal();
+
+        if isatty {
+            println!("Reading stdin, each line is sent\n");
+        }
+
+        let max = self.max_message;
+        let parent = &self.parent;
+        let input = if isatty { UserInput::typed } else { UserInput::new };
+        let send = |data: Vec<u8>| parent.send(input(data)).wait().map_or(false, |taken| taken.is_ok());
+        let mut reader = BufReader::new(&mut self.input);
+
+        loop {
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return true,
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Input error: {:?}", e);
+                    return false;
+                }
+            }
+
+            let end = match line.len() {
+                len if line.ends_with(b"\r\n") => len - 2,
+                len if line.ends_with(b"\n") => len - 1,
+                len => len
+            };
+            if end == 0 && !self.allow_empty {
+                continue;
+            }
+            if !self.keep_newline {
+                line.truncate(end);
+            }
+
+            if line.len() > max {
+                match self.on_oversize {
+                    Oversize::Split => {
+                        for part in line.chunks(max) {
+                            if !send(part.to_vec()) {
+                                return false;
+                            }
+                        }
+                        continue;
+                    }
+                    Oversize::Truncate => {
+                        println!("Line longer than {} bytes, truncated", max);
+                        line.truncate(max);
+                    }
+                    Oversize::Reject if isatty => {
+                        println!("Line longer than {} bytes, not sent", max);
+                        continue;
+                    }
+                    Oversize::Reject => {
+                        println!("Input line longer than {} bytes, not sent", max);
+                        ExitCode::Failure.exit();
+                    }
+                }
+            }
+
+            if !send(line) {
+                return false;
+            }
+        }
     }
 
-    /// Loop reading stdin
-    fn read_stdin(&self) {
-        let isatty = atty::is(atty::Stream::Stdin);
+    /// Send the messages of a transcript, waiting their delays
+    ///
+    /// Return false if the parent is gone, true once all are sent.
+    fn replay(&self, messages: Vec<Replayed>) -> bool {
+        for message in messages {
+            if message.delay > Duration::from_secs(0) {
+                thread::sleep(message.delay.div_f64(self.replay_speed));
+            }
+            if !self.send(message.data) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Read the input, whole or by line
+    fn read(&mut self) -> bool {
+        if let Input::Replay(ref mut messages) = self.input {
+            let messages = mem::take(messages);
+            return self.replay(messages);
+        }
+        if self.lines {
+            self.read_lines()
+        } else if self.follow && !self.input.is_terminal() {
+            self.read_chunks()
+        } else {
+            self.read_input()
+        }
+    }
+
+    /// Loop reading the input
+    ///
+    /// A message longer than `--max-message` is split while it is read,
+    /// so it is never held entirely in memory, unless `--on-oversize`
+    /// says otherwise.
+    ///
+    /// Return false if the input failed or the parent is gone, true at
+    /// the end of the input.
+    fn read_input(&mut self) -> bool {
+        let isatty = self.input.is_terminal();
 
         if isatty {
             println!("Reading stdin, CTRL+D to send\n");
         }
 
+        let max = self.max_message;
+        let mut buffer = [0; 4096];
+
         loop {
             let mut input = Vec::new();
-            if let Err(e) = ::std::io::stdin().read_to_end(&mut input) {
-                println!("stdin error: {:?}", e);
-                return;
+            // Some parts of the message were already sent
+            let mut chunked = false;
+            // The message is longer than the maximum, the rest is skipped
+            let mut oversize = false;
+            loop {
+                match self.input.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if isatty {
+                            self.parent.do_send(Typing);
+                        }
+                        if oversize {
+                            continue;
+                        }
+                        input.extend_from_slice(&buffer[..n]);
+                        match self.on_oversize {
+                            Oversize::Split => {
+                                while input.len() >= max {
+                                    let rest = input.split_off(max);
+                                    if !self.send(input) {
+                                        return false;
+                                    }
+                                    input = rest;
+                                    chunked = true;
+                                }
+                            }
+                            Oversize::Truncate if input.len() > max => {
+                                input.truncate(max);
+                                oversize = true;
+                            }
+                            Oversize::Reject if input.len() > max => {
+                                input = Vec::new();
+                                oversize = true;
+                            }
+                            _ => ()
+                        }
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        println!("Input error: {:?}", e);
+                        return false;
+                    }
+                }
             }
-            if !isatty && input.is_empty() {
-                println!("No more data on stdin.");
-                println!("Still can receive messages from others..\n");
-                return;
+            if oversize {
+                match self.on_oversize {
+                    Oversize::Reject if isatty => {
+                        println!("Message longer than {} bytes, not sent", max);
+                        continue;
+                    }
+                    Oversize::Reject => {
+                        println!("Input longer than {} bytes, not sent", max);
+                        ExitCode::Failure.exit();
+                    }
+                    _ => println!("Message longer than {} bytes, truncated", max)
+                }
             }
-            if input.len() > MESSAGE_MAX_LEN as usize {
-                println!("Message is too big, cancelled");
-                continue;
+            if input.is_empty() && (chunked || (!isatty && !self.allow_empty)) {
+                if isatty {
+                    continue;
+                }
+                return true;
+            }
+            if !self.send(input) {
+                return false;
+            }
+            if !isatty {
+                // Not a terminal, we read until the end of the input
+                return true;
             }
-            self.parent.do_send(UserInput(input));
         };
     }
+
+    /// Loop reading the input, sending what each read gives as a
+    /// message
+    ///
+    /// A read returns what is available so far, at most `--max-message`
+    /// bytes, so the data is sent as it comes in.
+    ///
+    /// Return false if the input failed or the parent is gone, true at
+    /// the end of the input.
+    fn read_chunks(&mut self) -> bool {
+        let mut buffer = vec![0; self.max_message.clamp(1, 65536)];
+
+        loop {
+            match self.input.read(&mut buffer) {
+                Ok(0) => return true,
+                Ok(n) => {
+                    if !self.send(buffer[..n].to_vec()) {
+                        return false;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    println!("Input error: {:?}", e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Continue with the terminal once the piped input ended, so more
+    /// can be typed
+    fn follow_tty(&mut self) {
+        match File::open("/dev/tty") {
+            Ok(tty) => {
+                self.input = Input::Tty(tty);
+                self.read();
+            }
+            Err(e) => println!("Can not read the terminal: {}", e)
+        }
+    }
+
+    /// Send `input` to the parent, and wait for the peers to take it
+    /// before reading more
+    ///
+    /// Return false if the parent is gone, or refused the input because
+    /// it won't take more.
+    fn send(&self, data: Vec<u8>) -> bool {
+        let input = if self.input.is_terminal() { UserInput::typed(data) } else { UserInput::new(data) };
+        self.parent.send(input).wait().map_or(false, |taken| taken.is_ok())
+    }
 }
 
 impl<T> Actor for User<T>
 where
     T: Actor,
     T: Handler<UserInput>,
-    T::Context: ToEnvelope<T, UserInput>
+    T: Handler<InputClosed>,
+    T: Handler<Typing>,
+    T::Context: ToEnvelope<T, UserInput>,
+    T::Context: ToEnvelope<T, InputClosed>,
+    T::Context: ToEnvelope<T, Typing>
 {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // The actor is created, start to read stdin
-        self.read_stdin();
+        // The actor is created, start to read the input
+        if self.read() && self.follow_input && !self.input.is_terminal() {
+            self.follow_tty();
+        }
         ctx.stop();
     }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.parent.do_send(InputClosed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::VecDeque;
+    use std::io::{self, Cursor, Read};
+    use std::sync::{Arc, Mutex};
+    use actix::prelude::*;
+    use super::{Command, Input, InputClosed, Typing, User, UserInput};
+    use Display;
+
+    /// Parent keeping the messages received from its [`User`]
+    struct Parent {
+        inputs: Arc<Mutex<Vec<Vec<u8>>>>
+    }
+
+    impl Actor for Parent {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<UserInput> for Parent {
+        type Result = Result<(), ()>;
+
+        fn handle(&mut self, input: UserInput, _ctx: &mut Context<Self>) -> Result<(), ()> {
+            self.inputs.lock().unwrap().push(input.0.to_vec());
+            Ok(())
+        }
+    }
+
+    impl Handler<InputClosed> for Parent {
+        type Result = ();
+
+        fn handle(&mut self, _: InputClosed, _ctx: &mut Context<Self>) {
+            System::current().stop();
+        }
+    }
+
+    impl Handler<Typing> for Parent {
+        type Result = ();
+
+        fn handle(&mut self, _: Typing, _ctx: &mut Context<Self>) {}
+    }
+
+    /// Return the messages read from `data`, with the options `args`
+    fn read(data: Vec<u8>, args: &[&str]) -> Vec<Vec<u8>> {
+        read_from(Box::new(Cursor::new(data)), args)
+    }
+
+    /// Return the messages read from `reader`, with the options `args`
+    fn read_from(reader: Box<dyn Read + Send>, args: &[&str]) -> Vec<Vec<u8>> {
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let parent_inputs = inputs.clone();
+        let mut all_args = vec!["chat"];
+        all_args.extend_from_slice(args);
+        let config = ::config_from(&::app().get_matches_from(all_args));
+
+        System::run(move || {
+            let parent = Parent { inputs: parent_inputs }.start();
+            // The User blocks its thread while reading
+            Arbiter::start(move |_| {
+                User::new(parent, Input::Reader(reader), &config)
+            });
+        });
+
+        let inputs = inputs.lock().unwrap().clone();
+        inputs
+    }
+
+    #[test]
+    fn reader_input() {
+        assert_eq!(read(b"hello\nworld\n".to_vec(), &[]), vec![b"hello\nworld\n".to_vec()]);
+        assert!(read(Vec::new(), &[]).is_empty());
+        assert_eq!(read(Vec::new(), &["--allow-empty"]), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn large_input_is_split() {
+        let max = 64 * 1024;
+        let data: Vec<u8> = (0..max * 2 + 100).map(|i| i as u8).collect();
+        let inputs = read(data.clone(), &[]);
+
+        let lens: Vec<usize> = inputs.iter().map(Vec::len).collect();
+        assert_eq!(lens, vec![max, max, 100]);
+        assert_eq!(inputs.concat(), data);
+
+        // No empty message after the last full part
+        assert_eq!(read(vec![0; max], &["--allow-empty"]).len(), 1);
+
+        // Smaller than what is read at once
+        let inputs = read(b"hello world".to_vec(), &["--max-message", "4"]);
+        assert_eq!(inputs, vec![b"hell".to_vec(), b"o wo".to_vec(), b"rld".to_vec()]);
+    }
+
+    #[test]
+    fn large_input_is_truncated() {
+        let inputs = read(vec![1; 10000], &["--max-message", "5000", "--on-oversize", "truncate"]);
+        assert_eq!(inputs, vec![vec![1; 5000]]);
+
+        let inputs = read(vec![1; 10], &["--max-message", "10", "--on-oversize", "truncate"]);
+        assert_eq!(inputs, vec![vec![1; 10]]);
+    }
+
+    #[test]
+    fn lines() {
+        let data = b"hello\r\n\nworld\nlast".to_vec();
+        assert_eq!(read(data.clone(), &["--lines"]),
+                   vec![b"hello".to_vec(), b"world".to_vec(), b"last".to_vec()]);
+        assert_eq!(read(data.clone(), &["--lines", "--allow-empty"]).len(), 4);
+        assert_eq!(read(data.clone(), &["--lines", "--keep-newline"]),
+                   vec![b"hello\r\n".to_vec(), b"world\n".to_vec(), b"last".to_vec()]);
+        assert_eq!(read(data, &["--lines", "--keep-newline", "--allow-empty"]),
+                   vec![b"hello\r\n".to_vec(), b"\n".to_vec(), b"world\n".to_vec(), b"last".to_vec()]);
+        assert!(read(b"\r\n\n".to_vec(), &["--lines"]).is_empty());
+
+        let inputs = read(b"abcdef\ngh\n".to_vec(), &["--lines", "--max-message", "4"]);
+        assert_eq!(inputs, vec![b"abcd".to_vec(), b"ef".to_vec(), b"gh".to_vec()]);
+    }
+
+    /// Pipe giving its data in several reads, like `tail -f`
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.0.pop_front() {
+                Some(chunk) => chunk,
+                None => return Ok(0)
+            };
+            let len = chunk.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&chunk[..len]);
+            if len < chunk.len() {
+                self.0.push_front(&chunk[len..]);
+            }
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn follow() {
+        let chunks = || Box::new(Chunks(vec![&b"hel"[..], b"lo wor", b"ld"].into_iter().collect()));
+        assert_eq!(read_from(chunks(), &[]), vec![b"hello world".to_vec()]);
+        assert_eq!(read_from(chunks(), &["--follow"]),
+                   vec![b"hel".to_vec(), b"lo wor".to_vec(), b"ld".to_vec()]);
+        assert_eq!(read_from(chunks(), &["--follow", "--max-message", "4"]),
+                   vec![b"hel".to_vec(), b"lo w".to_vec(), b"or".to_vec(), b"ld".to_vec()]);
+    }
+
+    #[test]
+    fn commands() {
+        match Command::parse(b"/display utf8\n") {
+            Some(Ok(Command::SetDisplay(Display::Utf8))) => {}
+            other => panic!("{:?}", other)
+        }
+        match Command::parse(b"/display hexdump") {
+            Some(Ok(Command::SetDisplay(Display::HexDump))) => {}
+            other => panic!("{:?}", other)
+        }
+        match Command::parse(b"/display hex\n") {
+            Some(Err(e)) => assert!(e.starts_with("Unknown display mode 'hex'"), "{}", e),
+            other => panic!("{:?}", other)
+        }
+
+        // Sent as messages
+        assert!(Command::parse(b"hello").is_none());
+        assert!(Command::parse(b"/display").is_none());
+        assert!(Command::parse(b"/displayed utf8").is_none());
+        assert!(Command::parse(b"/display \xFF").is_none());
+
+        // Only typed in a terminal
+        assert!(UserInput::typed(b"/display utf8".to_vec()).command().is_some());
+        assert!(UserInput::new(b"/display utf8".to_vec()).command().is_none());
+    }
 }